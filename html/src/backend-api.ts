@@ -4,9 +4,14 @@ import backend_config from "./backend-config.json";
 
 export class APIError extends Error {
 	status: number;
-	constructor(status: number, text: string) {
+	/** Stable, such as `log_not_found`. `null` if the response was not an error from the API. */
+	code: string | null;
+	details: any;
+	constructor(status: number, text: string, code: string | null = null, details: any = null) {
 		super(`Server responded with ${status}: ${text}`);
 		this.status = status;
+		this.code = code;
+		this.details = details;
 	}
 }
 export class NetworkError extends Error {
@@ -26,6 +31,13 @@ export async function get_json<T>(path: string, fetch: FetchFn): Promise<T> {
 		let res = await fetch(backend_config.url + path);
 		if (res.status !== 200) {
 			let text = await res.text();
+			let body: { code?: string, message?: string, details?: any } | null = null;
+			try {
+				body = JSON.parse(text);
+			} catch (_) {}
+			if (body && typeof body.code === "string") {
+				throw new APIError(res.status, body.message || body.code, body.code, body.details || null);
+			}
 			throw new APIError(res.status, text);
 		}
 		return (await res.json()) as T;
@@ -37,6 +49,27 @@ export async function get_json<T>(path: string, fetch: FetchFn): Promise<T> {
 	}
 }
 
+/** A page of a list endpoint. `next` is the path of the next page, if there is one. */
+export type Page<T> = {
+	items: T[],
+	cursor: string | null,
+	next: string | null
+};
+
+/** Follows `next` until the last page, and returns all items. */
+export async function get_all_pages<T>(path: string, fetch: FetchFn): Promise<T[]> {
+	let items: T[] = [];
+	let next: string | null = path;
+	while (next !== null) {
+		let page: Page<T> = await get_json<Page<T>>(next, fetch);
+		items.push(...page.items);
+		next = page.next;
+	}
+	return items;
+}
+
+// The types below follow the schemas in doc/openapi.json, which is generated from the server code.
+
 export type Stats = {
 	nb_logs_active: number,
 	nb_logs_total: number
@@ -50,6 +83,7 @@ export type CtLogs = Array<BasicCtLogInfo>;
 export type BasicCtLogInfo = {
 	log_id: string,
 	name: string,
+	operator: string | null,
 	monitoring: boolean,
 	endpoint_url: string,
 	latest_sth: BasicSthInfo | null,
@@ -64,13 +98,14 @@ export type BasicSthInfo = {
 }
 
 export function ctlogs(fetch: FetchFn, include_retired: boolean): Promise<CtLogs> {
-	return get_json<CtLogs>(`/ctlogs?include_retired=${include_retired}`, fetch);
+	return get_all_pages<BasicCtLogInfo>(`/ctlogs?include_retired=${include_retired}&limit=1000`, fetch);
 }
 
 export type CtLog = {
 	log_id: string,
 	endpoint_url: string,
 	name: string,
+	operator: string | null,
 	public_key: string, // base64
 	monitoring: boolean,
 	latest_sth: number | null,
@@ -89,9 +124,62 @@ export type Sth = {
 	sth_timestamp: number,
 	received_time: number,
 	signature: string,
-	checked_consistent_with_latest: boolean
+	checked_consistent_with_latest: boolean,
+	was_latest: boolean
 }
 
 export function sth(fetch: FetchFn, log_id: string, sth_id: number): Promise<Sth> {
 	return get_json<Sth>(`/log/${encodeURIComponent(log_id)}/sth/${sth_id}`, fetch);
 }
+
+export type LogEvent =
+	{ type: "latest_sth", log_id: string, latest_sth: BasicSthInfo } |
+	{ type: "sth_error", log_id: string, error: string | null } |
+	{ type: "consistency_error", log_id: string, from_sth_id: number, to_sth_id: number, error: string } |
+	{ type: "fetch_error", log_id: string, from_tree_size: number, to_tree_size: number, error: string } |
+	// Events were missed, so the state should be reloaded.
+	{ type: "dropped" };
+
+/**
+ * Calls `handler` with changes in the status of the given logs (all if null), as they happen.
+ * If the backend has no stream server (no `stream_url` in backend-config.json) or it can't be
+ * reached, calls `poll` instead, which should start polling and return a function to stop it.
+ * Returns a function to unsubscribe.
+ */
+export function log_events(log_ids: string[] | null, handler: (event: LogEvent) => void, poll: () => (() => void)): () => void {
+	let stream_url: string | undefined = (backend_config as any).stream_url;
+	if (!stream_url || typeof EventSource === "undefined") {
+		return poll();
+	}
+	let url = stream_url + "/events";
+	if (log_ids !== null) {
+		url += "?log=" + log_ids.map(encodeURIComponent).join(",");
+	}
+	let source = new EventSource(url);
+	for (let type of ["latest_sth", "sth_error", "consistency_error", "fetch_error"]) {
+		source.addEventListener(type, (e: MessageEvent) => {
+			handler({ type, ...JSON.parse(e.data) });
+		});
+	}
+	source.addEventListener("dropped", () => handler({ type: "dropped" }));
+	let opened = false;
+	// EventSource reconnects by itself, but anything that happened in the meantime is lost.
+	source.addEventListener("open", () => {
+		opened = true;
+		handler({ type: "dropped" });
+	});
+	let stop_polling: (() => void) | null = null;
+	source.addEventListener("error", () => {
+		// Once connected, EventSource keeps retrying unless it gives up.
+		if (stop_polling === null && (!opened || source.readyState === EventSource.CLOSED)) {
+			source.close();
+			stop_polling = poll();
+		}
+	});
+	return () => {
+		source.close();
+		if (stop_polling !== null) {
+			stop_polling();
+		}
+	};
+}
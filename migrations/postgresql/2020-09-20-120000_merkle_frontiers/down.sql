@@ -0,0 +1 @@
+DROP TABLE merkle_frontiers CASCADE;
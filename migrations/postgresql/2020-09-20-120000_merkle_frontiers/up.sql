@@ -0,0 +1,5 @@
+CREATE TABLE merkle_frontiers (
+    "log_id" bytea UNIQUE NOT NULL PRIMARY KEY REFERENCES ctlogs("log_id"),
+    "tree_size" bigint NOT NULL,
+    "subtree_hashes" bytea[] NOT NULL -- roots of the perfect subtrees covering [0, tree_size), largest first
+);
@@ -0,0 +1,10 @@
+CREATE TABLE raw_leaves (
+    "log_id" bytea NOT NULL REFERENCES ctlogs("log_id"),
+    "leaf_index" bigint NOT NULL,
+    "leaf_hash" bytea NOT NULL,
+    "leaf_input" bytea NOT NULL, -- MerkleTreeLeaf, zlib compressed
+    "extra_data" bytea NOT NULL, -- zlib compressed
+    PRIMARY KEY ("log_id", "leaf_index")
+);
+
+CREATE INDEX raw_leaves_by_leaf_hash ON raw_leaves ("log_id", "leaf_hash");
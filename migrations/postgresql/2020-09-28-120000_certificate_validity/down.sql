@@ -0,0 +1,6 @@
+DROP INDEX certificates_by_not_before;
+DROP INDEX certificates_by_not_after;
+ALTER TABLE certificates DROP COLUMN "not_before", DROP COLUMN "not_after";
+DROP FUNCTION ctcrab_x509_validity(bytea);
+DROP FUNCTION ctcrab_der_time(bytea, integer);
+DROP FUNCTION ctcrab_der_header(bytea, integer);
@@ -0,0 +1,82 @@
+ALTER TABLE certificates
+    ADD COLUMN "not_before" timestamp with time zone DEFAULT NULL,
+    ADD COLUMN "not_after" timestamp with time zone DEFAULT NULL;
+
+-- Just enough DER parsing to backfill existing rows. New rows get their validity from openssl at
+-- insert time. Positions are 0-based, as for get_byte.
+
+CREATE FUNCTION ctcrab_der_header(der bytea, pos integer, OUT tag integer, OUT content_start integer, OUT content_len integer) AS $$
+DECLARE
+    l integer;
+BEGIN
+    tag := get_byte(der, pos);
+    l := get_byte(der, pos + 1);
+    IF l < 128 THEN
+        content_len := l;
+        content_start := pos + 2;
+    ELSE
+        content_len := 0;
+        FOR i IN 1..(l & 127) LOOP
+            content_len := content_len * 256 + get_byte(der, pos + 1 + i);
+        END LOOP;
+        content_start := pos + 2 + (l & 127);
+    END IF;
+END;
+$$ LANGUAGE plpgsql IMMUTABLE STRICT;
+
+-- UTCTime (YYMMDDHHMMSSZ) or GeneralizedTime (YYYYMMDDHHMMSSZ) at pos.
+CREATE FUNCTION ctcrab_der_time(der bytea, pos integer) RETURNS timestamp with time zone AS $$
+DECLARE
+    h record;
+    s text;
+BEGIN
+    h := ctcrab_der_header(der, pos);
+    s := convert_from(substring(der FROM h.content_start + 1 FOR h.content_len), 'SQL_ASCII');
+    IF h.tag = 23 THEN
+        s := (CASE WHEN substring(s FROM 1 FOR 2)::integer >= 50 THEN '19' ELSE '20' END) || s;
+    ELSIF h.tag <> 24 THEN
+        RAISE EXCEPTION 'Expected a time, got tag %.', h.tag;
+    END IF;
+    RETURN format('%s-%s-%s %s:%s:%s',
+        substring(s FROM 1 FOR 4), substring(s FROM 5 FOR 2), substring(s FROM 7 FOR 2),
+        substring(s FROM 9 FOR 2), substring(s FROM 11 FOR 2), substring(s FROM 13 FOR 2)
+    )::timestamp AT TIME ZONE 'UTC';
+END;
+$$ LANGUAGE plpgsql IMMUTABLE STRICT;
+
+-- (NULL, NULL) if the certificate can't be parsed.
+CREATE FUNCTION ctcrab_x509_validity(der bytea, OUT not_before timestamp with time zone, OUT not_after timestamp with time zone) AS $$
+DECLARE
+    h record;
+    pos integer;
+BEGIN
+    h := ctcrab_der_header(der, 0); -- Certificate
+    h := ctcrab_der_header(der, h.content_start); -- TBSCertificate
+    pos := h.content_start;
+    h := ctcrab_der_header(der, pos);
+    IF h.tag = 160 THEN -- [0] version
+        pos := h.content_start + h.content_len;
+    END IF;
+    FOR i IN 1..3 LOOP -- serialNumber, signature, issuer
+        h := ctcrab_der_header(der, pos);
+        pos := h.content_start + h.content_len;
+    END LOOP;
+    h := ctcrab_der_header(der, pos); -- Validity
+    IF h.tag <> 48 THEN
+        RETURN;
+    END IF;
+    not_before := ctcrab_der_time(der, h.content_start);
+    h := ctcrab_der_header(der, h.content_start);
+    not_after := ctcrab_der_time(der, h.content_start + h.content_len);
+EXCEPTION WHEN others THEN
+    not_before := NULL;
+    not_after := NULL;
+END;
+$$ LANGUAGE plpgsql IMMUTABLE STRICT;
+
+UPDATE certificates SET ("not_before", "not_after") = (
+    SELECT v.not_before, v.not_after FROM ctcrab_x509_validity("x509") v
+);
+
+CREATE INDEX certificates_by_not_after ON certificates ("not_after");
+CREATE INDEX certificates_by_not_before ON certificates ("not_before");
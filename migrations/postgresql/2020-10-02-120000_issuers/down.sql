@@ -0,0 +1,3 @@
+DROP INDEX certificates_by_issuer;
+ALTER TABLE certificates DROP COLUMN "issuer_id";
+DROP TABLE issuers;
@@ -0,0 +1,13 @@
+CREATE TABLE issuers (
+    "id" bigserial UNIQUE NOT NULL PRIMARY KEY,
+    "issuer_dn" text NOT NULL,
+    "authority_key_id" bytea NOT NULL, -- empty if the certificates don't have one
+    "spki_sha256" bytea NOT NULL -- of the issuer's SubjectPublicKeyInfo, empty if no chain was logged
+);
+
+CREATE UNIQUE INDEX issuers_i ON issuers ("issuer_dn", "authority_key_id", "spki_sha256");
+
+-- Filled in at insert time, or by `ctcrab reindex` for certificates inserted before this.
+ALTER TABLE certificates ADD COLUMN "issuer_id" bigint DEFAULT NULL REFERENCES issuers("id");
+
+CREATE INDEX certificates_by_issuer ON certificates ("issuer_id", "fingerprint");
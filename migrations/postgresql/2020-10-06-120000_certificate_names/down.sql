@@ -0,0 +1,13 @@
+CREATE TABLE certificate_dns_names (
+    "__pk" bigserial UNIQUE NOT NULL PRIMARY KEY,
+    "cert_fp" bytea NOT NULL REFERENCES "certificates"("fingerprint"),
+    "dns_name" text NOT NULL
+);
+
+INSERT INTO certificate_dns_names ("cert_fp", "dns_name")
+    SELECT "cert_fp", "name" FROM certificate_names WHERE "name_type" = 'dns';
+
+CREATE INDEX certificate_dns_names_suffix_ind ON certificate_dns_names (reverse("dns_name"));
+CREATE UNIQUE INDEX certificate_dns_names_dup_check ON certificate_dns_names ("cert_fp", "dns_name");
+
+DROP TABLE certificate_names;
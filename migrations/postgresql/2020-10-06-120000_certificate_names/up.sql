@@ -0,0 +1,17 @@
+CREATE TABLE certificate_names (
+    "__pk" bigserial UNIQUE NOT NULL PRIMARY KEY,
+    "cert_fp" bytea NOT NULL REFERENCES "certificates"("fingerprint"),
+    "name_type" text NOT NULL CHECK ("name_type" IN ('dns', 'ip', 'email', 'uri')),
+    "name" text NOT NULL,
+    "ip" bytea DEFAULT NULL -- 4 or 16 bytes, for name_type = 'ip'
+);
+
+INSERT INTO certificate_names ("cert_fp", "name_type", "name")
+    SELECT "cert_fp", 'dns', "dns_name" FROM certificate_dns_names;
+
+DROP TABLE certificate_dns_names;
+
+CREATE UNIQUE INDEX certificate_names_dup_check ON certificate_names ("cert_fp", "name_type", "name");
+CREATE INDEX certificate_names_by_name ON certificate_names ("name_type", "name");
+CREATE INDEX certificate_names_suffix_ind ON certificate_names ("name_type", reverse("name"));
+CREATE INDEX certificate_names_by_ip ON certificate_names ("ip") WHERE "ip" IS NOT NULL;
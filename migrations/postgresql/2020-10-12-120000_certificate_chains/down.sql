@@ -0,0 +1,17 @@
+CREATE TABLE certificate_chain (
+    "__pk" bigserial UNIQUE NOT NULL PRIMARY KEY,
+    "certificate_fingerprint" bytea NOT NULL REFERENCES certificates("fingerprint"),
+    "chain" bytea[] NOT NULL -- der blobs of parents
+);
+
+-- One chain per certificate, as before. Intermediates are left in certificates.
+INSERT INTO certificate_chain ("certificate_fingerprint", "chain")
+    SELECT DISTINCT ON (a."cert_fp") a."cert_fp",
+        ARRAY(SELECT c."x509" FROM unnest(ch."fingerprints") WITH ORDINALITY t(fp, i)
+              JOIN certificates c ON c."fingerprint" = t.fp ORDER BY t.i)
+    FROM certificate_appears_in_leaf a JOIN certificate_chains ch ON ch."id" = a."chain_id"
+    ORDER BY a."cert_fp", a."__pk";
+
+DROP INDEX certificate_appears_in_leaf_by_chain;
+ALTER TABLE certificate_appears_in_leaf DROP COLUMN "chain_id";
+DROP TABLE certificate_chains;
@@ -0,0 +1,37 @@
+CREATE TABLE certificate_chains (
+    "id" bigserial UNIQUE NOT NULL PRIMARY KEY,
+    "chain_hash" bytea UNIQUE NOT NULL, -- sha256 of the concatenated fingerprints
+    "fingerprints" bytea[] NOT NULL -- of the parents, in submission order, all in certificates
+);
+
+ALTER TABLE certificate_appears_in_leaf
+    ADD COLUMN "chain_id" bigint DEFAULT NULL REFERENCES certificate_chains("id");
+
+-- Intermediates and roots become ordinary certificates. Their issuer_id is left for
+-- `ctcrab reindex`.
+INSERT INTO certificates ("fingerprint", "x509", "not_before", "not_after")
+    SELECT DISTINCT ON (sha256(d)) sha256(d), d, v.not_before, v.not_after
+    FROM certificate_chain, unnest("chain") d, ctcrab_x509_validity(d) v
+    ON CONFLICT DO NOTHING;
+
+CREATE TEMPORARY TABLE old_chains AS
+    SELECT "__pk", "certificate_fingerprint",
+        ARRAY(SELECT sha256(d) FROM unnest("chain") WITH ORDINALITY t(d, i) ORDER BY i) AS "fingerprints",
+        sha256(coalesce((SELECT string_agg(sha256(d), ''::bytea ORDER BY i) FROM unnest("chain") WITH ORDINALITY t(d, i)), ''::bytea)) AS "chain_hash"
+    FROM certificate_chain;
+
+INSERT INTO certificate_chains ("chain_hash", "fingerprints")
+    SELECT DISTINCT ON ("chain_hash") "chain_hash", "fingerprints" FROM old_chains
+    ON CONFLICT DO NOTHING;
+
+-- Only the first chain seen for each certificate was kept, so that is the best we can do.
+UPDATE certificate_appears_in_leaf a SET "chain_id" = c."id"
+    FROM (SELECT DISTINCT ON ("certificate_fingerprint") "certificate_fingerprint", "chain_hash"
+          FROM old_chains ORDER BY "certificate_fingerprint", "__pk") o
+    JOIN certificate_chains c ON c."chain_hash" = o."chain_hash"
+    WHERE a."cert_fp" = o."certificate_fingerprint";
+
+DROP TABLE old_chains;
+DROP TABLE certificate_chain;
+
+CREATE INDEX certificate_appears_in_leaf_by_chain ON certificate_appears_in_leaf ("chain_id");
@@ -0,0 +1,67 @@
+ALTER TABLE certificate_names RENAME TO certificate_names_old;
+ALTER TABLE certificate_appears_in_leaf RENAME TO certificate_appears_in_leaf_old;
+ALTER TABLE certificates RENAME TO certificates_old;
+ALTER TABLE certificates_old RENAME CONSTRAINT certificates_pkey TO certificates_old_pkey;
+ALTER TABLE certificate_names_old RENAME CONSTRAINT certificate_names_pkey TO certificate_names_old_pkey;
+ALTER TABLE certificate_appears_in_leaf_old RENAME CONSTRAINT certificate_appears_in_leaf_pkey TO certificate_appears_in_leaf_old_pkey;
+DROP INDEX certificates_by_issuer, certificates_by_not_after, certificates_by_not_before,
+    certificate_names_dup_check, certificate_names_by_name, certificate_names_suffix_ind, certificate_names_by_ip,
+    certificate_appears_in_leaf_by_cert, certificate_appears_in_leaf_by_leaf, certificate_appears_in_leaf_by_leaf_hash,
+    certificate_appears_in_leaf_by_chain;
+
+CREATE TABLE certificates (
+    "fingerprint" bytea NOT NULL PRIMARY KEY,
+    "x509" bytea NOT NULL,
+    "not_before" timestamptz DEFAULT NULL,
+    "not_after" timestamptz DEFAULT NULL,
+    "issuer_id" bigint DEFAULT NULL REFERENCES issuers("id")
+);
+INSERT INTO certificates SELECT "fingerprint", "x509", "not_before", "not_after", "issuer_id" FROM certificates_old;
+
+CREATE TABLE certificate_names (
+    "__pk" bigint NOT NULL PRIMARY KEY DEFAULT nextval('certificate_names___pk_seq'),
+    "cert_fp" bytea NOT NULL REFERENCES "certificates"("fingerprint"),
+    "name_type" text NOT NULL CHECK ("name_type" IN ('dns', 'ip', 'email', 'uri')),
+    "name" text NOT NULL,
+    "ip" bytea DEFAULT NULL
+);
+ALTER SEQUENCE certificate_names___pk_seq OWNED BY certificate_names."__pk";
+INSERT INTO certificate_names SELECT "__pk", "cert_fp", "name_type", "name", "ip" FROM certificate_names_old;
+
+CREATE TABLE certificate_appears_in_leaf (
+    "__pk" bigint NOT NULL PRIMARY KEY DEFAULT nextval('certificate_appears_in_leaf___pk_seq'),
+    "leaf_hash" bytea NOT NULL,
+    "cert_fp" bytea NOT NULL REFERENCES "certificates"("fingerprint"),
+    "log_id" bytea NOT NULL REFERENCES ctlogs("log_id"),
+    "leaf_index" bigint NOT NULL,
+    "chain_id" bigint DEFAULT NULL REFERENCES certificate_chains("id")
+);
+ALTER SEQUENCE certificate_appears_in_leaf___pk_seq OWNED BY certificate_appears_in_leaf."__pk";
+INSERT INTO certificate_appears_in_leaf SELECT "__pk", "leaf_hash", "cert_fp", "log_id", "leaf_index", "chain_id" FROM certificate_appears_in_leaf_old;
+
+-- Partitions that were detached by `ctcrab partitions retire-log` are left alone.
+DROP TABLE certificate_names_old;
+DROP TABLE certificate_appears_in_leaf_old;
+DROP TABLE certificates_old;
+DROP TABLE certificate_fingerprints;
+
+CREATE INDEX certificates_by_issuer ON certificates ("issuer_id", "fingerprint");
+CREATE INDEX certificates_by_not_after ON certificates ("not_after");
+CREATE INDEX certificates_by_not_before ON certificates ("not_before");
+
+CREATE UNIQUE INDEX certificate_names_dup_check ON certificate_names ("cert_fp", "name_type", "name");
+CREATE INDEX certificate_names_by_name ON certificate_names ("name_type", "name");
+CREATE INDEX certificate_names_suffix_ind ON certificate_names ("name_type", reverse("name"));
+CREATE INDEX certificate_names_by_ip ON certificate_names ("ip") WHERE "ip" IS NOT NULL;
+
+CREATE INDEX certificate_appears_in_leaf_by_cert ON certificate_appears_in_leaf ("cert_fp", "log_id");
+CREATE UNIQUE INDEX certificate_appears_in_leaf_by_leaf ON certificate_appears_in_leaf ("log_id", "leaf_index");
+CREATE UNIQUE INDEX certificate_appears_in_leaf_by_leaf_hash ON certificate_appears_in_leaf ("log_id", "leaf_hash");
+CREATE INDEX certificate_appears_in_leaf_by_chain ON certificate_appears_in_leaf ("chain_id");
+
+DROP TRIGGER ctlogs_create_partition ON ctlogs;
+DROP FUNCTION ctcrab_ctlogs_create_partition();
+DROP FUNCTION ctcrab_retire_log_partition(bytea, boolean);
+DROP FUNCTION ctcrab_create_log_partition(bytea);
+DROP FUNCTION ctcrab_log_partition_name(bytea);
+DROP FUNCTION ctcrab_create_month_partitions(timestamptz);
@@ -0,0 +1,160 @@
+-- certificates and certificate_names are partitioned by the month in which the certificate was
+-- first ingested, and certificate_appears_in_leaf by log. A unique index on a partitioned table
+-- must include the partition key, so the uniqueness of fingerprints is enforced by
+-- certificate_fingerprints instead, which is also what foreign keys now reference.
+
+CREATE TABLE certificate_fingerprints (
+    "fingerprint" bytea NOT NULL PRIMARY KEY,
+    -- When certificates existing before this migration were ingested is unknown, so they all get
+    -- the time of the migration.
+    "ingested_at" timestamptz NOT NULL DEFAULT now()
+);
+INSERT INTO certificate_fingerprints ("fingerprint") SELECT "fingerprint" FROM certificates;
+
+-- Monthly partitions, in UTC, from the current month up to the one containing `upto`. Rows
+-- outside of every monthly partition go to the default partition, and a monthly partition can
+-- not be created once the default partition has rows for it, so this must be called ahead of
+-- time (`ctcrab serve` does so every few hours).
+CREATE FUNCTION ctcrab_create_month_partitions(upto timestamptz) RETURNS integer AS $$
+DECLARE
+    t text;
+    m timestamp;
+    part text;
+    created integer := 0;
+BEGIN
+    PERFORM pg_advisory_xact_lock(hashtext('ctcrab_create_month_partitions'));
+    FOREACH t IN ARRAY ARRAY['certificates', 'certificate_names'] LOOP
+        m := date_trunc('month', now() AT TIME ZONE 'UTC');
+        WHILE m <= upto AT TIME ZONE 'UTC' LOOP
+            part := t || to_char(m, '"_y"YYYY"m"MM');
+            IF to_regclass(part) IS NULL THEN
+                EXECUTE format('CREATE TABLE %I PARTITION OF %I FOR VALUES FROM (%L) TO (%L)',
+                    part, t, m AT TIME ZONE 'UTC', (m + interval '1 month') AT TIME ZONE 'UTC');
+                created := created + 1;
+            END IF;
+            m := m + interval '1 month';
+        END LOOP;
+    END LOOP;
+    RETURN created;
+END
+$$ LANGUAGE plpgsql;
+
+CREATE FUNCTION ctcrab_log_partition_name(log_id bytea) RETURNS text AS $$
+    SELECT 'certificate_appears_in_leaf_' || encode(substring(log_id FROM 1 FOR 8), 'hex')
+$$ LANGUAGE sql IMMUTABLE;
+
+CREATE FUNCTION ctcrab_create_log_partition(log_id bytea) RETURNS boolean AS $$
+DECLARE
+    part text := ctcrab_log_partition_name(log_id);
+BEGIN
+    IF to_regclass(part) IS NOT NULL THEN
+        RETURN false;
+    END IF;
+    EXECUTE format('CREATE TABLE %I PARTITION OF certificate_appears_in_leaf FOR VALUES IN (%L)', part, log_id);
+    RETURN true;
+END
+$$ LANGUAGE plpgsql;
+
+-- Detach the partition holding the appearances of this log, so that they are no longer seen by
+-- queries, and drop it if asked to. Returns the name of the partition.
+CREATE FUNCTION ctcrab_retire_log_partition(log_id bytea, drop_data boolean) RETURNS text AS $$
+DECLARE
+    part text := ctcrab_log_partition_name(log_id);
+BEGIN
+    IF to_regclass(part) IS NULL THEN
+        RAISE EXCEPTION 'No partition % for this log.', part;
+    END IF;
+    IF EXISTS (SELECT 1 FROM pg_inherits WHERE inhrelid = part::regclass AND inhparent = 'certificate_appears_in_leaf'::regclass) THEN
+        EXECUTE format('ALTER TABLE certificate_appears_in_leaf DETACH PARTITION %I', part);
+    END IF;
+    IF drop_data THEN
+        EXECUTE format('DROP TABLE %I', part);
+    END IF;
+    RETURN part;
+END
+$$ LANGUAGE plpgsql;
+
+CREATE FUNCTION ctcrab_ctlogs_create_partition() RETURNS trigger AS $$
+BEGIN
+    PERFORM ctcrab_create_log_partition(NEW."log_id");
+    RETURN NULL;
+END
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER ctlogs_create_partition AFTER INSERT ON ctlogs
+    FOR EACH ROW EXECUTE FUNCTION ctcrab_ctlogs_create_partition();
+
+-- Move the existing rows into partitioned tables.
+
+ALTER TABLE certificate_names RENAME TO certificate_names_old;
+ALTER TABLE certificate_appears_in_leaf RENAME TO certificate_appears_in_leaf_old;
+ALTER TABLE certificates RENAME TO certificates_old;
+ALTER TABLE certificates_old RENAME CONSTRAINT certificates_pkey TO certificates_old_pkey;
+ALTER TABLE certificate_names_old RENAME CONSTRAINT certificate_names_pkey TO certificate_names_old_pkey;
+ALTER TABLE certificate_appears_in_leaf_old RENAME CONSTRAINT certificate_appears_in_leaf_pkey TO certificate_appears_in_leaf_old_pkey;
+
+CREATE TABLE certificates (
+    "fingerprint" bytea NOT NULL REFERENCES certificate_fingerprints("fingerprint"),
+    "x509" bytea NOT NULL,
+    "not_before" timestamptz DEFAULT NULL,
+    "not_after" timestamptz DEFAULT NULL,
+    "issuer_id" bigint DEFAULT NULL REFERENCES issuers("id"),
+    "ingested_at" timestamptz NOT NULL,
+    PRIMARY KEY ("fingerprint", "ingested_at")
+) PARTITION BY RANGE ("ingested_at");
+CREATE TABLE certificates_default PARTITION OF certificates DEFAULT;
+
+CREATE TABLE certificate_names (
+    "__pk" bigint NOT NULL DEFAULT nextval('certificate_names___pk_seq'),
+    "cert_fp" bytea NOT NULL REFERENCES certificate_fingerprints("fingerprint"),
+    "name_type" text NOT NULL CHECK ("name_type" IN ('dns', 'ip', 'email', 'uri')),
+    "name" text NOT NULL,
+    "ip" bytea DEFAULT NULL,
+    -- That of the certificate.
+    "ingested_at" timestamptz NOT NULL,
+    PRIMARY KEY ("__pk", "ingested_at")
+) PARTITION BY RANGE ("ingested_at");
+CREATE TABLE certificate_names_default PARTITION OF certificate_names DEFAULT;
+ALTER SEQUENCE certificate_names___pk_seq OWNED BY certificate_names."__pk";
+
+CREATE TABLE certificate_appears_in_leaf (
+    "__pk" bigint NOT NULL DEFAULT nextval('certificate_appears_in_leaf___pk_seq'),
+    "leaf_hash" bytea NOT NULL,
+    "cert_fp" bytea NOT NULL REFERENCES certificate_fingerprints("fingerprint"),
+    "log_id" bytea NOT NULL REFERENCES ctlogs("log_id"),
+    "leaf_index" bigint NOT NULL,
+    "chain_id" bigint DEFAULT NULL REFERENCES certificate_chains("id"),
+    PRIMARY KEY ("log_id", "__pk")
+) PARTITION BY LIST ("log_id");
+CREATE TABLE certificate_appears_in_leaf_default PARTITION OF certificate_appears_in_leaf DEFAULT;
+ALTER SEQUENCE certificate_appears_in_leaf___pk_seq OWNED BY certificate_appears_in_leaf."__pk";
+
+SELECT ctcrab_create_log_partition("log_id") FROM ctlogs;
+SELECT ctcrab_create_month_partitions(now() + interval '3 months');
+
+INSERT INTO certificates ("fingerprint", "x509", "not_before", "not_after", "issuer_id", "ingested_at")
+    SELECT c."fingerprint", c."x509", c."not_before", c."not_after", c."issuer_id", f."ingested_at"
+    FROM certificates_old c JOIN certificate_fingerprints f USING ("fingerprint");
+INSERT INTO certificate_names ("__pk", "cert_fp", "name_type", "name", "ip", "ingested_at")
+    SELECT n."__pk", n."cert_fp", n."name_type", n."name", n."ip", f."ingested_at"
+    FROM certificate_names_old n JOIN certificate_fingerprints f ON f."fingerprint" = n."cert_fp";
+INSERT INTO certificate_appears_in_leaf ("__pk", "leaf_hash", "cert_fp", "log_id", "leaf_index", "chain_id")
+    SELECT "__pk", "leaf_hash", "cert_fp", "log_id", "leaf_index", "chain_id" FROM certificate_appears_in_leaf_old;
+
+DROP TABLE certificate_names_old;
+DROP TABLE certificate_appears_in_leaf_old;
+DROP TABLE certificates_old;
+
+CREATE INDEX certificates_by_issuer ON certificates ("issuer_id", "fingerprint");
+CREATE INDEX certificates_by_not_after ON certificates ("not_after");
+CREATE INDEX certificates_by_not_before ON certificates ("not_before");
+
+CREATE UNIQUE INDEX certificate_names_dup_check ON certificate_names ("cert_fp", "name_type", "name", "ingested_at");
+CREATE INDEX certificate_names_by_name ON certificate_names ("name_type", "name");
+CREATE INDEX certificate_names_suffix_ind ON certificate_names ("name_type", reverse("name"));
+CREATE INDEX certificate_names_by_ip ON certificate_names ("ip") WHERE "ip" IS NOT NULL;
+
+CREATE INDEX certificate_appears_in_leaf_by_cert ON certificate_appears_in_leaf ("cert_fp", "log_id");
+CREATE UNIQUE INDEX certificate_appears_in_leaf_by_leaf ON certificate_appears_in_leaf ("log_id", "leaf_index");
+CREATE UNIQUE INDEX certificate_appears_in_leaf_by_leaf_hash ON certificate_appears_in_leaf ("log_id", "leaf_hash");
+CREATE INDEX certificate_appears_in_leaf_by_chain ON certificate_appears_in_leaf ("chain_id");
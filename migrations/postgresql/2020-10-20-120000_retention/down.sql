@@ -0,0 +1,3 @@
+DROP TABLE prune_runs;
+DROP INDEX sth_by_received_time;
+ALTER TABLE sth DROP COLUMN "was_latest";
@@ -0,0 +1,20 @@
+-- Set whenever ctlogs.latest_sth is set to this sth. Which sth were latest before this migration
+-- is unknown, but latest_sth is always checked, so all checked sth are assumed to have been.
+ALTER TABLE sth ADD COLUMN "was_latest" boolean NOT NULL DEFAULT false;
+UPDATE sth SET "was_latest" = true WHERE "checked_consistent_with_latest";
+-- To find the first sth of each hour.
+CREATE INDEX sth_by_received_time ON sth ("log_id", "received_time");
+
+CREATE TABLE prune_runs (
+    "id" bigserial NOT NULL PRIMARY KEY,
+    "started_at" timestamptz NOT NULL DEFAULT now(),
+    "finished_at" timestamptz DEFAULT NULL, -- NULL if the run failed or is still going
+    "dry_run" boolean NOT NULL,
+    -- The settings used.
+    "sth_days" integer NOT NULL,
+    "expired_certificate_days" integer DEFAULT NULL,
+    "watchlist" text[] NOT NULL,
+    -- What was (or with dry_run, would have been) deleted.
+    "sth_deleted" bigint NOT NULL DEFAULT 0,
+    "certificates_deleted" bigint NOT NULL DEFAULT 0
+);
@@ -0,0 +1,3 @@
+DROP INDEX certificate_appears_in_leaf_by_ingest_seq;
+ALTER TABLE certificate_appears_in_leaf DROP COLUMN "ingest_seq";
+DROP TABLE ingest_counter;
@@ -0,0 +1,15 @@
+-- Position of each appearance in the order ingestion committed them, for consumers of /changes.
+-- Numbers are taken from ingest_counter, whose single row stays locked by the ingesting
+-- transaction until it commits, so an appearance can never become visible after one with a larger
+-- ingest_seq. There may be gaps. Appearances from before this migration are numbered by __pk.
+CREATE TABLE ingest_counter (
+    "id" boolean NOT NULL PRIMARY KEY DEFAULT true CHECK ("id"),
+    "last_seq" bigint NOT NULL
+);
+
+ALTER TABLE certificate_appears_in_leaf ADD COLUMN "ingest_seq" bigint;
+UPDATE certificate_appears_in_leaf SET "ingest_seq" = "__pk";
+ALTER TABLE certificate_appears_in_leaf ALTER COLUMN "ingest_seq" SET NOT NULL;
+INSERT INTO ingest_counter ("last_seq") SELECT coalesce(max("ingest_seq"), 0) FROM certificate_appears_in_leaf;
+
+CREATE INDEX certificate_appears_in_leaf_by_ingest_seq ON certificate_appears_in_leaf ("ingest_seq");
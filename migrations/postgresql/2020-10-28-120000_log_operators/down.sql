@@ -0,0 +1 @@
+ALTER TABLE ctlogs DROP COLUMN "operator";
@@ -0,0 +1,2 @@
+-- From the log list, filled in the next time it is read.
+ALTER TABLE ctlogs ADD COLUMN "operator" text DEFAULT NULL;
@@ -0,0 +1,12 @@
+CREATE TABLE api_keys (
+    "id" bigserial NOT NULL PRIMARY KEY,
+    "name" text NOT NULL,
+    -- sha256 of the key. The key itself is only shown when it is created.
+    "key_hash" bytea NOT NULL UNIQUE,
+    -- Any of read, search, webhooks and admin.
+    "scopes" text[] NOT NULL,
+    -- Requests per minute, instead of rate_limit.key_per_minute.
+    "per_minute" integer DEFAULT NULL,
+    "created_at" timestamptz NOT NULL DEFAULT now(),
+    "revoked_at" timestamptz DEFAULT NULL
+);
@@ -0,0 +1,8 @@
+-- Logs whose backfilled merkle frontier did not match their sth. Backfilling them stops until the
+-- row is deleted (`ctcrab retry-backfill`).
+CREATE TABLE merkle_backfill_failures (
+    "log_id" bytea NOT NULL PRIMARY KEY REFERENCES ctlogs("log_id"),
+    "tree_size" bigint NOT NULL,
+    "error_msg" text NOT NULL,
+    "failed_at" timestamp with time zone NOT NULL DEFAULT now()
+);
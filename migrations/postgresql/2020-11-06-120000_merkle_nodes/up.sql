@@ -0,0 +1,10 @@
+-- Roots of the perfect subtrees of mirrored logs, every 8th level above the leaves: node
+-- `node_index` at `level` covers leaves [node_index << level, (node_index + 1) << level). Each
+-- level is kept from node 0 without gaps.
+CREATE TABLE merkle_nodes (
+    "log_id" bytea NOT NULL REFERENCES ctlogs("log_id"),
+    "level" smallint NOT NULL,
+    "node_index" bigint NOT NULL,
+    "hash" bytea NOT NULL,
+    PRIMARY KEY ("log_id", "level", "node_index")
+);
@@ -0,0 +1,2 @@
+DROP INDEX certificate_chains_by_fingerprint;
+ALTER TABLE certificates DROP COLUMN "chain_only";
@@ -0,0 +1,9 @@
+-- Certificates only ever seen as the parent of a logged certificate (intermediates and roots).
+-- They are kept for chains and issuers, but left out of certificate listings and issuer counts.
+ALTER TABLE certificates
+    ADD COLUMN "chain_only" boolean NOT NULL DEFAULT false;
+UPDATE certificates c SET "chain_only" = true
+    WHERE NOT EXISTS (SELECT 1 FROM certificate_appears_in_leaf a WHERE a."cert_fp" = c."fingerprint");
+
+-- For finding the chains a certificate is a parent in.
+CREATE INDEX certificate_chains_by_fingerprint ON certificate_chains USING GIN ("fingerprints");
@@ -0,0 +1,3 @@
+DROP INDEX issuers_by_issuer_dn;
+DROP INDEX issuers_by_cert_count;
+ALTER TABLE issuers DROP COLUMN "cert_count";
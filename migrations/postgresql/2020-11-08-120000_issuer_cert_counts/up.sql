@@ -0,0 +1,11 @@
+-- Number of certificates (not chain-only) with this issuer, kept up to date on insert and prune.
+ALTER TABLE issuers
+    ADD COLUMN "cert_count" bigint NOT NULL DEFAULT 0;
+UPDATE issuers i SET "cert_count" = c."count"
+    FROM (SELECT "issuer_id", count(*) AS "count" FROM certificates
+          WHERE "issuer_id" IS NOT NULL AND NOT "chain_only" GROUP BY "issuer_id") c
+    WHERE c."issuer_id" = i."id";
+
+-- For paging through /issuers.
+CREATE INDEX issuers_by_cert_count ON issuers ("cert_count", "id");
+CREATE INDEX issuers_by_issuer_dn ON issuers ("issuer_dn", "id");
@@ -0,0 +1,344 @@
+//! Who may call which route, and how often. Routes take an `Authorized<R>` guard, where `R` says
+//! what scope is needed and how much a call costs.
+//!
+//! The API key is taken from `Authorization: Bearer <key>` or `X-API-Key` (or `?key=` on the stream
+//! server, for WebSocket and EventSource clients, which can't set headers). Requests without one
+//! have `api.anonymous_scopes`. Each key, and each client ip without a key, has a token bucket
+//! refilled at its per-minute rate; requests that find it empty get a 429 with `Retry-After`.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use rocket::{Catcher, Outcome, Request, State};
+use rocket::http::Status;
+use rocket::request::{self, FromRequest};
+use rocket::response::{self, Responder};
+use serde_json::json;
+
+use crate::core::api_keys::{self, Scope};
+use crate::core::config::{Config, RateLimitConfig};
+use crate::core::context::CtCrabContext;
+use crate::core::db::DBPool;
+use crate::core::stream_server::Authorize;
+
+use super::{APIError, ErrorCode};
+
+/// Buckets kept before idle ones are forgotten.
+const MAX_BUCKETS: usize = 10000;
+/// Tokens taken by a connection to the stream server, which then lasts.
+const STREAM_COST: u32 = 5;
+
+pub trait Requirement {
+  /// `None` for routes anyone may call.
+  const SCOPE: Option<Scope>;
+  /// Tokens taken from the bucket.
+  const COST: u32;
+}
+
+/// Log status routes.
+pub enum Public {}
+
+impl Requirement for Public {
+  const SCOPE: Option<Scope> = None;
+  const COST: u32 = 1;
+}
+
+pub enum Read {}
+
+impl Requirement for Read {
+  const SCOPE: Option<Scope> = Some(Scope::Read);
+  const COST: u32 = 1;
+}
+
+/// Bulk exports, which go through many pages of certificates.
+pub enum Bulk {}
+
+impl Requirement for Bulk {
+  const SCOPE: Option<Scope> = Some(Scope::Read);
+  const COST: u32 = 20;
+}
+
+/// Full-text search, which ranks every hit.
+pub enum Search {}
+
+impl Requirement for Search {
+  const SCOPE: Option<Scope> = Some(Scope::Search);
+  const COST: u32 = 5;
+}
+
+#[derive(Debug, Clone, Error)]
+pub enum AuthError {
+  #[error("Unknown or revoked API key.")]
+  InvalidKey,
+  #[error("This needs an API key with the {0} scope.")]
+  KeyRequired(Scope),
+  #[error("This API key does not have the {0} scope.")]
+  MissingScope(Scope),
+  #[error("Too many requests, retry in {0} s.")]
+  RateLimited(u64),
+  #[error("Unable to check the API key.")]
+  Internal,
+}
+
+impl AuthError {
+  fn status(&self) -> Status {
+    match self {
+      AuthError::InvalidKey | AuthError::KeyRequired(_) => Status::Unauthorized,
+      AuthError::MissingScope(_) => Status::Forbidden,
+      AuthError::RateLimited(_) => Status::TooManyRequests,
+      AuthError::Internal => Status::InternalServerError,
+    }
+  }
+}
+
+impl ErrorCode for AuthError {
+  fn code(&self) -> Cow<'static, str> {
+    match self {
+      AuthError::InvalidKey => "invalid_api_key",
+      AuthError::KeyRequired(_) => "api_key_required",
+      AuthError::MissingScope(_) => "missing_scope",
+      AuthError::RateLimited(_) => "rate_limited",
+      AuthError::Internal => "internal_error",
+    }.into()
+  }
+
+  fn details(&self) -> Option<serde_json::Value> {
+    match *self {
+      AuthError::KeyRequired(scope) | AuthError::MissingScope(scope) => Some(json!({ "scope": scope.as_str() })),
+      AuthError::RateLimited(secs) => Some(json!({ "retry_after": secs })),
+      _ => None
+    }
+  }
+}
+
+impl<'r> Responder<'r> for AuthError {
+  fn respond_to(self, request: &Request) -> response::Result<'r> {
+    let retry_after = match self {
+      AuthError::RateLimited(secs) => Some(secs),
+      _ => None
+    };
+    let mut res = APIError(self.status().code, Box::new(self)).respond_to(request)?;
+    if let Some(secs) = retry_after {
+      res.set_raw_header("Retry-After", secs.to_string());
+    }
+    Ok(res)
+  }
+}
+
+/// The error of the guard that failed, for the catchers, which can't otherwise see it.
+struct GuardError(Option<AuthError>);
+
+fn fail<T>(request: &Request, e: AuthError) -> request::Outcome<T, AuthError> {
+  request.local_cache(|| GuardError(Some(e.clone())));
+  Outcome::Failure((e.status(), e))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Client {
+  Key(i64),
+  Ip(IpAddr),
+}
+
+struct Bucket {
+  tokens: f64,
+  updated: Instant,
+}
+
+struct RateLimiter {
+  burst_seconds: u32,
+  buckets: Mutex<HashMap<Client, Bucket>>,
+}
+
+impl RateLimiter {
+  fn new(config: &RateLimitConfig) -> RateLimiter {
+    RateLimiter { burst_seconds: config.burst_seconds, buckets: Mutex::new(HashMap::new()) }
+  }
+
+  fn capacity(&self, per_minute: u32, cost: u32) -> f64 {
+    (per_minute as f64 * self.burst_seconds as f64 / 60.0).max(cost as f64)
+  }
+
+  /// Take `cost` tokens from the bucket of `client`, which refills at `per_minute`. If there are
+  /// not enough, returns how long until there are.
+  fn take(&self, client: Client, per_minute: u32, cost: u32, now: Instant) -> Result<(), Duration> {
+    let capacity = self.capacity(per_minute, cost);
+    let per_second = per_minute as f64 / 60.0;
+    let mut buckets = self.buckets.lock().unwrap();
+    if buckets.len() >= MAX_BUCKETS {
+      // Buckets idle for burst_seconds are full again (bar costs above the burst), the same as no
+      // bucket.
+      let idle = Duration::from_secs(self.burst_seconds as u64);
+      buckets.retain(|_, b| now.saturating_duration_since(b.updated) < idle);
+    }
+    let b = buckets.entry(client).or_insert(Bucket { tokens: capacity, updated: now });
+    b.tokens = (b.tokens + now.saturating_duration_since(b.updated).as_secs_f64() * per_second).min(capacity);
+    b.updated = now;
+    if b.tokens >= cost as f64 {
+      b.tokens -= cost as f64;
+      Ok(())
+    } else {
+      Err(Duration::from_secs_f64((cost as f64 - b.tokens) / per_second))
+    }
+  }
+}
+
+/// Managed by Rocket, from the config, and shared with the stream server.
+pub struct Access {
+  anonymous_scopes: Vec<Scope>,
+  trusted_proxies: Vec<IpAddr>,
+  limits: Option<(RateLimitConfig, RateLimiter)>,
+}
+
+impl Access {
+  pub fn new(config: &Config) -> Access {
+    Access {
+      // Checked by Config::validate.
+      anonymous_scopes: config.api.anonymous_scopes.iter().filter_map(|s| s.parse().ok()).collect(),
+      trusted_proxies: config.rate_limit.trusted_proxies.clone(),
+      limits: if config.rate_limit.enabled {
+        Some((config.rate_limit.clone(), RateLimiter::new(&config.rate_limit)))
+      } else {
+        None
+      },
+    }
+  }
+}
+
+/// Proof that the request may call a route needing `R`.
+pub struct Authorized<R>(PhantomData<R>);
+
+pub(super) fn api_key<'a>(request: &'a Request) -> Option<&'a str> {
+  request.headers().get_one("Authorization")
+      .and_then(|v| v.strip_prefix("Bearer "))
+      .or_else(|| request.headers().get_one("X-API-Key"))
+      .map(|k| k.trim())
+}
+
+impl Access {
+  /// The ip of the client connected from `peer`: the `X-Real-IP` header if `peer` is a trusted
+  /// proxy, otherwise `peer` itself.
+  pub fn client_ip(&self, peer: Option<IpAddr>, real_ip: Option<&str>) -> Option<IpAddr> {
+    match peer {
+      Some(p) if self.trusted_proxies.contains(&p) => real_ip.and_then(|h| h.trim().parse().ok()).or(peer),
+      _ => peer
+    }
+  }
+
+  /// Look up `key` (if any), check that it (or anonymous access) has `scope`, and take `cost`
+  /// from the bucket of the key or of `ip`.
+  fn check(&self, db_pool: &DBPool, key: Option<&str>, ip: Option<IpAddr>, scope: Option<Scope>, cost: u32) -> Result<(), AuthError> {
+    let key = match key {
+      Some(k) => {
+        let found = db_pool.get().map_err(|e| e.to_string()).and_then(|db| api_keys::find(&db, k).map_err(|e| e.to_string()));
+        match found {
+          Ok(Some(key)) => Some(key),
+          Ok(None) => return Err(AuthError::InvalidKey),
+          Err(e) => {
+            log::error!("Unable to look up an API key: {}", e);
+            return Err(AuthError::Internal);
+          }
+        }
+      },
+      None => None
+    };
+    if let Some(needed) = scope {
+      let scopes = key.as_ref().map(|k| &k.scopes[..]).unwrap_or(&self.anonymous_scopes[..]);
+      if !scopes.iter().any(|s| s.grants(needed)) {
+        return Err(if key.is_some() { AuthError::MissingScope(needed) } else { AuthError::KeyRequired(needed) });
+      }
+    }
+    if let Some((ref config, ref limiter)) = self.limits {
+      let client = match (&key, ip) {
+        (Some(k), _) => Some((Client::Key(k.id), k.per_minute.unwrap_or(config.key_per_minute))),
+        (None, Some(ip)) => Some((Client::Ip(ip), config.ip_per_minute)),
+        (None, None) => None
+      };
+      if let Some((client, per_minute)) = client {
+        if let Err(wait) = limiter.take(client, per_minute.max(1), cost, Instant::now()) {
+          return Err(AuthError::RateLimited(wait.as_secs() + 1));
+        }
+      }
+    }
+    Ok(())
+  }
+}
+
+impl<'a, 'r, R: Requirement> FromRequest<'a, 'r> for Authorized<R> {
+  type Error = AuthError;
+
+  fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, AuthError> {
+    let (access, ctx) = match (request.guard::<State<Arc<Access>>>(), request.guard::<State<CtCrabContext>>()) {
+      (Outcome::Success(a), Outcome::Success(c)) => (a, c),
+      _ => return fail(request, AuthError::Internal)
+    };
+    let ip = access.client_ip(request.remote().map(|a| a.ip()), request.headers().get_one("X-Real-IP"));
+    match access.check(ctx.db_pool(), api_key(request), ip, R::SCOPE, R::COST) {
+      Ok(()) => Outcome::Success(Authorized(PhantomData)),
+      Err(e) => fail(request, e)
+    }
+  }
+}
+
+/// For `core::stream_server`, which is not served by Rocket: the same checks as `Authorized`, against
+/// the same buckets as the API.
+pub fn stream_authorizer(access: Arc<Access>, db_pool: DBPool) -> Arc<Authorize> {
+  Arc::new(move |key: Option<&str>, peer: Option<IpAddr>, real_ip: Option<&str>, scope: Option<Scope>| {
+    let ip = access.client_ip(peer, real_ip);
+    access.check(&db_pool, key, ip, scope, STREAM_COST).map_err(|e| (e.status().code, e.to_string()))
+  })
+}
+
+fn guard_error(request: &Request, otherwise: AuthError) -> AuthError {
+  request.local_cache(|| GuardError(None)).0.clone().unwrap_or(otherwise)
+}
+
+#[catch(401)]
+fn unauthorized(request: &Request) -> AuthError {
+  guard_error(request, AuthError::InvalidKey)
+}
+
+#[catch(403)]
+fn forbidden(request: &Request) -> AuthError {
+  guard_error(request, AuthError::MissingScope(Scope::Admin))
+}
+
+#[catch(429)]
+fn too_many_requests(request: &Request) -> AuthError {
+  guard_error(request, AuthError::RateLimited(1))
+}
+
+pub fn auth_catchers() -> Vec<Catcher> {
+  catchers![unauthorized, forbidden, too_many_requests]
+}
+
+#[test]
+fn test_token_bucket() {
+  let limiter = RateLimiter { burst_seconds: 10, buckets: Mutex::new(HashMap::new()) };
+  let client = Client::Key(1);
+  let t0 = Instant::now();
+  // 60 per minute with 10 s of burst: 10 at once, then one a second.
+  for _ in 0..10 {
+    assert!(limiter.take(client, 60, 1, t0).is_ok());
+  }
+  assert_eq!(limiter.take(client, 60, 1, t0), Err(Duration::from_secs(1)));
+  assert!(limiter.take(Client::Key(2), 60, 1, t0).is_ok());
+  assert!(limiter.take(client, 60, 1, t0 + Duration::from_secs(1)).is_ok());
+  assert!(limiter.take(client, 60, 1, t0 + Duration::from_secs(1)).is_err());
+  // A cost above the burst still gets through from a full bucket.
+  assert!(limiter.take(Client::Key(3), 60, 20, t0).is_ok());
+  assert_eq!(limiter.take(Client::Key(3), 60, 20, t0), Err(Duration::from_secs(20)));
+}
+
+#[test]
+fn test_client_ip() {
+  let proxy: IpAddr = "10.0.0.1".parse().unwrap();
+  let other: IpAddr = "192.0.2.1".parse().unwrap();
+  let access = Access { anonymous_scopes: Vec::new(), trusted_proxies: vec![proxy], limits: None };
+  assert_eq!(access.client_ip(Some(proxy), Some("198.51.100.7")), Some("198.51.100.7".parse().unwrap()));
+  assert_eq!(access.client_ip(Some(proxy), None), Some(proxy));
+  assert_eq!(access.client_ip(Some(other), Some("198.51.100.7")), Some(other));
+}
@@ -0,0 +1,140 @@
+//! Conditional requests: responses carry an ETag, and a request whose `If-None-Match` has it gets
+//! an empty 304 instead. They also say how long caches may keep them, publicly unless the request
+//! came with an API key.
+
+use ctclient::internal::re_exports::openssl::sha::Sha256;
+use rocket::{Outcome, Request, Response};
+use rocket::http::Status;
+use rocket::request::{self, FromRequest};
+use rocket::response::{self, Responder};
+use rocket_contrib::json::Json;
+use serde::Serialize;
+
+use crate::core::context::CtCrabContext;
+
+use super::auth::api_key;
+
+/// A year, the most RFC 7234 recommends.
+const FOREVER_SECONDS: u32 = 365 * 24 * 3600;
+
+/// A strong ETag, from a hash of whatever the response is built from.
+pub struct ETag(String);
+
+impl ETag {
+  pub fn of<'a>(parts: impl IntoIterator<Item = &'a [u8]>) -> ETag {
+    let mut h = Sha256::new();
+    for p in parts {
+      h.update(&(p.len() as u64).to_be_bytes());
+      h.update(p);
+    }
+    ETag(format!("\"{}\"", ctclient::utils::u8_to_hex(&h.finish()[..16])))
+  }
+
+  /// From a hash of the JSON of `value`, such as the rows a response is built from.
+  pub fn of_json<T: Serialize>(value: &T) -> ETag {
+    ETag::of(std::iter::once(&serde_json::to_vec(value).unwrap_or_default()[..]))
+  }
+
+  /// Whether an `If-None-Match` value lists this tag. Weak comparison, as RFC 7232 requires for
+  /// `If-None-Match`.
+  fn matched_by(&self, if_none_match: &str) -> bool {
+    if_none_match.split(',').map(|t| t.trim()).any(|t| {
+      t == "*" || t.trim_start_matches("W/") == self.0
+    })
+  }
+}
+
+/// The `If-None-Match` values of the request, for handlers that can tell the ETag before doing the
+/// work of building the response.
+pub struct IfNoneMatch(Vec<String>);
+
+impl IfNoneMatch {
+  pub fn matches(&self, etag: &ETag) -> bool {
+    self.0.iter().any(|v| etag.matched_by(v))
+  }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for IfNoneMatch {
+  type Error = ();
+
+  fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, ()> {
+    Outcome::Success(IfNoneMatch(request.headers().get("If-None-Match").map(|v| v.to_owned()).collect()))
+  }
+}
+
+/// How long a response may be cached without checking its ETag again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lifetime {
+  /// It never changes.
+  Immutable,
+  MaxAge(u32),
+}
+
+impl Lifetime {
+  fn cache_control(self, private: bool) -> String {
+    let who = if private { "private" } else { "public" };
+    match self {
+      Lifetime::Immutable => format!("{}, max-age={}, immutable", who, FOREVER_SECONDS),
+      Lifetime::MaxAge(secs) => format!("{}, max-age={}", who, secs),
+    }
+  }
+}
+
+/// For responses that change as logs are updated, `api.cache_max_age_seconds`.
+pub fn short_lifetime(ctx: &CtCrabContext) -> Lifetime {
+  Lifetime::MaxAge(ctx.config().api.cache_max_age_seconds)
+}
+
+pub struct Cached<R> {
+  pub etag: ETag,
+  pub lifetime: Lifetime,
+  pub inner: R,
+}
+
+impl<T: Serialize> Cached<Json<T>> {
+  /// Tagged with a hash of the JSON body.
+  pub fn json(value: T, lifetime: Lifetime) -> Cached<Json<T>> {
+    Cached { etag: ETag::of_json(&value), lifetime, inner: Json(value) }
+  }
+}
+
+impl<R> Cached<Option<R>> {
+  /// For when `IfNoneMatch` already matched `etag`: a 304, without building the response.
+  pub fn not_modified(etag: ETag, lifetime: Lifetime) -> Cached<Option<R>> {
+    Cached { etag, lifetime, inner: None }
+  }
+}
+
+impl<'r, R: Responder<'r>> Responder<'r> for Cached<R> {
+  fn respond_to(self, request: &Request) -> response::Result<'r> {
+    // Anything a request without a key may see, anyone may.
+    let cache_control = self.lifetime.cache_control(api_key(request).is_some());
+    if request.headers().get("If-None-Match").any(|v| self.etag.matched_by(v)) {
+      return Response::build()
+          .status(Status::NotModified)
+          .raw_header("ETag", self.etag.0)
+          .raw_header("Cache-Control", cache_control)
+          .ok();
+    }
+    Response::build_from(self.inner.respond_to(request)?)
+        .raw_header("ETag", self.etag.0)
+        .raw_header("Cache-Control", cache_control)
+        .ok()
+  }
+}
+
+#[test]
+fn test_etag_matching() {
+  let tag = ETag::of(vec![&b"a"[..], &b"bc"[..]]);
+  assert_ne!(tag.0, ETag::of(vec![&b"ab"[..], &b"c"[..]]).0);
+  assert!(tag.matched_by(&tag.0));
+  assert!(tag.matched_by(&format!("\"x\", W/{}", tag.0)));
+  assert!(tag.matched_by("*"));
+  assert!(!tag.matched_by("\"x\""));
+}
+
+#[test]
+fn test_cache_control() {
+  assert_eq!(Lifetime::MaxAge(10).cache_control(false), "public, max-age=10");
+  assert_eq!(Lifetime::Immutable.cache_control(true), "private, max-age=31536000, immutable");
+}
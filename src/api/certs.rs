@@ -0,0 +1,264 @@
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::error::Error;
+
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use diesel::prelude::*;
+use rocket::State;
+use rocket::http::RawStr;
+use rocket::http::uri::Origin;
+use rocket_contrib::json::Json;
+use schemars::JsonSchema;
+use serde::Serialize;
+
+use crate::core::certinfo::NameType;
+use crate::core::context::CtCrabContext;
+use crate::core::db::DBConn;
+use crate::models::{Hash, HashFromStrError};
+
+use super::{APIError, ErrorCode, NotFound, TimestampMs};
+use super::auth::{Authorized, Read};
+use super::caching::{Cached, Lifetime, short_lifetime};
+use super::list::{self, Page, Sort, SortKey};
+use super::names::name_filter;
+
+/// An error code and a message.
+#[derive(Debug, Error)]
+#[error("{1}")]
+pub(super) struct BadRequest(pub &'static str, pub &'static str);
+
+impl ErrorCode for BadRequest {
+  fn code(&self) -> Cow<'static, str> {
+    self.0.into()
+  }
+}
+
+#[derive(Serialize, JsonSchema, Clone)]
+pub struct CertInfo {
+  pub(super) fingerprint: Hash,
+  pub(super) not_before: Option<TimestampMs>,
+  pub(super) not_after: Option<TimestampMs>,
+  pub(super) dns_names: Vec<String>,
+  pub(super) ip_addresses: Vec<String>,
+  pub(super) emails: Vec<String>,
+  pub(super) uris: Vec<String>,
+}
+
+/// For lists of certificates that can only be sorted by fingerprint.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct FingerprintSort;
+
+impl SortKey for FingerprintSort {
+  fn from_name(name: &str) -> Option<Self> {
+    if name == "fingerprint" { Some(FingerprintSort) } else { None }
+  }
+
+  fn name(self) -> &'static str {
+    "fingerprint"
+  }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum CertSort {
+  NotAfter,
+  Fingerprint,
+}
+
+impl SortKey for CertSort {
+  fn from_name(name: &str) -> Option<Self> {
+    match name {
+      "not_after" => Some(CertSort::NotAfter),
+      "fingerprint" => Some(CertSort::Fingerprint),
+      _ => None
+    }
+  }
+
+  fn name(self) -> &'static str {
+    match self {
+      CertSort::NotAfter => "not_after",
+      CertSort::Fingerprint => "fingerprint",
+    }
+  }
+}
+
+/// Certificates matching all given filters, a page at a time (see `list`). Only certificates
+/// logged as a leaf are listed, not the CA certificates of their chains.
+///
+/// * `dns`: `example.com` matches that name exactly, `*.example.com` matches any name under
+///   `example.com` (including wildcard names).
+/// * `expiring_within_days`: not yet expired, but will be within this many days.
+/// * `valid_at`: ms timestamp within the validity period.
+/// * `sort`: `not_after` (soonest expiring first, the default) or `fingerprint`. Certificates
+///   without a validity period come last, or first with `-not_after`.
+#[get("/certs?<dns>&<expiring_within_days>&<valid_at>&<sort>&<cursor>&<limit>")]
+pub fn certs(dns: Option<&RawStr>, expiring_within_days: Option<u32>, valid_at: Option<i64>, sort: Option<&RawStr>, cursor: Option<&RawStr>,
+             limit: Option<i64>, origin: &Origin, ctx: State<CtCrabContext>, _auth: Authorized<Read>) -> Result<Json<Page<CertInfo>>, APIError> {
+  let db = ctx.db()?;
+  let limit = list::check_limit(limit)?;
+  if dns.is_none() && expiring_within_days.is_none() && valid_at.is_none() {
+    return Err(APIError(400, Box::new(BadRequest("missing_filter", "Expected at least one of dns, expiring_within_days or valid_at."))));
+  }
+  let sort = Sort::parse(sort, Sort { key: CertSort::NotAfter, desc: false }, "Expected sort not_after or fingerprint.")?;
+  // ms timestamp of not_after (if sorting by it), and fingerprint, of the last certificate.
+  let after: Option<(Option<i64>, Hash)> = list::decode_cursor(cursor, &sort)?;
+
+  use crate::schema::certificates::dsl as c;
+  use crate::schema::certificate_names::dsl as n;
+  let mut query = c::certificates
+      .select((c::fingerprint, c::not_before, c::not_after))
+      .filter(c::chain_only.eq(false))
+      .into_boxed();
+  if let Some(dns) = dns {
+    let dns = dns.url_decode().map_err(|_| APIError(400, Box::new(BadRequest("invalid_dns", "Invalid dns."))))?;
+    query = query.filter(c::fingerprint.eq_any(
+      n::certificate_names.select(n::cert_fp).filter(name_filter(NameType::Dns, &dns)?)
+    ));
+  }
+  if let Some(days) = expiring_within_days {
+    let now = Utc::now();
+    query = query.filter(c::not_after.ge(now).and(c::not_after.lt(now + Duration::days(days as i64))));
+  }
+  if let Some(t) = valid_at {
+    let t: DateTime<Utc> = Utc.timestamp_millis_opt(t).single()
+        .ok_or_else(|| APIError(400, Box::new(BadRequest("invalid_valid_at", "Invalid valid_at."))))?;
+    query = query.filter(c::not_before.le(t).and(c::not_after.ge(t)));
+  }
+  let invalid_cursor = || APIError(400, Box::new(BadRequest("invalid_cursor", "Invalid cursor.")));
+  // Postgres puts nulls last in ascending order, and first in descending order.
+  query = match (sort.key, sort.desc, after) {
+    (CertSort::NotAfter, false, Some((Some(t), fp))) => {
+      let t = Utc.timestamp_millis_opt(t).single().ok_or_else(invalid_cursor)?;
+      query.filter(c::not_after.gt(t).or(c::not_after.eq(t).and(c::fingerprint.gt(fp))).or(c::not_after.is_null()))
+    },
+    (CertSort::NotAfter, false, Some((None, fp))) => query.filter(c::not_after.is_null().and(c::fingerprint.gt(fp))),
+    (CertSort::NotAfter, true, Some((Some(t), fp))) => {
+      let t = Utc.timestamp_millis_opt(t).single().ok_or_else(invalid_cursor)?;
+      query.filter(c::not_after.lt(t).or(c::not_after.eq(t).and(c::fingerprint.lt(fp))))
+    },
+    (CertSort::NotAfter, true, Some((None, fp))) => query.filter(c::not_after.is_null().and(c::fingerprint.lt(fp)).or(c::not_after.is_not_null())),
+    (CertSort::Fingerprint, false, Some((_, fp))) => query.filter(c::fingerprint.gt(fp)),
+    (CertSort::Fingerprint, true, Some((_, fp))) => query.filter(c::fingerprint.lt(fp)),
+    (_, _, None) => query
+  };
+  query = match (sort.key, sort.desc) {
+    (CertSort::NotAfter, false) => query.order_by((c::not_after.asc(), c::fingerprint.asc())),
+    (CertSort::NotAfter, true) => query.order_by((c::not_after.desc(), c::fingerprint.desc())),
+    (CertSort::Fingerprint, false) => query.order_by(c::fingerprint.asc()),
+    (CertSort::Fingerprint, true) => query.order_by(c::fingerprint.desc()),
+  };
+  let rows: Vec<(Hash, Option<DateTime<Utc>>, Option<DateTime<Utc>>)> = query
+      .limit(limit + 1)
+      .load(&db).map_err(|e| Box::new(e) as Box<dyn Error>)?;
+  let page = list::page(rows, limit, &sort, origin, |r| match sort.key {
+    CertSort::NotAfter => (r.2.map(|t| t.timestamp_millis()), r.0),
+    CertSort::Fingerprint => (None, r.0),
+  });
+  Ok(Json(Page { items: load_cert_infos(&db, page.items)?, cursor: page.cursor, next: page.next }))
+}
+
+/// Names of the logs each certificate appears in.
+pub(super) fn log_names(db: &DBConn, fps: &[Hash]) -> Result<BTreeMap<Hash, Vec<String>>, Box<dyn Error>> {
+  use crate::schema::certificate_appears_in_leaf::dsl as a;
+  use crate::schema::ctlogs::dsl as l;
+  let rows: Vec<(Hash, String)> = a::certificate_appears_in_leaf
+      .inner_join(l::ctlogs)
+      .select((a::cert_fp, l::name))
+      .filter(a::cert_fp.eq_any(fps))
+      .distinct()
+      .order_by((a::cert_fp, l::name))
+      .load(db)?;
+  let mut res: BTreeMap<Hash, Vec<String>> = BTreeMap::new();
+  for (fp, name) in rows {
+    res.entry(fp).or_default().push(name);
+  }
+  Ok(res)
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct CertDetail {
+  #[serde(flatten)]
+  pub(super) cert: CertInfo,
+  pub(super) issuer: Option<String>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct CertLogs {
+  /// Names of the logs the certificate appears in.
+  logs: Vec<String>,
+}
+
+/// A certificate and its issuer. The logs it appears in, which change, are at `/cert/<fp>/logs`.
+#[get("/cert/<fp>")]
+pub fn cert(fp: Result<Hash, HashFromStrError>, ctx: State<CtCrabContext>, _auth: Authorized<Read>) -> Result<Cached<Json<CertDetail>>, APIError> {
+  let fp = fp?;
+  let db = ctx.db()?;
+  use crate::schema::certificates::dsl as c;
+  use crate::schema::issuers::dsl as i;
+  let row: Option<(Hash, Option<DateTime<Utc>>, Option<DateTime<Utc>>, bool, Option<String>)> = c::certificates
+      .left_join(i::issuers)
+      .select((c::fingerprint, c::not_before, c::not_after, c::chain_only, i::issuer_dn.nullable()))
+      .filter(c::fingerprint.eq(fp))
+      .first(&db).optional().map_err(|e| Box::new(e) as Box<dyn Error>)?;
+  let (fp, not_before, not_after, chain_only, issuer) = row.ok_or_else(|| APIError(404, Box::new(NotFound("certificate"))))?;
+  let cert = load_cert_infos(&db, vec![(fp, not_before, not_after)])?.remove(0);
+  // Certificates do not change, but the names of a chain-only certificate are only stored once it
+  // is logged itself, and expired certificates can be pruned.
+  let lifetime = if chain_only || ctx.config().retention.expired_certificate_days.is_some() {
+    short_lifetime(&ctx)
+  } else {
+    Lifetime::Immutable
+  };
+  Ok(Cached::json(CertDetail { cert, issuer }, lifetime))
+}
+
+#[get("/cert/<fp>/logs")]
+pub fn cert_logs(fp: Result<Hash, HashFromStrError>, ctx: State<CtCrabContext>, _auth: Authorized<Read>) -> Result<Cached<Json<CertLogs>>, APIError> {
+  let fp = fp?;
+  let db = ctx.db()?;
+  let logs = log_names(&db, &[fp])?.remove(&fp).unwrap_or_default();
+  if logs.is_empty() {
+    use crate::schema::certificate_fingerprints::dsl as f;
+    let exists = f::certificate_fingerprints.select(f::fingerprint).filter(f::fingerprint.eq(fp))
+        .first::<Hash>(&db).optional().map_err(|e| Box::new(e) as Box<dyn Error>)?.is_some();
+    if !exists {
+      return Err(APIError(404, Box::new(NotFound("certificate"))));
+    }
+  }
+  Ok(Cached::json(CertLogs { logs }, short_lifetime(&ctx)))
+}
+
+/// Attach the names to `(fingerprint, not_before, not_after)` rows.
+pub(super) fn load_cert_infos(db: &DBConn, rows: Vec<(Hash, Option<DateTime<Utc>>, Option<DateTime<Utc>>)>) -> Result<Vec<CertInfo>, APIError> {
+  use crate::schema::certificate_names::dsl as n;
+  let fps: Vec<Hash> = rows.iter().map(|r| r.0).collect();
+  let names: Vec<(Hash, String, String)> = n::certificate_names
+      .select((n::cert_fp, n::name_type, n::name))
+      .filter(n::cert_fp.eq_any(fps))
+      .order_by(n::name.asc())
+      .load(db).map_err(|e| Box::new(e) as Box<dyn Error>)?;
+  let mut names_by_fp: BTreeMap<Hash, Vec<(String, String)>> = BTreeMap::new();
+  for (fp, name_type, name) in names {
+    names_by_fp.entry(fp).or_default().push((name_type, name));
+  }
+  Ok(rows.into_iter().map(|(fingerprint, not_before, not_after)| {
+    let mut info = CertInfo {
+      fingerprint,
+      not_before: not_before.map(TimestampMs),
+      not_after: not_after.map(TimestampMs),
+      dns_names: Vec::new(),
+      ip_addresses: Vec::new(),
+      emails: Vec::new(),
+      uris: Vec::new(),
+    };
+    for (name_type, name) in names_by_fp.remove(&fingerprint).unwrap_or_default() {
+      match name_type.parse() {
+        Ok(NameType::Dns) => info.dns_names.push(name),
+        Ok(NameType::Ip) => info.ip_addresses.push(name),
+        Ok(NameType::Email) => info.emails.push(name),
+        Ok(NameType::Uri) => info.uris.push(name),
+        Err(()) => {}
+      }
+    }
+    info
+  }).collect())
+}
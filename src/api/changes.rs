@@ -0,0 +1,79 @@
+//! Appearances of certificates in logs, in the order they were stored, for consumers that want
+//! everything ingested since their last run.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use rocket::State;
+use rocket_contrib::json::Json;
+use schemars::JsonSchema;
+use serde::Serialize;
+
+use crate::core::context::CtCrabContext;
+use crate::models::Hash;
+
+use super::APIError;
+use super::auth::{Authorized, Read};
+use super::certs::{BadRequest, CertInfo, load_cert_infos};
+
+const DEFAULT_LIMIT: i64 = 100;
+const MAX_LIMIT: i64 = 1000;
+
+#[derive(Serialize, JsonSchema)]
+pub struct Change {
+  seq: i64,
+  log_id: Hash,
+  leaf_index: i64,
+  cert: CertInfo,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct Changes {
+  changes: Vec<Change>,
+  /// Pass as `since` to get what comes after. The same as `since` if there was nothing new.
+  next: i64,
+}
+
+/// Appearances stored after `since` (0, or the `next` of a previous call), oldest first.
+///
+/// Each appearance is numbered when its transaction commits, so one can't show up later with a
+/// number lower than what was already returned: following `next` sees everything exactly once.
+/// Numbers may have gaps. Appearances deleted by pruning are not reported.
+#[get("/changes?<since>&<limit>")]
+pub fn changes(since: Option<i64>, limit: Option<i64>, ctx: State<CtCrabContext>, _auth: Authorized<Read>) -> Result<Json<Changes>, APIError> {
+  let db = ctx.db()?;
+  let since = since.unwrap_or(0);
+  let limit = limit.unwrap_or(DEFAULT_LIMIT);
+  if limit <= 0 || limit > MAX_LIMIT {
+    return Err(APIError(400, Box::new(BadRequest("invalid_limit", "Expected 0 < limit <= 1000."))));
+  }
+  use crate::schema::certificate_appears_in_leaf::dsl as a;
+  use crate::schema::certificates::dsl as c;
+  let rows: Vec<(i64, Hash, i64, Hash, Option<DateTime<Utc>>, Option<DateTime<Utc>>)> = a::certificate_appears_in_leaf
+      .inner_join(c::certificates)
+      .select((a::ingest_seq, a::log_id, a::leaf_index, c::fingerprint, c::not_before, c::not_after))
+      .filter(a::ingest_seq.gt(since))
+      .order_by(a::ingest_seq.asc())
+      .limit(limit)
+      .load(&db).map_err(|e| Box::new(e) as Box<dyn Error>)?;
+  let next = rows.last().map(|r| r.0).unwrap_or(since);
+
+  // A certificate may appear in several logs.
+  let mut distinct = BTreeMap::new();
+  for r in &rows {
+    distinct.entry(r.3).or_insert((r.3, r.4, r.5));
+  }
+  let certs: BTreeMap<Hash, CertInfo> = load_cert_infos(&db, distinct.into_iter().map(|(_, r)| r).collect())?
+      .into_iter().map(|info| (info.fingerprint, info)).collect();
+  Ok(Json(Changes {
+    changes: rows.into_iter().map(|(seq, log_id, leaf_index, fp, ..)| Change {
+      seq,
+      log_id,
+      leaf_index,
+      cert: certs[&fp].clone(),
+    }).collect(),
+    next,
+  }))
+}
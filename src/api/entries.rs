@@ -0,0 +1,62 @@
+use std::borrow::Cow;
+use std::error::Error;
+
+use diesel::prelude::*;
+use rocket::State;
+use rocket_contrib::json::Json;
+
+use crate::core::context::CtCrabContext;
+use crate::core::db::DBConn;
+use crate::core::raw_entries::{decompress, GetEntriesJson, LeafEntryJson};
+use crate::models::{Hash, HashFromStrError, RawLeaf};
+
+use super::{APIError, ErrorCode, NotFound};
+use super::auth::{Authorized, Read};
+
+const MAX_ENTRIES: i64 = 1000;
+
+#[derive(Debug, Error)]
+#[error("Expected 0 <= start <= end.")]
+pub(super) struct InvalidRange;
+
+impl ErrorCode for InvalidRange {
+  fn code(&self) -> Cow<'static, str> {
+    "invalid_range".into()
+  }
+}
+
+/// Stored raw entries `[start, end]` (or less), stopping at the first missing one.
+pub(super) fn load_entries(db: &DBConn, id: &Hash, start: i64, end: i64) -> Result<Vec<LeafEntryJson>, APIError> {
+  if start < 0 || end < start {
+    return Err(APIError(400, Box::new(InvalidRange)));
+  }
+  let end = std::cmp::min(end, start + MAX_ENTRIES - 1);
+  use crate::schema::raw_leaves::dsl::*;
+  let rows: Vec<RawLeaf> = raw_leaves
+      .filter(log_id.eq(id).and(leaf_index.between(start, end)))
+      .order_by(leaf_index.asc())
+      .load(db).map_err(|e| Box::new(e) as Box<dyn Error>)?;
+  let mut entries = Vec::with_capacity(rows.len());
+  for (expected_index, row) in (start..).zip(rows) {
+    if row.leaf_index != expected_index {
+      break;
+    }
+    entries.push(LeafEntryJson {
+      leaf_input: base64::encode(&decompress(&row.leaf_input).map_err(|e| Box::new(e) as Box<dyn Error>)?),
+      extra_data: base64::encode(&decompress(&row.extra_data).map_err(|e| Box::new(e) as Box<dyn Error>)?),
+    });
+  }
+  Ok(entries)
+}
+
+/// Raw entries stored for a log, in get-entries format. Like get-entries, `end` is inclusive and
+/// fewer entries than asked for may be returned. Only available for logs with `store_raw_leaves`.
+#[get("/log/<id>/entries?<start>&<end>")]
+pub fn raw_entries(id: Result<Hash, HashFromStrError>, start: i64, end: i64, ctx: State<CtCrabContext>, _auth: Authorized<Read>) -> Result<Json<GetEntriesJson>, APIError> {
+  let id = id?;
+  let entries = load_entries(&ctx.db()?, &id, start, end)?;
+  if entries.is_empty() {
+    return Err(APIError(404, Box::new(NotFound("entry"))));
+  }
+  Ok(Json(GetEntriesJson { entries }))
+}
@@ -0,0 +1,248 @@
+//! Bulk export of every certificate matching a filter, streamed a page at a time so that memory
+//! use does not depend on the size of the export.
+
+use std::error::Error;
+use std::io::{self, Read};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use diesel::prelude::*;
+use rocket::State;
+use rocket::http::{ContentType, RawStr};
+use rocket::response::{Content, Stream};
+use serde::Serialize;
+
+use crate::core::certinfo::NameType;
+use crate::core::context::CtCrabContext;
+use crate::core::db::{DBConn, DBPool};
+use crate::models::Hash;
+
+use super::APIError;
+use super::auth::{Authorized, Bulk};
+use super::certs::{BadRequest, CertDetail, load_cert_infos, log_names};
+use super::names::name_filter;
+
+/// Certificates fetched from the database at a time.
+const PAGE_SIZE: i64 = 500;
+
+#[derive(Debug, Clone, Copy)]
+enum Format {
+  Ndjson,
+  Csv,
+  Pem,
+}
+
+impl Format {
+  fn content_type(self) -> ContentType {
+    match self {
+      Format::Ndjson => ContentType::new("application", "x-ndjson"),
+      Format::Csv => ContentType::new("text", "csv"),
+      Format::Pem => ContentType::new("application", "x-pem-file"),
+    }
+  }
+}
+
+struct Filter {
+  dns: Option<String>,
+  issuer: Option<i64>,
+}
+
+type Row = (Hash, Vec<u8>, Option<DateTime<Utc>>, Option<DateTime<Utc>>, Option<String>);
+
+fn load_page(db: &DBConn, filter: &Filter, after: Option<Hash>, limit: i64) -> Result<Vec<Row>, Box<dyn Error>> {
+  use crate::schema::certificates::dsl as c;
+  use crate::schema::certificate_names::dsl as n;
+  use crate::schema::issuers::dsl as i;
+  let mut query = c::certificates
+      .left_join(i::issuers)
+      .select((c::fingerprint, c::x509, c::not_before, c::not_after, i::issuer_dn.nullable()))
+      .filter(c::chain_only.eq(false))
+      .into_boxed();
+  if let Some(ref dns) = filter.dns {
+    let names = name_filter(NameType::Dns, dns).map_err(|e| e.1.to_string())?;
+    query = query.filter(c::fingerprint.eq_any(n::certificate_names.select(n::cert_fp).filter(names)));
+  }
+  if let Some(id) = filter.issuer {
+    query = query.filter(c::issuer_id.eq(id));
+  }
+  if let Some(after) = after {
+    query = query.filter(c::fingerprint.gt(after));
+  }
+  Ok(query.order_by(c::fingerprint.asc()).limit(limit).load(db)?)
+}
+
+fn csv_field(s: &str) -> String {
+  if s.contains(|c| c == ',' || c == '"' || c == '\n' || c == '\r') {
+    format!("\"{}\"", s.replace('"', "\"\""))
+  } else {
+    s.to_owned()
+  }
+}
+
+fn rfc3339(t: Option<&super::TimestampMs>) -> String {
+  t.map(|t| t.0.to_rfc3339_opts(SecondsFormat::Secs, true)).unwrap_or_default()
+}
+
+fn write_pem(out: &mut Vec<u8>, der: &[u8]) {
+  out.extend_from_slice(b"-----BEGIN CERTIFICATE-----\n");
+  for line in base64::encode(der).as_bytes().chunks(64) {
+    out.extend_from_slice(line);
+    out.push(b'\n');
+  }
+  out.extend_from_slice(b"-----END CERTIFICATE-----\n");
+}
+
+/// A line of the ndjson export: `/cert/<fp>`, with the logs of `/cert/<fp>/logs`.
+#[derive(Serialize)]
+struct Record {
+  #[serde(flatten)]
+  detail: CertDetail,
+  logs: Vec<String>,
+}
+
+/// Produces the export a page at a time, as the response body is read. A connection is only held
+/// while loading a page, not while the client reads it.
+pub struct Export {
+  db_pool: DBPool,
+  format: Format,
+  filter: Filter,
+  after: Option<Hash>,
+  remaining: Option<u64>,
+  buf: Vec<u8>,
+  pos: usize,
+  done: bool,
+}
+
+impl Export {
+  fn fill(&mut self) -> Result<(), Box<dyn Error>> {
+    let limit = match self.remaining {
+      Some(r) => (r as i64).min(PAGE_SIZE),
+      None => PAGE_SIZE,
+    };
+    if limit == 0 {
+      self.done = true;
+      self.buf.clear();
+      self.pos = 0;
+      return Ok(());
+    }
+    let db = self.db_pool.get()?;
+    let rows = load_page(&db, &self.filter, self.after, limit)?;
+    if (rows.len() as i64) < limit {
+      self.done = true;
+    }
+    if let Some(last) = rows.last() {
+      self.after = Some(last.0);
+    }
+    if let Some(ref mut r) = self.remaining {
+      *r -= rows.len() as u64;
+    }
+    self.buf.clear();
+    self.pos = 0;
+    if let Format::Pem = self.format {
+      for (fp, der, ..) in &rows {
+        self.buf.extend_from_slice(format!("fingerprint: {}\n", fp).as_bytes());
+        write_pem(&mut self.buf, der);
+      }
+      return Ok(());
+    }
+    let fps: Vec<Hash> = rows.iter().map(|r| r.0).collect();
+    let mut logs = log_names(&db, &fps)?;
+    let mut issuers: Vec<Option<String>> = Vec::with_capacity(rows.len());
+    let infos = load_cert_infos(&db, rows.into_iter().map(|(fp, _, nb, na, issuer)| {
+      issuers.push(issuer);
+      (fp, nb, na)
+    }).collect()).map_err(|e| e.1.to_string())?;
+    for (cert, issuer) in infos.into_iter().zip(issuers) {
+      let logs = logs.remove(&cert.fingerprint).unwrap_or_default();
+      match self.format {
+        Format::Ndjson => {
+          serde_json::to_writer(&mut self.buf, &Record { detail: CertDetail { cert, issuer }, logs })?;
+          self.buf.push(b'\n');
+        },
+        Format::Csv => {
+          let names = cert.dns_names.iter().chain(&cert.ip_addresses).chain(&cert.emails).chain(&cert.uris)
+              .map(|s| s.as_str()).collect::<Vec<_>>().join(" ");
+          let line = [
+            cert.fingerprint.to_string(),
+            csv_field(&names),
+            csv_field(issuer.as_deref().unwrap_or("")),
+            rfc3339(cert.not_before.as_ref()),
+            rfc3339(cert.not_after.as_ref()),
+            csv_field(&logs.join(";")),
+          ].join(",");
+          self.buf.extend_from_slice(line.as_bytes());
+          self.buf.extend_from_slice(b"\r\n");
+        },
+        Format::Pem => unreachable!()
+      }
+    }
+    Ok(())
+  }
+}
+
+impl Read for Export {
+  fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+    while self.pos == self.buf.len() {
+      if self.done {
+        return Ok(0);
+      }
+      // The status has already been sent, so all we can do is to cut the response short. The
+      // client can resume after the last complete record.
+      self.fill().map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+    }
+    let n = out.len().min(self.buf.len() - self.pos);
+    out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+    self.pos += n;
+    Ok(n)
+  }
+}
+
+/// Every certificate with a dns name matching `dns` (see `/certs`) and/or issued by `issuer`, by
+/// fingerprint, as `ndjson` (the fields of `/certs`, plus the issuer and logs), `csv`
+/// (fingerprint, names, issuer, not_before, not_after, logs) or `pem`. Each PEM block is preceded
+/// by a `fingerprint:` line, which PEM parsers ignore.
+///
+/// An interrupted export can be resumed by passing the last fingerprint received as `after`.
+/// `limit` caps the number of certificates, for exporting in parts.
+#[get("/export/<format>?<dns>&<issuer>&<after>&<limit>")]
+pub fn export(format: &RawStr, dns: Option<&RawStr>, issuer: Option<i64>, after: Option<Hash>, limit: Option<u64>, ctx: State<CtCrabContext>, _auth: Authorized<Bulk>) -> Result<Content<Stream<Export>>, APIError> {
+  let format = match format.as_str() {
+    "ndjson" => Format::Ndjson,
+    "csv" => Format::Csv,
+    "pem" => Format::Pem,
+    _ => return Err(APIError(400, Box::new(BadRequest("unknown_format", "Expected format ndjson, csv or pem."))))
+  };
+  let dns = match dns {
+    Some(dns) => {
+      let dns = dns.url_decode().map_err(|_| APIError(400, Box::new(BadRequest("invalid_dns", "Invalid dns."))))?;
+      name_filter(NameType::Dns, &dns)?;
+      Some(dns)
+    },
+    None => None
+  };
+  if dns.is_none() && issuer.is_none() {
+    return Err(APIError(400, Box::new(BadRequest("missing_filter", "Expected at least one of dns or issuer."))));
+  }
+  let mut export = Export {
+    db_pool: ctx.db_pool().clone(),
+    format,
+    filter: Filter { dns, issuer },
+    after,
+    remaining: limit,
+    buf: Vec::new(),
+    pos: 0,
+    done: false,
+  };
+  if let Format::Csv = format {
+    export.buf.extend_from_slice(b"fingerprint,names,issuer,not_before,not_after,logs\r\n");
+  }
+  Ok(Content(format.content_type(), Stream::from(export)))
+}
+
+#[test]
+fn test_csv_field() {
+  assert_eq!(csv_field("example.com www.example.com"), "example.com www.example.com");
+  assert_eq!(csv_field("CN=Example CA, O=Example"), "\"CN=Example CA, O=Example\"");
+  assert_eq!(csv_field("O=\"Quoted\" Inc"), "\"O=\"\"Quoted\"\" Inc\"");
+  assert_eq!(csv_field("a\nb"), "\"a\nb\"");
+  assert_eq!(csv_field(""), "");
+}
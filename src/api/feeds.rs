@@ -0,0 +1,124 @@
+//! Atom feeds of newly ingested certificates, for feed readers.
+
+use std::error::Error;
+use std::fmt::Write;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use diesel::dsl::{count_star, max};
+use diesel::prelude::*;
+use rocket::State;
+use rocket::http::{ContentType, RawStr};
+use rocket::response::Content;
+
+use crate::core::certinfo::NameType;
+use crate::core::context::CtCrabContext;
+use crate::models::Hash;
+
+use super::APIError;
+use super::auth::{Authorized, Read};
+use super::caching::{Cached, ETag, IfNoneMatch, short_lifetime};
+use super::certs::{BadRequest, load_cert_infos};
+use super::names::name_filter;
+
+/// Entries in a feed.
+const FEED_LEN: i64 = 50;
+
+fn escape_xml(s: &str) -> String {
+  let mut res = String::with_capacity(s.len());
+  for c in s.chars() {
+    match c {
+      '&' => res.push_str("&amp;"),
+      '<' => res.push_str("&lt;"),
+      '>' => res.push_str("&gt;"),
+      '"' => res.push_str("&quot;"),
+      '\'' => res.push_str("&apos;"),
+      c => res.push(c),
+    }
+  }
+  res
+}
+
+fn rfc3339(t: &DateTime<Utc>) -> String {
+  t.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// The most recently ingested certificates for `suffix` (`<suffix>.atom`) or any name under it,
+/// newest first. Links are relative to the feed, and go to `/cert/<fingerprint>`.
+#[get("/feeds/dns/<file>")]
+pub fn dns_feed(file: &RawStr, if_none_match: IfNoneMatch, ctx: State<CtCrabContext>, _auth: Authorized<Read>) -> Result<Cached<Option<Content<String>>>, APIError> {
+  let suffix = file.url_decode().ok()
+      .and_then(|f| f.strip_suffix(".atom").map(|s| s.to_lowercase()))
+      .ok_or_else(|| APIError(404, Box::new(BadRequest("feed_not_found", "Expected /feeds/dns/<domain>.atom."))))?;
+  if suffix.is_empty() || suffix.contains('*') || suffix.starts_with('.') {
+    return Err(APIError(400, Box::new(BadRequest("invalid_domain", "Expected a domain name, without wildcards."))));
+  }
+  let db = ctx.db()?;
+  use crate::schema::certificates::dsl as c;
+  use crate::schema::certificate_names::dsl as n;
+  use crate::schema::issuers::dsl as i;
+  let names = || -> Result<_, APIError> {
+    Ok(name_filter(NameType::Dns, &suffix)?.or(name_filter(NameType::Dns, &format!("*.{}", suffix))?))
+  };
+
+  // The entries only change when a certificate is added or pruned, and certificates do not
+  // change, so the latest ingested_at and the number of names tell whether the feed changed,
+  // without loading it.
+  let (latest, count): (Option<DateTime<Utc>>, i64) = n::certificate_names
+      .select((max(n::ingested_at), count_star()))
+      .filter(names()?)
+      .first(&db).map_err(|e| Box::new(e) as Box<dyn Error>)?;
+  let latest_us = latest.map(|t| t.timestamp_nanos() / 1000).unwrap_or(0);
+  let etag = ETag::of(vec![suffix.as_bytes(), &latest_us.to_be_bytes()[..], &count.to_be_bytes()[..]]);
+  if if_none_match.matches(&etag) {
+    return Ok(Cached::not_modified(etag, short_lifetime(&ctx)));
+  }
+  let rows: Vec<(Hash, Option<DateTime<Utc>>, Option<DateTime<Utc>>, DateTime<Utc>, Option<String>)> = c::certificates
+      .left_join(i::issuers)
+      .select((c::fingerprint, c::not_before, c::not_after, c::ingested_at, i::issuer_dn.nullable()))
+      .filter(c::fingerprint.eq_any(n::certificate_names.select(n::cert_fp).filter(names()?)))
+      .order_by((c::ingested_at.desc(), c::fingerprint.asc()))
+      .limit(FEED_LEN)
+      .load(&db).map_err(|e| Box::new(e) as Box<dyn Error>)?;
+
+  let updated = rows.first().map(|r| r.3).unwrap_or_else(Utc::now);
+  let mut extra = Vec::with_capacity(rows.len());
+  let infos = load_cert_infos(&db, rows.into_iter().map(|(fp, nb, na, ingested_at, issuer)| {
+    extra.push((ingested_at, issuer));
+    (fp, nb, na)
+  }).collect())?;
+
+  let mut xml = String::new();
+  let _ = write!(xml, "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+                      <feed xmlns=\"http://www.w3.org/2005/Atom\">\n\
+                      <id>urn:ctcrab:feeds:dns:{suffix}</id>\n\
+                      <title>New certificates for {suffix}</title>\n\
+                      <updated>{updated}</updated>\n\
+                      <author><name>ctcrab</name></author>\n\
+                      <link rel=\"self\" href=\"{suffix}.atom\"/>\n",
+                 suffix = escape_xml(&suffix), updated = rfc3339(&updated));
+  for (cert, (ingested_at, issuer)) in infos.into_iter().zip(extra) {
+    let title = cert.dns_names.first().cloned().unwrap_or_else(|| cert.fingerprint.to_string());
+    let validity = |t: &Option<super::TimestampMs>| t.as_ref().map(|t| rfc3339(&t.0)).unwrap_or_else(|| "?".to_owned());
+    let content = format!("Issuer: {}\nValid from {} to {}\nNames: {}\nSHA-256 fingerprint: {}",
+                          issuer.as_deref().unwrap_or("unknown"), validity(&cert.not_before), validity(&cert.not_after),
+                          cert.dns_names.iter().chain(&cert.ip_addresses).chain(&cert.emails).chain(&cert.uris)
+                              .map(|s| s.as_str()).collect::<Vec<_>>().join(", "),
+                          cert.fingerprint);
+    let _ = write!(xml, "<entry>\n\
+                        <id>urn:ctcrab:certificate:{fp}</id>\n\
+                        <title>{title}</title>\n\
+                        <updated>{updated}</updated>\n\
+                        <link href=\"../../cert/{fp}\"/>\n\
+                        <content type=\"text\">{content}</content>\n\
+                        </entry>\n",
+                   fp = cert.fingerprint, title = escape_xml(&title), updated = rfc3339(&ingested_at),
+                   content = escape_xml(&content));
+  }
+  xml.push_str("</feed>\n");
+  Ok(Cached { etag, lifetime: short_lifetime(&ctx), inner: Some(Content(ContentType::new("application", "atom+xml"), xml)) })
+}
+
+#[test]
+fn test_escape_xml() {
+  assert_eq!(escape_xml("<a href=\"x\">&'</a>"), "&lt;a href=&quot;x&quot;&gt;&amp;&apos;&lt;/a&gt;");
+}
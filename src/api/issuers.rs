@@ -0,0 +1,145 @@
+use std::error::Error;
+
+use chrono::{DateTime, Utc};
+use diesel::dsl::count_star;
+use diesel::prelude::*;
+use rocket::State;
+use rocket::http::RawStr;
+use rocket::http::uri::Origin;
+use rocket_contrib::json::Json;
+use schemars::JsonSchema;
+use serde::Serialize;
+
+use crate::core::context::CtCrabContext;
+use crate::models::Hash;
+
+use super::{APIError, NotFound};
+use super::auth::{Authorized, Read};
+use super::certs::{CertInfo, FingerprintSort, load_cert_infos};
+use super::list::{self, Page, Sort, SortKey};
+
+#[derive(Serialize, JsonSchema)]
+pub struct IssuerInfo {
+  id: i64,
+  issuer_dn: String,
+  /// Hex, if the certificates have one.
+  authority_key_id: Option<String>,
+  /// Hex sha256 of the issuer's SubjectPublicKeyInfo, if a chain was logged.
+  spki_sha256: Option<String>,
+  cert_count: i64,
+}
+
+fn hex_or_none(b: Vec<u8>) -> Option<String> {
+  if b.is_empty() {
+    None
+  } else {
+    Some(ctclient::utils::u8_to_hex(&b))
+  }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum IssuerSort {
+  CertCount,
+  IssuerDn,
+  Id,
+}
+
+impl SortKey for IssuerSort {
+  fn from_name(name: &str) -> Option<Self> {
+    match name {
+      "cert_count" => Some(IssuerSort::CertCount),
+      "issuer_dn" => Some(IssuerSort::IssuerDn),
+      "id" => Some(IssuerSort::Id),
+      _ => None
+    }
+  }
+
+  fn name(self) -> &'static str {
+    match self {
+      IssuerSort::CertCount => "cert_count",
+      IssuerSort::IssuerDn => "issuer_dn",
+      IssuerSort::Id => "id",
+    }
+  }
+}
+
+/// Every issuer seen, with the number of logged (not chain-only) certificates it issued, a page
+/// at a time (see `list`).
+/// `sort` is `-cert_count` (most prolific first, the default), `issuer_dn` or `id`.
+#[get("/issuers?<sort>&<cursor>&<limit>")]
+pub fn issuers(sort: Option<&RawStr>, cursor: Option<&RawStr>, limit: Option<i64>, origin: &Origin, ctx: State<CtCrabContext>, _auth: Authorized<Read>) -> Result<Json<Page<IssuerInfo>>, APIError> {
+  let db = ctx.db()?;
+  let limit = list::check_limit(limit)?;
+  let sort = Sort::parse(sort, Sort { key: IssuerSort::CertCount, desc: true }, "Expected sort cert_count, issuer_dn or id.")?;
+  // cert_count (if sorting by it), issuer_dn (if sorting by it), and id, of the last issuer.
+  let after: Option<(i64, String, i64)> = list::decode_cursor(cursor, &sort)?;
+  use crate::schema::issuers::dsl::*;
+  let mut query = issuers
+      .select((id, issuer_dn, authority_key_id, spki_sha256, cert_count))
+      .into_boxed();
+  query = match (sort.key, sort.desc, after) {
+    (IssuerSort::CertCount, false, Some((n, _, i))) => query.filter(cert_count.gt(n).or(cert_count.eq(n).and(id.gt(i)))),
+    (IssuerSort::CertCount, true, Some((n, _, i))) => query.filter(cert_count.lt(n).or(cert_count.eq(n).and(id.lt(i)))),
+    (IssuerSort::IssuerDn, false, Some((_, dn, i))) => query.filter(issuer_dn.gt(dn.clone()).or(issuer_dn.eq(dn).and(id.gt(i)))),
+    (IssuerSort::IssuerDn, true, Some((_, dn, i))) => query.filter(issuer_dn.lt(dn.clone()).or(issuer_dn.eq(dn).and(id.lt(i)))),
+    (IssuerSort::Id, false, Some((_, _, i))) => query.filter(id.gt(i)),
+    (IssuerSort::Id, true, Some((_, _, i))) => query.filter(id.lt(i)),
+    (_, _, None) => query
+  };
+  query = match (sort.key, sort.desc) {
+    (IssuerSort::CertCount, false) => query.order_by((cert_count.asc(), id.asc())),
+    (IssuerSort::CertCount, true) => query.order_by((cert_count.desc(), id.desc())),
+    (IssuerSort::IssuerDn, false) => query.order_by((issuer_dn.asc(), id.asc())),
+    (IssuerSort::IssuerDn, true) => query.order_by((issuer_dn.desc(), id.desc())),
+    (IssuerSort::Id, false) => query.order_by(id.asc()),
+    (IssuerSort::Id, true) => query.order_by(id.desc()),
+  };
+  let rows: Vec<(i64, String, Vec<u8>, Vec<u8>, i64)> = query
+      .limit(limit + 1)
+      .load(&db).map_err(|e| Box::new(e) as Box<dyn Error>)?;
+  let res: Vec<IssuerInfo> = rows.into_iter().map(|(i, dn, aki, spki, n)| IssuerInfo {
+    id: i,
+    issuer_dn: dn,
+    authority_key_id: hex_or_none(aki),
+    spki_sha256: hex_or_none(spki),
+    cert_count: n,
+  }).collect();
+  Ok(Json(list::page(res, limit, &sort, origin, |i: &IssuerInfo| match sort.key {
+    IssuerSort::CertCount => (i.cert_count, String::new(), i.id),
+    IssuerSort::IssuerDn => (0, i.issuer_dn.clone(), i.id),
+    IssuerSort::Id => (0, String::new(), i.id),
+  })))
+}
+
+/// Certificates issued by this issuer, by fingerprint (`sort=fingerprint`, the default, or
+/// `-fingerprint`).
+#[get("/issuers/<id>/certs?<sort>&<cursor>&<limit>")]
+pub fn issuer_certs(id: i64, sort: Option<&RawStr>, cursor: Option<&RawStr>, limit: Option<i64>, origin: &Origin, ctx: State<CtCrabContext>, _auth: Authorized<Read>) -> Result<Json<Page<CertInfo>>, APIError> {
+  let db = ctx.db()?;
+  let limit = list::check_limit(limit)?;
+  let sort = Sort::parse(sort, Sort { key: FingerprintSort, desc: false }, "Expected sort fingerprint.")?;
+  let after: Option<Hash> = list::decode_cursor(cursor, &sort)?;
+  let exists: i64 = {
+    use crate::schema::issuers::dsl as i;
+    i::issuers.select(count_star()).filter(i::id.eq(id)).first(&db).map_err(|e| Box::new(e) as Box<dyn Error>)?
+  };
+  if exists == 0 {
+    return Err(APIError(404, Box::new(NotFound("issuer"))));
+  }
+  use crate::schema::certificates::dsl::*;
+  let mut query = certificates
+      .select((fingerprint, not_before, not_after))
+      .filter(issuer_id.eq(id).and(chain_only.eq(false)))
+      .into_boxed();
+  query = match (after, sort.desc) {
+    (Some(after), false) => query.filter(fingerprint.gt(after)).order_by(fingerprint.asc()),
+    (Some(after), true) => query.filter(fingerprint.lt(after)).order_by(fingerprint.desc()),
+    (None, false) => query.order_by(fingerprint.asc()),
+    (None, true) => query.order_by(fingerprint.desc()),
+  };
+  let rows: Vec<(Hash, Option<DateTime<Utc>>, Option<DateTime<Utc>>)> = query
+      .limit(limit + 1)
+      .load(&db).map_err(|e| Box::new(e) as Box<dyn Error>)?;
+  let page = list::page(rows, limit, &sort, origin, |r| r.0);
+  Ok(Json(Page { items: load_cert_infos(&db, page.items)?, cursor: page.cursor, next: page.next }))
+}
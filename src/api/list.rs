@@ -0,0 +1,179 @@
+//! What list endpoints have in common. They take `limit`, `sort` (a key, `-` first for
+//! descending) and `cursor`, and respond with a `Page`.
+//!
+//! A cursor holds the sort key it was made for and the position of the last item returned, so the
+//! next page starts right after that item even if rows were added or removed in between. Cursors
+//! are opaque to clients, which should follow `next`.
+//!
+//! `/changes` is a feed rather than a list, and keeps its own `since` and `next`.
+
+use std::fmt::Debug;
+
+use rocket::http::RawStr;
+use rocket::http::uri::Origin;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+
+use super::APIError;
+use super::certs::BadRequest;
+
+pub const DEFAULT_LIMIT: i64 = 100;
+pub const MAX_LIMIT: i64 = 1000;
+
+#[derive(Serialize, JsonSchema)]
+pub struct Page<T> {
+  pub items: Vec<T>,
+  /// Pass as `cursor`, with the same filters and sort, to get the next page. `None` on the last
+  /// page.
+  pub cursor: Option<String>,
+  /// Path and query of the next page.
+  pub next: Option<String>,
+}
+
+impl<T> Page<T> {
+  pub fn try_map<U, E>(self, f: impl FnMut(T) -> Result<U, E>) -> Result<Page<U>, E> {
+    Ok(Page {
+      items: self.items.into_iter().map(f).collect::<Result<_, _>>()?,
+      cursor: self.cursor,
+      next: self.next,
+    })
+  }
+}
+
+/// Sort keys of an endpoint.
+pub trait SortKey: Sized + Copy + Debug {
+  fn from_name(name: &str) -> Option<Self>;
+  fn name(self) -> &'static str;
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Sort<K> {
+  pub key: K,
+  pub desc: bool,
+}
+
+impl<K: SortKey> Sort<K> {
+  /// `key` or `-key`, or `default` if not given.
+  pub fn parse(sort: Option<&RawStr>, default: Sort<K>, expected: &'static str) -> Result<Sort<K>, APIError> {
+    let sort = match sort {
+      Some(s) => s.as_str(),
+      None => return Ok(default)
+    };
+    let (name, desc) = match sort.strip_prefix('-') {
+      Some(name) => (name, true),
+      None => (sort, false)
+    };
+    let key = K::from_name(name).ok_or_else(|| APIError(400, Box::new(BadRequest("invalid_sort", expected))))?;
+    Ok(Sort { key, desc })
+  }
+
+  fn token(&self) -> String {
+    format!("{}{}", if self.desc { "-" } else { "" }, self.key.name())
+  }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Cursor<P> {
+  sort: String,
+  after: P,
+}
+
+pub fn check_limit(limit: Option<i64>) -> Result<i64, APIError> {
+  let limit = limit.unwrap_or(DEFAULT_LIMIT);
+  if limit <= 0 || limit > MAX_LIMIT {
+    return Err(APIError(400, Box::new(BadRequest("invalid_limit", "Expected 0 < limit <= 1000."))));
+  }
+  Ok(limit)
+}
+
+/// The position in a cursor made with the same sort, or `None` for the first page.
+pub fn decode_cursor<K: SortKey, P: DeserializeOwned>(cursor: Option<&RawStr>, sort: &Sort<K>) -> Result<Option<P>, APIError> {
+  let cursor = match cursor {
+    Some(c) => c,
+    None => return Ok(None)
+  };
+  let invalid = || APIError(400, Box::new(BadRequest("invalid_cursor", "Invalid cursor.")));
+  let json = base64::decode_config(cursor.as_str(), base64::URL_SAFE_NO_PAD).map_err(|_| invalid())?;
+  let cursor: Cursor<P> = serde_json::from_slice(&json).map_err(|_| invalid())?;
+  if cursor.sort != sort.token() {
+    return Err(APIError(400, Box::new(BadRequest("invalid_cursor", "The cursor is for another sort order."))));
+  }
+  Ok(Some(cursor.after))
+}
+
+fn encode_cursor<K: SortKey, P: Serialize>(sort: &Sort<K>, after: P) -> String {
+  let json = serde_json::to_vec(&Cursor { sort: sort.token(), after }).unwrap();
+  base64::encode_config(&json, base64::URL_SAFE_NO_PAD)
+}
+
+/// The request, with `cursor` replaced.
+fn next_link(origin: &Origin, cursor: &str) -> String {
+  let mut query = origin.query().unwrap_or("").split('&')
+      .filter(|p| !p.is_empty() && !p.starts_with("cursor="))
+      .map(|p| p.to_owned())
+      .collect::<Vec<_>>();
+  query.push(format!("cursor={}", cursor));
+  format!("{}?{}", origin.path(), query.join("&"))
+}
+
+/// `items` should hold up to `limit + 1` items, the last of which only tells that there is a next
+/// page. `position` gives what the cursor to that page holds.
+pub fn page<T, K: SortKey, P: Serialize>(mut items: Vec<T>, limit: i64, sort: &Sort<K>, origin: &Origin, position: impl FnOnce(&T) -> P) -> Page<T> {
+  if items.len() as i64 <= limit {
+    return Page { items, cursor: None, next: None };
+  }
+  items.truncate(limit as usize);
+  let cursor = encode_cursor(sort, position(items.last().unwrap()));
+  Page { next: Some(next_link(origin, &cursor)), cursor: Some(cursor), items }
+}
+
+/// For endpoints that sort in memory: the items after `after`, up to `limit + 1` of them.
+/// `items` must already be sorted by `sort`, and `position` gives what cursors hold.
+pub fn after_position<T, K: SortKey, P: Ord>(items: Vec<T>, limit: i64, sort: &Sort<K>, after: Option<P>, position: impl Fn(&T) -> P) -> Vec<T> {
+  items.into_iter()
+      .filter(|i| match after {
+        Some(ref after) if sort.desc => position(i) < *after,
+        Some(ref after) => position(i) > *after,
+        None => true
+      })
+      .take(limit as usize + 1)
+      .collect()
+}
+
+#[cfg(test)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TestKey {
+  Name,
+}
+
+#[cfg(test)]
+impl SortKey for TestKey {
+  fn from_name(name: &str) -> Option<Self> {
+    match name {
+      "name" => Some(TestKey::Name),
+      _ => None
+    }
+  }
+
+  fn name(self) -> &'static str {
+    "name"
+  }
+}
+
+#[test]
+fn test_cursors() {
+  let sort = Sort::parse(Some(RawStr::from_str("-name")), Sort { key: TestKey::Name, desc: false }, "").unwrap();
+  assert!(sort.desc);
+  let origin = Origin::parse("/things?limit=2&cursor=old&x=1").unwrap();
+  let p = page(vec!["c", "b", "a"], 2, &sort, &origin, |s| s.to_string());
+  assert_eq!(p.items, vec!["c", "b"]);
+  let cursor = p.cursor.unwrap();
+  assert_eq!(p.next.unwrap(), format!("/things?limit=2&x=1&cursor={}", cursor));
+  let after: Option<String> = decode_cursor(Some(RawStr::from_str(&cursor)), &sort).unwrap();
+  assert_eq!(after.as_deref(), Some("b"));
+  let rest = after_position(vec!["c", "b", "a"], 2, &sort, after.as_deref(), |s| *s);
+  assert_eq!(rest, vec!["a"]);
+  let asc = Sort { key: TestKey::Name, desc: false };
+  assert!(decode_cursor::<_, String>(Some(RawStr::from_str(&cursor)), &asc).is_err());
+}
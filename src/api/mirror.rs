@@ -0,0 +1,123 @@
+//! A read-only RFC 6962 API for each log, served under `/mirror/<log id>/`, built only from data
+//! we have stored and verified.
+
+use std::borrow::Cow;
+use std::error::Error;
+
+use rocket::State;
+use rocket::http::RawStr;
+use rocket_contrib::json::Json;
+use schemars::JsonSchema;
+use serde::Serialize;
+
+use crate::core::context::CtCrabContext;
+use crate::core::db::DBConn;
+use crate::core::merkle::HashBytes;
+use crate::core::mirror::{self, MirrorError};
+use crate::core::raw_entries::GetEntriesJson;
+use crate::models::{Hash, HashFromStrError, Sth};
+
+use super::{APIError, ErrorCode};
+use super::auth::{Authorized, Read};
+use super::certs::BadRequest;
+use super::entries::{InvalidRange, load_entries};
+
+#[derive(Debug, Error)]
+#[error("Log not mirrored, or the mirror has not caught up with it.")]
+struct NotMirrored;
+
+impl ErrorCode for NotMirrored {
+  fn code(&self) -> Cow<'static, str> {
+    "log_not_mirrored".into()
+  }
+}
+
+impl From<MirrorError> for APIError {
+  fn from(e: MirrorError) -> Self {
+    APIError::from(Box::new(e) as Box<dyn Error>)
+  }
+}
+
+fn served_sth(db: &DBConn, log_id: &Hash) -> Result<Sth, APIError> {
+  mirror::mirrored_sth(db, log_id)?.ok_or_else(|| APIError(404, Box::new(NotMirrored)))
+}
+
+fn b64(hashes: Vec<HashBytes>) -> Vec<String> {
+  hashes.iter().map(base64::encode).collect()
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct GetSthJson {
+  tree_size: u64,
+  timestamp: u64,
+  sha256_root_hash: String,
+  tree_head_signature: String,
+}
+
+#[get("/mirror/<log_id>/ct/v1/get-sth")]
+pub fn get_sth(log_id: Result<Hash, HashFromStrError>, ctx: State<CtCrabContext>, _auth: Authorized<Read>) -> Result<Json<GetSthJson>, APIError> {
+  let log_id = log_id?;
+  let s = served_sth(&ctx.db()?, &log_id)?;
+  Ok(Json(GetSthJson {
+    tree_size: s.tree_size as u64,
+    timestamp: s.sth_timestamp as u64,
+    sha256_root_hash: base64::encode(&s.tree_hash.0),
+    tree_head_signature: base64::encode(&s.signature.0),
+  }))
+}
+
+#[get("/mirror/<log_id>/ct/v1/get-entries?<start>&<end>")]
+pub fn get_entries(log_id: Result<Hash, HashFromStrError>, start: i64, end: i64, ctx: State<CtCrabContext>, _auth: Authorized<Read>) -> Result<Json<GetEntriesJson>, APIError> {
+  let log_id = log_id?;
+  let db = ctx.db()?;
+  let s = served_sth(&db, &log_id)?;
+  if start < 0 || end < start || start >= s.tree_size {
+    return Err(APIError(400, Box::new(InvalidRange)));
+  }
+  let end = std::cmp::min(end, s.tree_size - 1);
+  Ok(Json(GetEntriesJson { entries: load_entries(&db, &log_id, start, end)? }))
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct GetSthConsistencyJson {
+  consistency: Vec<String>,
+}
+
+#[get("/mirror/<log_id>/ct/v1/get-sth-consistency?<first>&<second>")]
+pub fn get_sth_consistency(log_id: Result<Hash, HashFromStrError>, first: u64, second: u64, ctx: State<CtCrabContext>, _auth: Authorized<Read>) -> Result<Json<GetSthConsistencyJson>, APIError> {
+  let log_id = log_id?;
+  let db = ctx.db()?;
+  let s = served_sth(&db, &log_id)?;
+  if first > second || second > s.tree_size as u64 {
+    return Err(APIError(400, Box::new(BadRequest("invalid_range", "Expected first <= second <= current tree size."))));
+  }
+  Ok(Json(GetSthConsistencyJson {
+    consistency: b64(mirror::consistency_proof(&db, &log_id, first, second)?)
+  }))
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct GetProofByHashJson {
+  leaf_index: u64,
+  audit_path: Vec<String>,
+}
+
+#[get("/mirror/<log_id>/ct/v1/get-proof-by-hash?<hash>&<tree_size>")]
+pub fn get_proof_by_hash(log_id: Result<Hash, HashFromStrError>, hash: &RawStr, tree_size: u64, ctx: State<CtCrabContext>, _auth: Authorized<Read>) -> Result<Json<GetProofByHashJson>, APIError> {
+  let log_id = log_id?;
+  let db = ctx.db()?;
+  let s = served_sth(&db, &log_id)?;
+  if tree_size == 0 || tree_size > s.tree_size as u64 {
+    return Err(APIError(400, Box::new(BadRequest("invalid_tree_size", "Expected 0 < tree_size <= current tree size."))));
+  }
+  // Unescaped '+' in the base64 hash may arrive as a space.
+  let hash = hash.url_decode().map_err(|_| APIError(400, Box::new(BadRequest("invalid_hash", "Invalid hash."))))?.replace(' ', "+");
+  let hash = base64::decode(&hash).map_err(|_| APIError(400, Box::new(BadRequest("invalid_hash", "Invalid hash."))))?;
+  match mirror::proof_by_hash(&db, &log_id, &hash, tree_size)? {
+    Some((leaf_index, audit_path)) => Ok(Json(GetProofByHashJson {
+      leaf_index,
+      audit_path: b64(audit_path)
+    })),
+    None => Err(APIError(404, Box::new(super::NotFound("leaf"))))
+  }
+}
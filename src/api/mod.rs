@@ -1,3 +1,5 @@
+use std::borrow::Cow;
+use std::collections::BTreeSet;
 use std::convert::TryInto;
 use std::error::Error;
 
@@ -5,16 +7,41 @@ use chrono::{DateTime, Utc};
 use diesel::expression::count::count_star;
 use diesel::prelude::*;
 use rocket::{Request, Response, State};
-use rocket::http::Status;
+use rocket::http::{RawStr, Status};
+use rocket::http::uri::Origin;
 use rocket::response::Responder;
 use rocket_contrib::json::Json;
+use schemars::JsonSchema;
+use schemars::gen::SchemaGenerator;
+use schemars::schema::{InstanceType, Schema, SchemaObject};
 use serde::{Serialize, Serializer};
+use serde_json::json;
 
 use crate::core::context::CtCrabContext;
 use crate::core::db::DBPooledConn;
-use crate::models::Hash;
-use crate::schema::ctlogs::columns::monitoring;
+use crate::models::{Hash, HashFromStrError};
 
+pub use self::auth::{Access, auth_catchers, stream_authorizer};
+use self::auth::{Authorized, Public};
+use self::caching::{Cached, ETag, IfNoneMatch, Lifetime, short_lifetime};
+use self::certs::BadRequest;
+use self::list::{Page, Sort, SortKey};
+
+mod auth;
+mod caching;
+mod certs;
+mod changes;
+mod entries;
+mod export;
+mod feeds;
+mod issuers;
+mod list;
+mod mirror;
+mod names;
+mod openapi;
+mod search;
+
+#[derive(Debug, Clone, Copy)]
 pub struct TimestampMs(DateTime<Utc>);
 impl Serialize for TimestampMs {
   fn serialize<S>(&self, serializer: S) -> Result<<S as Serializer>::Ok, <S as Serializer>::Error> where
@@ -22,12 +49,50 @@ impl Serialize for TimestampMs {
     serializer.serialize_i64(self.0.timestamp_millis())
   }
 }
+impl JsonSchema for TimestampMs {
+  fn is_referenceable() -> bool {
+    false
+  }
+  fn schema_name() -> String {
+    "TimestampMs".to_owned()
+  }
+  fn json_schema(_: &mut SchemaGenerator) -> Schema {
+    SchemaObject {
+      instance_type: Some(InstanceType::Integer.into()),
+      format: Some("int64".to_owned()),
+      ..Default::default()
+    }.into()
+  }
+}
+
+/// What an error tells clients besides its message.
+pub trait ErrorCode: Error {
+  /// Stable, for clients to tell errors apart, such as `log_not_found`.
+  fn code(&self) -> Cow<'static, str>;
+  /// Anything else a client could act on.
+  fn details(&self) -> Option<serde_json::Value> {
+    None
+  }
+}
+
+/// The body of every error response.
+#[derive(Serialize, JsonSchema)]
+pub struct ErrorJson {
+  /// Stable, such as `log_not_found`.
+  code: String,
+  /// For people, may change.
+  message: String,
+  /// Depends on the code, such as the scope an API key is missing.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  details: Option<serde_json::Value>,
+}
 
 #[derive(Debug)]
-pub struct APIError(pub u16, pub Box<dyn Error>);
+pub struct APIError(pub u16, pub Box<dyn ErrorCode>);
 impl<'r> Responder<'r> for APIError {
   fn respond_to(self, request: &Request) -> rocket::response::Result<'static> {
-    let res = Response::build_from(rocket::response::content::Plain(format!("{}", &self.1)).respond_to(request)?)
+    let body = ErrorJson { code: self.1.code().into_owned(), message: self.1.to_string(), details: self.1.details() };
+    let res = Response::build_from(Json(body).respond_to(request)?)
         .status(Status::from_code(self.0).unwrap()).finalize();
     Ok(res)
   }
@@ -37,9 +102,31 @@ impl From<Box<dyn Error>> for APIError {
     #[derive(Debug, Error)]
     #[error("Whoops! Looks like we messed up. (Unknown internal error)")]
     struct Wrapper(#[source] Box<dyn Error>);
+    impl ErrorCode for Wrapper {
+      fn code(&self) -> Cow<'static, str> {
+        "internal_error".into()
+      }
+    }
     APIError(500, Box::new(Wrapper(e)) as Box<_>)
   }
 }
+impl From<HashFromStrError> for APIError {
+  fn from(e: HashFromStrError) -> Self {
+    APIError(400, Box::new(e))
+  }
+}
+
+impl ErrorCode for HashFromStrError {
+  fn code(&self) -> Cow<'static, str> {
+    "invalid_hash".into()
+  }
+  fn details(&self) -> Option<serde_json::Value> {
+    Some(match *self {
+      HashFromStrError::InvalidLength(length) => json!({ "expected_length": 64, "length": length }),
+      HashFromStrError::UnexpectedByte(byte) => json!({ "unexpected_byte": byte }),
+    })
+  }
+}
 
 #[derive(Debug, Error)]
 #[error("Expected to find exactly one {0}.")]
@@ -50,18 +137,23 @@ struct SignedDurationIsNegative(#[source] Option<Box<dyn Error>>);
 #[derive(Debug, Error)]
 #[error("{0} not found.")]
 struct NotFound(&'static str);
+impl ErrorCode for NotFound {
+  fn code(&self) -> Cow<'static, str> {
+    format!("{}_not_found", self.0).into()
+  }
+}
 
-pub type CtLogs = Vec<BasicCtLogInfo>;
-#[derive(Serialize)]
+#[derive(Serialize, JsonSchema)]
 pub struct BasicCtLogInfo {
   log_id: Hash,
   name: String,
   monitoring: bool,
   endpoint_url: String,
   latest_sth: Option<BasicSthInfo>,
-  last_sth_error: Option<String>
+  last_sth_error: Option<String>,
+  operator: Option<String>,
 }
-#[derive(Serialize)]
+#[derive(Serialize, JsonSchema)]
 pub struct BasicSthInfo {
   id: i64,
   tree_size: u64,
@@ -93,77 +185,178 @@ fn get_basic_sth_info(sth_id: Option<i64>, db: &DBPooledConn) -> Result<Option<B
   }
 }
 
-#[get("/ctlogs?<include_retired>")]
-pub fn ctlogs(ctx: State<CtCrabContext>, include_retired: bool) -> Result<Json<CtLogs>, APIError> {
-  let db = ctx.db()?;
-  use crate::schema::ctlogs::dsl::*;
-  let logs: Vec<(Hash, String, String, Option<i64>, Option<String>, bool)> = {
-    let mut query = ctlogs
-        .select((log_id, name, endpoint_url, latest_sth, last_sth_error, monitoring))
-        .order_by((monitoring.desc(), name.asc()));
-    if !include_retired {
-      query.filter(monitoring.eq(true)).load(&db)
-    } else {
-      query.load(&db)
+#[derive(Debug, Clone, Copy)]
+pub enum CtLogSort {
+  /// Monitored logs first, then by name.
+  Status,
+  Name,
+  LogId,
+}
+
+impl SortKey for CtLogSort {
+  fn from_name(name: &str) -> Option<Self> {
+    match name {
+      "status" => Some(CtLogSort::Status),
+      "name" => Some(CtLogSort::Name),
+      "log_id" => Some(CtLogSort::LogId),
+      _ => None
+    }
+  }
+
+  fn name(self) -> &'static str {
+    match self {
+      CtLogSort::Status => "status",
+      CtLogSort::Name => "name",
+      CtLogSort::LogId => "log_id",
     }
-  }.map_err(|e| Box::new(e) as Box<dyn Error>)?;
-  Ok(Json(logs.into_iter().map(|log| -> Result<BasicCtLogInfo, Box<dyn Error>> {
-    let lsth = get_basic_sth_info(log.3, &db)?;
+  }
+}
+
+/// Ids of logs with a consistency check or certificate fetch error.
+fn logs_with_errors(db: &DBPooledConn) -> Result<BTreeSet<Hash>, Box<dyn Error>> {
+  use crate::schema::consistency_check_errors::dsl as cce;
+  use crate::schema::cert_fetch_errors::dsl as cfe;
+  let mut ids: BTreeSet<Hash> = cce::consistency_check_errors.select(cce::log_id).distinct().load::<Hash>(db)?.into_iter().collect();
+  ids.extend(cfe::cert_fetch_errors.select(cfe::log_id).distinct().load::<Hash>(db)?);
+  Ok(ids)
+}
+
+/// Logs, a page at a time (see `list`). There are few enough of them to filter and sort in memory.
+///
+/// * `monitoring`: only monitored (`true`) or retired (`false`) logs. Without it, retired logs are
+///   only included with `include_retired=true`.
+/// * `operator`: exactly this operator, as named in the log list.
+/// * `has_errors`: whether the log has an sth error, or a consistency check or certificate fetch
+///   error.
+/// * `sort`: `status` (the default), `name` or `log_id`.
+#[get("/ctlogs?<include_retired>&<monitoring>&<operator>&<has_errors>&<sort>&<cursor>&<limit>")]
+pub fn ctlogs(ctx: State<CtCrabContext>, origin: &Origin, include_retired: Option<bool>, monitoring: Option<bool>, operator: Option<&RawStr>,
+              has_errors: Option<bool>, sort: Option<&RawStr>, cursor: Option<&RawStr>, limit: Option<i64>, if_none_match: IfNoneMatch,
+              _auth: Authorized<Public>) -> Result<Cached<Option<Json<Page<BasicCtLogInfo>>>>, APIError> {
+  let db = ctx.db()?;
+  let limit = list::check_limit(limit)?;
+  let sort = Sort::parse(sort, Sort { key: CtLogSort::Status, desc: false }, "Expected sort status, name or log_id.")?;
+  let after = list::decode_cursor(cursor, &sort)?;
+  let monitoring = match (monitoring, include_retired) {
+    (Some(m), _) => Some(m),
+    (None, Some(true)) => None,
+    (None, _) => Some(true)
+  };
+  let operator = match operator {
+    Some(o) => Some(o.url_decode().map_err(|_| APIError(400, Box::new(BadRequest("invalid_operator", "Invalid operator."))))?),
+    None => None
+  };
+  use crate::schema::ctlogs::dsl as l;
+  let mut query = l::ctlogs
+      .select((l::log_id, l::name, l::endpoint_url, l::latest_sth, l::last_sth_error, l::monitoring, l::operator))
+      .into_boxed();
+  if let Some(m) = monitoring {
+    query = query.filter(l::monitoring.eq(m));
+  }
+  if let Some(o) = operator {
+    query = query.filter(l::operator.eq(o));
+  }
+  let mut logs: Vec<(Hash, String, String, Option<i64>, Option<String>, bool, Option<String>)> = query
+      .load(&db).map_err(|e| Box::new(e) as Box<dyn Error>)?;
+  if let Some(has_errors) = has_errors {
+    let with_errors = logs_with_errors(&db)?;
+    logs.retain(|log| (log.4.is_some() || with_errors.contains(&log.0)) == has_errors);
+  }
+  // Stored sths do not change, so the rows, with their latest_sth ids, make the response.
+  let etag = ETag::of_json(&logs);
+  if if_none_match.matches(&etag) {
+    return Ok(Cached::not_modified(etag, short_lifetime(&ctx)));
+  }
+  let position = |log: &(Hash, String, String, Option<i64>, Option<String>, bool, Option<String>)| match sort.key {
+    CtLogSort::Status => (!log.5, log.1.clone(), log.0),
+    CtLogSort::Name => (false, log.1.clone(), log.0),
+    CtLogSort::LogId => (false, String::new(), log.0),
+  };
+  logs.sort_by_cached_key(position);
+  if sort.desc {
+    logs.reverse();
+  }
+  let logs = list::after_position(logs, limit, &sort, after, position);
+  let page = list::page(logs, limit, &sort, origin, position).try_map(|log| -> Result<BasicCtLogInfo, Box<dyn Error>> {
     Ok(BasicCtLogInfo {
+      latest_sth: get_basic_sth_info(log.3, &db)?,
       log_id: log.0,
       name: log.1,
       endpoint_url: log.2,
-      latest_sth: lsth,
       last_sth_error: log.4,
-      monitoring: log.5
+      monitoring: log.5,
+      operator: log.6,
     })
-  }).collect::<Result<Vec<BasicCtLogInfo>, Box<dyn Error>>>()?))
+  })?;
+  Ok(Cached { etag, lifetime: short_lifetime(&ctx), inner: Some(Json(page)) })
 }
 
 #[get("/log/<id>")]
-pub fn log(id: Hash, ctx: State<CtCrabContext>) -> Result<Json<crate::models::CtLog>, APIError> {
+pub fn log(id: Result<Hash, HashFromStrError>, ctx: State<CtCrabContext>, if_none_match: IfNoneMatch, _auth: Authorized<Public>) -> Result<Cached<Option<Json<crate::models::CtLog>>>, APIError> {
+  let id = id?;
   use crate::schema::ctlogs::dsl::*;
   let res: Vec<crate::models::CtLog> = ctlogs
       .filter(log_id.eq(id))
       .load(&ctx.db()?).map_err(|e| Box::new(e) as Box<dyn Error>)?;
-  if let Some(res) = res.into_iter().next() {
-    Ok(Json(res))
-  } else {
-    Err(APIError(404, Box::new(NotFound("log"))))
+  let res = res.into_iter().next().ok_or_else(|| APIError(404, Box::new(NotFound("log"))))?;
+  // Only the latest sth, the sth error and the log list fields change; not the key.
+  let etag = ETag::of_json(&(&res.log_id, &res.endpoint_url, &res.name, res.monitoring, res.latest_sth, &res.last_sth_error, &res.operator));
+  if if_none_match.matches(&etag) {
+    return Ok(Cached::not_modified(etag, short_lifetime(&ctx)));
   }
+  Ok(Cached { etag, lifetime: short_lifetime(&ctx), inner: Some(Json(res)) })
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, JsonSchema)]
 pub struct Stats {
   nb_logs_active: usize,
   nb_logs_total: usize
 }
 
 #[get("/stats")]
-pub fn stats(ctx: State<CtCrabContext>) -> Result<Json<Stats>, APIError> {
+pub fn stats(ctx: State<CtCrabContext>, _auth: Authorized<Public>) -> Result<Cached<Json<Stats>>, APIError> {
   use crate::schema::ctlogs::dsl::*;
   let db = ctx.db()?;
   let nb_logs_active: i64 = ctlogs.select(count_star()).filter(monitoring.eq(true)).first(&db)
       .map_err(|e| Box::new(e) as Box<dyn Error>)?;
   let nb_logs_total: i64 = ctlogs.select(count_star()).first(&db)
       .map_err(|e| Box::new(e) as Box<dyn Error>)?;
-  Ok(Json(Stats {
-    nb_logs_active: nb_logs_active.try_into().unwrap(), nb_logs_total: nb_logs_total.try_into().unwrap()
-  }))
+  Ok(Cached {
+    etag: ETag::of(vec![&nb_logs_active.to_be_bytes()[..], &nb_logs_total.to_be_bytes()[..]]),
+    lifetime: short_lifetime(&ctx),
+    inner: Json(Stats { nb_logs_active: nb_logs_active.try_into().unwrap(), nb_logs_total: nb_logs_total.try_into().unwrap() }),
+  })
 }
 
 #[get("/log/<log_id>/sth/<sth_id>")]
-pub fn get_sth(log_id: Hash, sth_id: i64, ctx: State<CtCrabContext>) -> Result<Json<crate::models::Sth>, APIError> {
+pub fn get_sth(log_id: Result<Hash, HashFromStrError>, sth_id: i64, ctx: State<CtCrabContext>, _auth: Authorized<Public>) -> Result<Cached<Json<crate::models::Sth>>, APIError> {
+  let log_id = log_id?;
   use crate::schema::sth::dsl::*;
   let db = ctx.db()?;
   let res: Vec<crate::models::Sth> = sth.filter(id.eq(sth_id).and(log_id.eq(log_id)))
       .load(&db).map_err(|e| Box::new(e) as Box<dyn Error>)?;
-  if res.is_empty() {
-    return Err(APIError(404, Box::new(NotFound("sth"))));
-  }
-  Ok(Json(res.into_iter().next().unwrap()))
+  let s = match res.into_iter().next() {
+    Some(s) => s,
+    None => return Err(APIError(404, Box::new(NotFound("sth"))))
+  };
+  // Once checked against the latest sth, nothing about a stored sth changes. Only sths that were
+  // latest are never pruned, so others may still turn into a 404.
+  let lifetime = if s.checked_consistent_with_latest && s.was_latest { Lifetime::Immutable } else { short_lifetime(&ctx) };
+  Ok(Cached::json(s, lifetime))
 }
 
 pub fn api_routes() -> Vec<rocket::Route> {
-  routes![ctlogs, log, stats, get_sth]
+  routes![
+    ctlogs, log, stats, get_sth, entries::raw_entries, certs::certs, certs::cert, certs::cert_logs, changes::changes, feeds::dns_feed,
+    issuers::issuers, issuers::issuer_certs, names::search, search::search, export::export,
+    mirror::get_sth, mirror::get_entries, mirror::get_sth_consistency, mirror::get_proof_by_hash, openapi::openapi_json
+  ]
+}
+
+#[test]
+fn test_hash_error_code() {
+  use std::convert::TryFrom;
+  let e = Hash::try_from(&b"abc"[..]).unwrap_err();
+  assert_eq!(e.code(), "invalid_hash");
+  assert_eq!(e.details(), Some(json!({ "expected_length": 64, "length": 3 })));
 }
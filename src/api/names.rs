@@ -0,0 +1,91 @@
+use std::error::Error;
+
+use chrono::{DateTime, Utc};
+use diesel::expression::BoxableExpression;
+use diesel::pg::Pg;
+use diesel::prelude::*;
+use diesel::sql_types::{Binary, Bool, Integer, Nullable, Text};
+use rocket::State;
+use rocket::http::RawStr;
+use rocket::http::uri::Origin;
+use rocket_contrib::json::Json;
+
+use crate::core::certinfo::{ip_range, NameType};
+use crate::core::context::CtCrabContext;
+use crate::models::Hash;
+use crate::schema::certificate_names;
+
+use super::APIError;
+use super::auth::{Authorized, Read};
+use super::certs::{BadRequest, CertInfo, FingerprintSort, load_cert_infos};
+use super::list::{self, Page, Sort};
+
+sql_function!(fn reverse(x: Text) -> Text);
+sql_function!(fn octet_length(x: Nullable<Binary>) -> Nullable<Integer>);
+
+type NameFilter = Box<dyn BoxableExpression<certificate_names::table, Pg, SqlType = Bool>>;
+
+fn escape_like(s: &str) -> String {
+  s.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_")
+}
+
+/// Names ending with `suffix`, using the index on the reversed name.
+fn suffix_pattern(suffix: &str) -> String {
+  format!("{}%", escape_like(&suffix.chars().rev().collect::<String>()))
+}
+
+/// Which names of this type match `q`:
+///
+/// * dns: `example.com` exactly, or `*.example.com` for any name under `example.com` (including
+///   wildcard names).
+/// * ip: an address, or any address within a CIDR block such as `10.0.0.0/8` or `2001:db8::/32`.
+/// * email: `user@example.com` exactly, or `@example.com` for any address at that domain.
+/// * uri: exactly, or `https://example.com/*` for any URI with that prefix.
+pub(super) fn name_filter(t: NameType, q: &str) -> Result<NameFilter, APIError> {
+  use crate::schema::certificate_names::dsl::*;
+  let of_type = name_type.eq(t.as_str());
+  Ok(match t {
+    NameType::Dns if q.starts_with("*.") => Box::new(of_type.and(reverse(name).like(suffix_pattern(&q[1..].to_lowercase())))),
+    NameType::Dns => Box::new(of_type.and(name.eq(q.to_lowercase()))),
+    NameType::Ip => {
+      let (lo, hi) = ip_range(q).ok_or_else(|| APIError(400, Box::new(BadRequest("invalid_ip", "Expected an ip address or CIDR block."))))?;
+      Box::new(of_type.and(octet_length(ip).eq(lo.len() as i32)).and(ip.between(lo, hi)))
+    },
+    NameType::Email if q.starts_with('@') => Box::new(of_type.and(reverse(name).like(suffix_pattern(q)))),
+    NameType::Email => Box::new(of_type.and(name.eq(q.to_owned()))),
+    NameType::Uri if q.ends_with('*') => Box::new(of_type.and(name.like(format!("{}%", escape_like(&q[..q.len() - 1]))))),
+    NameType::Uri => Box::new(of_type.and(name.eq(q.to_owned()))),
+  })
+}
+
+/// Certificates with a name of this type (`dns`, `ip`, `email` or `uri`) matching `q`, by
+/// fingerprint (`sort=fingerprint`, the default, or `-fingerprint`). See `name_filter` for the
+/// syntax of `q`.
+#[get("/names/<name_type>?<q>&<sort>&<cursor>&<limit>")]
+pub fn search(name_type: &RawStr, q: &RawStr, sort: Option<&RawStr>, cursor: Option<&RawStr>, limit: Option<i64>, origin: &Origin, ctx: State<CtCrabContext>, _auth: Authorized<Read>) -> Result<Json<Page<CertInfo>>, APIError> {
+  let db = ctx.db()?;
+  let name_type: NameType = name_type.parse().map_err(|_| APIError(400, Box::new(BadRequest("unknown_name_type", "Expected name type dns, ip, email or uri."))))?;
+  let q = q.url_decode().map_err(|_| APIError(400, Box::new(BadRequest("invalid_query", "Invalid q."))))?;
+  let limit = list::check_limit(limit)?;
+  let sort = Sort::parse(sort, Sort { key: FingerprintSort, desc: false }, "Expected sort fingerprint.")?;
+  let after: Option<Hash> = list::decode_cursor(cursor, &sort)?;
+  use crate::schema::certificates::dsl as c;
+  use crate::schema::certificate_names::dsl as n;
+  let mut query = c::certificates
+      .select((c::fingerprint, c::not_before, c::not_after))
+      .filter(c::fingerprint.eq_any(
+        n::certificate_names.select(n::cert_fp).filter(name_filter(name_type, &q)?)
+      ))
+      .into_boxed();
+  query = match (after, sort.desc) {
+    (Some(after), false) => query.filter(c::fingerprint.gt(after)).order_by(c::fingerprint.asc()),
+    (Some(after), true) => query.filter(c::fingerprint.lt(after)).order_by(c::fingerprint.desc()),
+    (None, false) => query.order_by(c::fingerprint.asc()),
+    (None, true) => query.order_by(c::fingerprint.desc()),
+  };
+  let rows: Vec<(Hash, Option<DateTime<Utc>>, Option<DateTime<Utc>>)> = query
+      .limit(limit + 1)
+      .load(&db).map_err(|e| Box::new(e) as Box<dyn Error>)?;
+  let page = list::page(rows, limit, &sort, origin, |r| r.0);
+  Ok(Json(Page { items: load_cert_infos(&db, page.items)?, cursor: page.cursor, next: page.next }))
+}
@@ -0,0 +1,318 @@
+//! An OpenAPI 3 description of `api_routes`, served at `/openapi.json`. Schemas are generated from
+//! the response types; routes and their parameters are listed in `operations`.
+//!
+//! doc/openapi.json holds a copy, so that changes to the API show up in review.
+//! `test_openapi_up_to_date` fails when it is stale (run it with `CTCRAB_UPDATE_OPENAPI=1` to
+//! update it), and `test_routes_described` when `operations` does not match the routes.
+
+use rocket_contrib::json::Json;
+use schemars::JsonSchema;
+use schemars::gen::{SchemaGenerator, SchemaSettings};
+use schemars::schema::Schema;
+use schemars::visit::Visitor;
+use serde_json::{json, Map, Value};
+
+use crate::core::api_keys::Scope;
+use crate::core::raw_entries::GetEntriesJson;
+use crate::core::search::SearchHit;
+use crate::models::{CtLog, Hash, Sth};
+
+use super::{BasicCtLogInfo, ErrorJson, Stats};
+use super::auth::{Authorized, Bulk, Public, Read, Requirement, Search};
+use super::certs::{CertDetail, CertInfo, CertLogs};
+use super::changes::Changes;
+use super::issuers::IssuerInfo;
+use super::list::Page;
+use super::mirror::{GetProofByHashJson, GetSthConsistencyJson, GetSthJson};
+
+type SchemaFn = fn(&mut SchemaGenerator) -> Schema;
+
+struct Param {
+  name: &'static str,
+  /// `path` or `query`.
+  location: &'static str,
+  required: bool,
+  schema: SchemaFn,
+}
+
+fn path<T: JsonSchema>(name: &'static str) -> Param {
+  Param { name, location: "path", required: true, schema: SchemaGenerator::subschema_for::<T> }
+}
+
+fn query<T: JsonSchema>(name: &'static str) -> Param {
+  Param { name, location: "query", required: true, schema: SchemaGenerator::subschema_for::<T> }
+}
+
+fn optional<T: JsonSchema>(name: &'static str) -> Param {
+  Param { required: false, ..query::<T>(name) }
+}
+
+/// `sort`, `cursor` and `limit`, see `list`.
+fn list_params() -> Vec<Param> {
+  vec![optional::<String>("sort"), optional::<String>("cursor"), optional::<i64>("limit")]
+}
+
+enum Body {
+  Json(SchemaFn),
+  /// Media types of a body that is not described further.
+  Other(&'static [&'static str]),
+}
+
+fn json<T: JsonSchema>() -> Body {
+  Body::Json(SchemaGenerator::subschema_for::<T>)
+}
+
+/// A `GET` route.
+struct Operation {
+  path: &'static str,
+  summary: &'static str,
+  /// The `SCOPE` of the route's `Authorized` guard.
+  scope: Option<Scope>,
+  params: Vec<Param>,
+  response: Body,
+}
+
+fn operations() -> Vec<Operation> {
+  vec![
+    Operation {
+      path: "/ctlogs",
+      summary: "Logs, a page at a time.",
+      scope: Public::SCOPE,
+      params: vec![optional::<bool>("include_retired"), optional::<bool>("monitoring"), optional::<String>("operator"),
+                   optional::<bool>("has_errors")].into_iter().chain(list_params()).collect(),
+      response: json::<Page<BasicCtLogInfo>>(),
+    },
+    Operation {
+      path: "/log/{id}",
+      summary: "A log.",
+      scope: Public::SCOPE,
+      params: vec![path::<Hash>("id")],
+      response: json::<CtLog>(),
+    },
+    Operation {
+      path: "/stats",
+      summary: "Number of logs.",
+      scope: Public::SCOPE,
+      params: vec![],
+      response: json::<Stats>(),
+    },
+    Operation {
+      path: "/log/{log_id}/sth/{sth_id}",
+      summary: "An sth received from a log.",
+      scope: Public::SCOPE,
+      params: vec![path::<Hash>("log_id"), path::<i64>("sth_id")],
+      response: json::<Sth>(),
+    },
+    Operation {
+      path: "/log/{id}/entries",
+      summary: "Raw entries stored for a log, in get-entries format.",
+      scope: Read::SCOPE,
+      params: vec![path::<Hash>("id"), query::<i64>("start"), query::<i64>("end")],
+      response: json::<GetEntriesJson>(),
+    },
+    Operation {
+      path: "/certs",
+      summary: "Certificates matching all given filters, a page at a time.",
+      scope: Read::SCOPE,
+      params: vec![optional::<String>("dns"), optional::<u32>("expiring_within_days"), optional::<i64>("valid_at")]
+          .into_iter().chain(list_params()).collect(),
+      response: json::<Page<CertInfo>>(),
+    },
+    Operation {
+      path: "/cert/{fp}",
+      summary: "A certificate, with its issuer.",
+      scope: Read::SCOPE,
+      params: vec![path::<Hash>("fp")],
+      response: json::<CertDetail>(),
+    },
+    Operation {
+      path: "/cert/{fp}/logs",
+      summary: "The logs a certificate appears in.",
+      scope: Read::SCOPE,
+      params: vec![path::<Hash>("fp")],
+      response: json::<CertLogs>(),
+    },
+    Operation {
+      path: "/changes",
+      summary: "Appearances of certificates in logs, in the order they were stored.",
+      scope: Read::SCOPE,
+      params: vec![optional::<i64>("since"), optional::<i64>("limit")],
+      response: json::<Changes>(),
+    },
+    Operation {
+      path: "/feeds/dns/{file}",
+      summary: "An Atom feed of the most recently ingested certificates for a domain (`<domain>.atom`).",
+      scope: Read::SCOPE,
+      params: vec![path::<String>("file")],
+      response: Body::Other(&["application/atom+xml"]),
+    },
+    Operation {
+      path: "/issuers",
+      summary: "Issuers, with the number of certificates they issued, a page at a time.",
+      scope: Read::SCOPE,
+      params: list_params(),
+      response: json::<Page<IssuerInfo>>(),
+    },
+    Operation {
+      path: "/issuers/{id}/certs",
+      summary: "Certificates issued by an issuer, a page at a time.",
+      scope: Read::SCOPE,
+      params: vec![path::<i64>("id")].into_iter().chain(list_params()).collect(),
+      response: json::<Page<CertInfo>>(),
+    },
+    Operation {
+      path: "/names/{name_type}",
+      summary: "Certificates with a name of this type (dns, ip, email or uri) matching q, a page at a time.",
+      scope: Read::SCOPE,
+      params: vec![path::<String>("name_type"), query::<String>("q")].into_iter().chain(list_params()).collect(),
+      response: json::<Page<CertInfo>>(),
+    },
+    Operation {
+      path: "/search",
+      summary: "Full-text search over certificate subjects, names and issuers, best match first.",
+      scope: Search::SCOPE,
+      params: vec![query::<String>("q"), optional::<String>("sort"), optional::<String>("cursor"), optional::<usize>("limit")],
+      response: json::<Page<SearchHit>>(),
+    },
+    Operation {
+      path: "/export/{format}",
+      summary: "Every certificate matching a filter, as ndjson, csv or pem.",
+      scope: Bulk::SCOPE,
+      params: vec![path::<String>("format"), optional::<String>("dns"), optional::<i64>("issuer"), optional::<Hash>("after"),
+                   optional::<u64>("limit")],
+      response: Body::Other(&["application/x-ndjson", "text/csv", "application/x-pem-file"]),
+    },
+    Operation {
+      path: "/mirror/{log_id}/ct/v1/get-sth",
+      summary: "RFC 6962 get-sth of a mirrored log.",
+      scope: Read::SCOPE,
+      params: vec![path::<Hash>("log_id")],
+      response: json::<GetSthJson>(),
+    },
+    Operation {
+      path: "/mirror/{log_id}/ct/v1/get-entries",
+      summary: "RFC 6962 get-entries of a mirrored log.",
+      scope: Read::SCOPE,
+      params: vec![path::<Hash>("log_id"), query::<i64>("start"), query::<i64>("end")],
+      response: json::<GetEntriesJson>(),
+    },
+    Operation {
+      path: "/mirror/{log_id}/ct/v1/get-sth-consistency",
+      summary: "RFC 6962 get-sth-consistency of a mirrored log.",
+      scope: Read::SCOPE,
+      params: vec![path::<Hash>("log_id"), query::<u64>("first"), query::<u64>("second")],
+      response: json::<GetSthConsistencyJson>(),
+    },
+    Operation {
+      path: "/mirror/{log_id}/ct/v1/get-proof-by-hash",
+      summary: "RFC 6962 get-proof-by-hash of a mirrored log.",
+      scope: Read::SCOPE,
+      params: vec![path::<Hash>("log_id"), query::<String>("hash"), query::<u64>("tree_size")],
+      response: json::<GetProofByHashJson>(),
+    },
+    Operation {
+      path: "/openapi.json",
+      summary: "This document.",
+      scope: Public::SCOPE,
+      params: vec![],
+      response: Body::Other(&["application/json"]),
+    },
+  ]
+}
+
+pub fn spec() -> Value {
+  let mut gen = SchemaSettings::openapi3().into_generator();
+  let error = json!({ "application/json": { "schema": gen.subschema_for::<ErrorJson>() } });
+  let mut paths = Map::new();
+  for op in operations() {
+    let parameters: Vec<Value> = op.params.iter().map(|p| json!({
+      "name": p.name,
+      "in": p.location,
+      "required": p.required,
+      "schema": (p.schema)(&mut gen),
+    })).collect();
+    let content = match op.response {
+      Body::Json(schema) => json!({ "application/json": { "schema": schema(&mut gen) } }),
+      Body::Other(types) => Value::Object(types.iter().map(|t| (t.to_string(), json!({}))).collect()),
+    };
+    let mut get = json!({
+      "summary": op.summary,
+      "parameters": parameters,
+      "responses": {
+        "200": { "description": "OK", "content": content },
+        "429": {
+          "description": "Rate limited.",
+          "headers": { "Retry-After": { "description": "Seconds to wait.", "schema": { "type": "integer" } } },
+          "content": error,
+        },
+        "default": { "description": "An error, with a stable `code`.", "content": error },
+      },
+    });
+    if let Some(scope) = op.scope {
+      get["description"] = json!(format!("Needs an API key with the `{}` scope, unless anonymous requests are given it.", scope));
+      get["security"] = json!([{ "api_key": [] }]);
+    }
+    paths.insert(op.path.to_owned(), json!({ "get": get }));
+  }
+  // What the openapi3 settings fix up, such as siblings of $ref, is only fixed up by the generator
+  // in root schemas.
+  let mut schemas = gen.take_definitions();
+  for visitor in gen.visitors_mut() {
+    for schema in schemas.values_mut() {
+      visitor.visit_schema(schema);
+    }
+  }
+  json!({
+    "openapi": "3.0.3",
+    "info": { "title": "ctcrab", "version": env!("CARGO_PKG_VERSION") },
+    "paths": paths,
+    "components": {
+      "schemas": schemas,
+      "securitySchemes": {
+        "api_key": {
+          "type": "http",
+          "scheme": "bearer",
+          "description": "A key from `ctcrab api-keys create`, which can also be sent as `X-API-Key`.",
+        },
+      },
+    },
+  })
+}
+
+#[get("/openapi.json")]
+pub fn openapi_json(_auth: Authorized<Public>) -> Json<Value> {
+  Json(spec())
+}
+
+#[test]
+fn test_routes_described() {
+  let ops = operations();
+  let routes = super::api_routes();
+  assert_eq!(routes.len(), ops.len());
+  for route in routes {
+    assert_eq!(route.method, rocket::http::Method::Get);
+    let path = route.uri.path().replace('<', "{").replace('>', "}");
+    let op = ops.iter().find(|op| op.path == path).unwrap_or_else(|| panic!("{} is not described.", route.uri));
+    let dynamic = |location: &'static str| move |seg: &str| -> Option<(String, &'static str)> {
+      Some((seg.strip_prefix('<')?.strip_suffix('>')?.to_owned(), location))
+    };
+    let mut params: Vec<(String, &'static str)> = route.uri.path().split('/').filter_map(dynamic("path"))
+        .chain(route.uri.query().unwrap_or("").split('&').filter_map(dynamic("query")))
+        .collect();
+    let mut described: Vec<(String, &'static str)> = op.params.iter().map(|p| (p.name.to_owned(), p.location)).collect();
+    params.sort();
+    described.sort();
+    assert_eq!(params, described, "parameters of {}", route.uri);
+  }
+}
+
+#[test]
+fn test_openapi_up_to_date() {
+  let file = concat!(env!("CARGO_MANIFEST_DIR"), "/doc/openapi.json");
+  let spec = spec();
+  if std::env::var_os("CTCRAB_UPDATE_OPENAPI").is_some() {
+    std::fs::write(file, serde_json::to_string_pretty(&spec).unwrap() + "\n").unwrap();
+  }
+  let saved: Value = serde_json::from_str(&std::fs::read_to_string(file).unwrap()).unwrap();
+  assert!(saved == spec, "doc/openapi.json is out of date. Run this test with CTCRAB_UPDATE_OPENAPI=1 to update it.");
+}
@@ -0,0 +1,78 @@
+use std::borrow::Cow;
+
+use rocket::State;
+use rocket::http::RawStr;
+use rocket::http::uri::Origin;
+use rocket_contrib::json::Json;
+
+use crate::core::context::CtCrabContext;
+use crate::core::search::{SearchError, SearchHit};
+
+use super::{APIError, ErrorCode};
+use super::auth::{Authorized, Search};
+use super::certs::BadRequest;
+use super::list::{self, Page, Sort, SortKey};
+
+const DEFAULT_LIMIT: usize = 20;
+const MAX_LIMIT: usize = 100;
+/// Deeper pages would cost too much, since every hit before them has to be ranked.
+const MAX_OFFSET: usize = 10000;
+
+#[derive(Debug, Error)]
+#[error("Full-text search is not enabled on this server.")]
+struct SearchDisabled;
+
+impl ErrorCode for SearchDisabled {
+  fn code(&self) -> Cow<'static, str> {
+    "search_disabled".into()
+  }
+}
+
+impl ErrorCode for SearchError {
+  fn code(&self) -> Cow<'static, str> {
+    match self {
+      SearchError::Query(_) | SearchError::Invalid(_) => "invalid_query",
+      _ => "internal_error"
+    }.into()
+  }
+}
+
+/// Best match first, the only order.
+#[derive(Debug, Clone, Copy)]
+pub struct SearchSort;
+
+impl SortKey for SearchSort {
+  fn from_name(name: &str) -> Option<Self> {
+    if name == "score" { Some(SearchSort) } else { None }
+  }
+
+  fn name(self) -> &'static str {
+    "score"
+  }
+}
+
+/// Full-text search over certificate subjects, names and issuers, best match first. See
+/// `SearchIndex::search` for the query syntax. Cursors hold the number of hits already returned,
+/// up to 10000.
+#[get("/search?<q>&<sort>&<cursor>&<limit>")]
+pub fn search(q: &RawStr, sort: Option<&RawStr>, cursor: Option<&RawStr>, limit: Option<usize>, origin: &Origin, ctx: State<CtCrabContext>, _auth: Authorized<Search>) -> Result<Json<Page<SearchHit>>, APIError> {
+  let index = ctx.search().ok_or_else(|| APIError(404, Box::new(SearchDisabled)))?;
+  let limit = limit.unwrap_or(DEFAULT_LIMIT);
+  if limit == 0 || limit > MAX_LIMIT {
+    return Err(APIError(400, Box::new(BadRequest("invalid_limit", "Expected 0 < limit <= 100."))));
+  }
+  let sort = Sort::parse(sort, Sort { key: SearchSort, desc: false }, "Expected sort score.")?;
+  if sort.desc {
+    return Err(APIError(400, Box::new(BadRequest("invalid_sort", "Expected sort score."))));
+  }
+  let offset: usize = list::decode_cursor(cursor, &sort)?.unwrap_or(0);
+  if offset > MAX_OFFSET {
+    return Err(APIError(400, Box::new(BadRequest("invalid_cursor", "Invalid cursor."))));
+  }
+  let q = q.url_decode().map_err(|_| APIError(400, Box::new(BadRequest("invalid_query", "Invalid q."))))?;
+  match index.search(&q, limit + 1, offset) {
+    Ok(hits) => Ok(Json(list::page(hits, limit as i64, &sort, origin, |_| offset + limit))),
+    Err(e @ SearchError::Query(_)) | Err(e @ SearchError::Invalid(_)) => Err(APIError(400, Box::new(e))),
+    Err(e) => Err(APIError::from(Box::new(e) as Box<dyn std::error::Error>))
+  }
+}
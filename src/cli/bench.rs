@@ -0,0 +1,137 @@
+//! `ctcrab bench-ingest`: compare storing leaves one at a time with `insert_leaves`, against the
+//! configured database. Everything is done in a transaction that is rolled back at the end.
+
+use std::error::Error;
+use std::time::{Duration, Instant};
+
+use ctclient::internal::re_exports::openssl;
+use diesel::prelude::*;
+use openssl::asn1::Asn1Time;
+use openssl::bn::{BigNum, MsbOption};
+use openssl::ec::{EcGroup, EcKey};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::x509::{X509, X509Name, X509NameRef};
+use openssl::x509::extension::SubjectAlternativeName;
+
+use crate::core::db::DBConn;
+use crate::models::Hash;
+use crate::models::inserts::{insert_leaves, insert_x509_and_chain, reserve_ingest_seqs, CertificateAppearsInLeaf, CtLog, ParsedLeaf};
+
+const BENCH_LOG_ID: Hash = Hash([0xbe; 32]);
+
+fn name(cn: &str) -> Result<X509Name, Box<dyn Error>> {
+  let mut b = X509Name::builder()?;
+  b.append_entry_by_text("O", "ctcrab bench")?;
+  b.append_entry_by_text("CN", cn)?;
+  Ok(b.build())
+}
+
+fn make_cert(subject: &X509NameRef, dns: Option<&str>, key: &PKey<Private>, issuer: &X509NameRef, issuer_key: &PKey<Private>) -> Result<X509, Box<dyn Error>> {
+  let mut b = X509::builder()?;
+  b.set_version(2)?;
+  let mut serial = BigNum::new()?;
+  serial.rand(128, MsbOption::MAYBE_ZERO, false)?;
+  b.set_serial_number(&serial.to_asn1_integer()?)?;
+  b.set_subject_name(subject)?;
+  b.set_issuer_name(issuer)?;
+  b.set_pubkey(key)?;
+  b.set_not_before(&Asn1Time::days_from_now(0)?)?;
+  b.set_not_after(&Asn1Time::days_from_now(90)?)?;
+  if let Some(dns) = dns {
+    let san = SubjectAlternativeName::new().dns(dns).build(&b.x509v3_context(None, None))?;
+    b.append_extension(san)?;
+  }
+  b.sign(issuer_key, MessageDigest::sha256())?;
+  Ok(b.build())
+}
+
+struct Generator {
+  key: PKey<Private>,
+  root: X509,
+  intermediate: X509,
+  next: u64,
+}
+
+impl Generator {
+  fn new() -> Result<Generator, Box<dyn Error>> {
+    let key = PKey::from_ec_key(EcKey::generate(&EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?)?)?;
+    let root_name = name("ctcrab bench root")?;
+    let root = make_cert(&root_name, None, &key, &root_name, &key)?;
+    let intermediate = make_cert(&name("ctcrab bench intermediate")?, None, &key, &root_name, &key)?;
+    Ok(Generator { key, root, intermediate, next: 0 })
+  }
+
+  /// `n` distinct leaves, all issued by the same intermediate.
+  fn leaves(&mut self, n: usize) -> Result<Vec<ParsedLeaf>, Box<dyn Error>> {
+    let mut leaves = Vec::with_capacity(n);
+    for _ in 0..n {
+      let dns = format!("leaf{}.bench.example", self.next);
+      let leaf = make_cert(&name(&dns)?, Some(&dns), &self.key, self.intermediate.subject_name(), &self.key)?;
+      let mut leaf_hash = [0u8; 32];
+      leaf_hash[..8].copy_from_slice(&self.next.to_be_bytes());
+      leaves.push(ParsedLeaf {
+        leaf_index: self.next,
+        leaf_hash: Hash(leaf_hash),
+        precert: false,
+        chain: vec![leaf, self.intermediate.clone(), self.root.clone()],
+      });
+      self.next += 1;
+    }
+    Ok(leaves)
+  }
+}
+
+/// The previous ingestion path: a transaction per leaf, and a few statements per certificate.
+fn insert_one_by_one(db: &DBConn, leaves: &[ParsedLeaf]) -> Result<(), diesel::result::Error> {
+  for leaf in leaves {
+    db.transaction(|| {
+      let (fp, chain_id) = insert_x509_and_chain(db, &leaf.chain)?;
+      let seq = reserve_ingest_seqs(db, 1)?;
+      use crate::schema::certificate_appears_in_leaf::dsl::*;
+      diesel::insert_into(certificate_appears_in_leaf)
+          .values(CertificateAppearsInLeaf { leaf_hash: leaf.leaf_hash, cert_fp: fp, log_id: BENCH_LOG_ID, leaf_index: leaf.leaf_index as i64, chain_id: Some(chain_id), ingest_seq: seq })
+          .on_conflict_do_nothing()
+          .execute(db)
+    })?;
+  }
+  Ok(())
+}
+
+fn report(what: &str, n: usize, time: Duration) {
+  println!("{:<24} {:>8} leaves in {:>8.3}s: {:>10.0} leaves/s", what, n, time.as_secs_f64(), n as f64 / time.as_secs_f64());
+}
+
+pub fn bench_ingest(db: &DBConn, leaves: usize, batch_sizes: &[usize]) -> Result<(), Box<dyn Error>> {
+  let mut gen = Generator::new()?;
+  let mut runs = Vec::with_capacity(batch_sizes.len() + 1);
+  for _ in 0..=batch_sizes.len() {
+    runs.push(gen.leaves(leaves)?);
+  }
+  let mut result: Result<(), Box<dyn Error>> = Ok(());
+  let _ = db.transaction::<(), _, _>(|| {
+    result = (|| -> Result<(), Box<dyn Error>> {
+      diesel::insert_into(crate::schema::ctlogs::table)
+          .values(CtLog { log_id: BENCH_LOG_ID, endpoint_url: "https://bench.invalid/", name: "ctcrab bench", public_key: &[], monitoring: false, operator: None })
+          .execute(db)?;
+      let start = Instant::now();
+      insert_one_by_one(db, &runs[0])?;
+      report("one by one", leaves, start.elapsed());
+      for (batch_size, run) in batch_sizes.iter().zip(&runs[1..]) {
+        let start = Instant::now();
+        for batch in run.chunks(*batch_size) {
+          db.transaction(|| insert_leaves(db, BENCH_LOG_ID, batch))?;
+        }
+        report(&format!("batches of {}", batch_size), leaves, start.elapsed());
+      }
+      // Inserting the same leaves again must not add anything.
+      let start = Instant::now();
+      db.transaction(|| insert_leaves(db, BENCH_LOG_ID, &runs[0]))?;
+      report("already stored", leaves, start.elapsed());
+      Ok(())
+    })();
+    Err(diesel::result::Error::RollbackTransaction)
+  });
+  result
+}
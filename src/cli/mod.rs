@@ -0,0 +1,361 @@
+use std::convert::TryFrom;
+use std::error::Error;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use diesel::expression::count::count_star;
+use diesel::prelude::*;
+use structopt::StructOpt;
+
+use crate::core::api_keys::Scope;
+use crate::core::config::{Config, ConfigError, ValidationErrors};
+use crate::core::context::CtCrabContext;
+use crate::core::db::DBConn;
+use crate::models::Hash;
+
+mod bench;
+mod status;
+
+embed_migrations!("migrations/postgresql");
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "ctcrab", about = "Certificate Transparency log monitor.")]
+pub struct Opt {
+  /// Path to the configuration file. Defaults to $CTCRAB_CONFIG, then ./ctcrab.toml.
+  #[structopt(long, short, parse(from_os_str), global = true)]
+  pub config: Option<PathBuf>,
+  #[structopt(subcommand)]
+  pub cmd: Option<Command>,
+}
+
+#[derive(Debug, StructOpt)]
+pub enum Command {
+  /// Monitor logs and serve the API. This is the default.
+  Serve,
+  /// Monitor logs without serving the API.
+  MonitorOnly,
+  /// Serve the API without monitoring any logs.
+  ApiOnly,
+  /// Run pending database migrations.
+  Migrate,
+  /// Fetch the log list and update the ctlogs table.
+  InitLogs,
+  /// Print the state of each log.
+  Status {
+    /// Also show logs that are not being monitored.
+    #[structopt(long)]
+    all: bool,
+  },
+  /// Export (or bring up to date) the tiles of every mirrored log.
+  ExportTiles {
+    /// Only export the log with this (hex) id.
+    #[structopt(long)]
+    log: Option<Hash>,
+  },
+  /// Extract issuer information (and optionally names) from certificates inserted before it was
+  /// indexed.
+  Reindex {
+    /// Also index the ip, email and URI names of every certificate.
+    #[structopt(long)]
+    names: bool,
+    /// Also add every certificate to the full-text index in search.index_dir. Stop any ingesting
+    /// ctcrab first, which holds the index open for writing.
+    #[structopt(long)]
+    search: bool,
+  },
+  /// Manage the partitions of the certificates, certificate_names and certificate_appears_in_leaf
+  /// tables.
+  Partitions {
+    #[structopt(subcommand)]
+    cmd: PartitionsCommand,
+  },
+  /// Delete old sth and expired certificates according to [retention]. The run is recorded in the
+  /// prune_runs table. Deleted certificates are also removed from the search index, which needs
+  /// any ingesting ctcrab to be stopped first.
+  Prune {
+    /// Only count what would be deleted.
+    #[structopt(long)]
+    dry_run: bool,
+  },
+  /// Measure how fast synthetic leaves are stored, one by one and in batches. Nothing is kept in
+  /// the database.
+  BenchIngest {
+    /// Number of leaves stored by each run.
+    #[structopt(long, default_value = "5000")]
+    leaves: usize,
+    /// Batch sizes to compare with storing leaves one by one.
+    #[structopt(long, default_value = "16,256,2048", use_delimiter = true)]
+    batch_sizes: Vec<usize>,
+  },
+  /// Re-check stored sth signatures and the invariants in doc/update_process.md, and print a JSON
+  /// report of violations. Exits with status 2 if any violation is found.
+  Verify {
+    /// Only check the log with this (hex) id.
+    #[structopt(long)]
+    log: Option<Hash>,
+    /// Fetch consistency proofs from the logs for each pair of consecutive checked sth.
+    #[structopt(long)]
+    fetch_proofs: bool,
+    /// Write the report to this file instead of stdout.
+    #[structopt(long, short, parse(from_os_str))]
+    output: Option<PathBuf>,
+  },
+  /// Backfill the merkle frontier of a log again after its root did not match the log's sth. Takes
+  /// effect at the next round of a running ctcrab.
+  RetryBackfill {
+    /// (hex) id of the log.
+    log: Hash,
+  },
+  /// Manage the API keys of the HTTP API.
+  ApiKeys {
+    #[structopt(subcommand)]
+    cmd: ApiKeysCommand,
+  },
+}
+
+#[derive(Debug, StructOpt)]
+pub enum ApiKeysCommand {
+  /// Create a key and print it. It can not be shown again.
+  Create {
+    /// What the key is for.
+    #[structopt(long)]
+    name: String,
+    /// read, search, webhooks (reserved, no route needs it yet) or admin. Can be repeated.
+    #[structopt(long = "scope", required = true)]
+    scopes: Vec<Scope>,
+    /// Requests per minute, instead of rate_limit.key_per_minute.
+    #[structopt(long)]
+    per_minute: Option<u32>,
+  },
+  /// Print every key, without the key itself.
+  List,
+  /// Stop accepting a key.
+  Revoke {
+    id: i64,
+  },
+}
+
+#[derive(Debug, StructOpt)]
+pub enum PartitionsCommand {
+  /// Print every partition with its estimated number of rows.
+  List,
+  /// Create the monthly partitions up to some months from now. `serve` and `monitor-only` also do
+  /// this every few hours.
+  Create {
+    #[structopt(long, default_value = "3")]
+    months_ahead: i32,
+  },
+  /// Stop monitoring a log and detach the partition holding its appearances, so that they no
+  /// longer show up in queries. Stop any running ctcrab first.
+  RetireLog {
+    /// (hex) id of the log.
+    log: Hash,
+    /// Also drop the detached partition.
+    #[structopt(long)]
+    drop: bool,
+  },
+}
+
+pub fn run(opt: Opt) -> Result<(), Box<dyn Error>> {
+  let config = match Config::load(opt.config.as_deref()) {
+    Ok(c) => c,
+    Err(e) => {
+      eprintln!("{}", e);
+      std::process::exit(1);
+    }
+  };
+  match opt.cmd.unwrap_or(Command::Serve) {
+    Command::Serve => {
+      let rocket_config = crate::rocket_config(&config)?;
+      let access = Arc::new(crate::api::Access::new(&config));
+      let ctx = CtCrabContext::new(config, true)?;
+      start_monitoring(&ctx, &access)?;
+      Err(Box::new(crate::build_rocket(rocket_config, ctx, access).launch()))
+    },
+    Command::MonitorOnly => {
+      if !config.ingestion.enabled {
+        eprintln!("{}", ConfigError::Invalid(ValidationErrors(vec![
+          "ingestion.enabled must be true for monitor-only, which would otherwise do nothing.".to_owned()
+        ])));
+        std::process::exit(1);
+      }
+      let access = Arc::new(crate::api::Access::new(&config));
+      let ctx = CtCrabContext::new(config, true)?;
+      start_monitoring(&ctx, &access)?;
+      loop {
+        std::thread::park();
+      }
+    },
+    Command::ApiOnly => {
+      let rocket_config = crate::rocket_config(&config)?;
+      let access = Arc::new(crate::api::Access::new(&config));
+      let ctx = CtCrabContext::new(config, false)?;
+      Err(Box::new(crate::build_rocket(rocket_config, ctx, access).launch()))
+    },
+    Command::Migrate => {
+      let db = crate::core::db::open_db(&config)?;
+      embedded_migrations::run_with_output(&db, &mut std::io::stdout())?;
+      Ok(())
+    },
+    Command::InitLogs => {
+      let db = crate::core::db::open_db(&config)?;
+      init_logs(&db, &config)
+    },
+    Command::Status { all } => {
+      let db = crate::core::db::open_db(&config)?;
+      status::print_status(&db, all, &mut std::io::stdout())
+    },
+    Command::ExportTiles { log } => {
+      let db = crate::core::db::open_db(&config)?;
+      let logs: Vec<crate::models::CtLog> = {
+        use crate::schema::ctlogs::dsl::*;
+        match log {
+          Some(id) => ctlogs.filter(log_id.eq(id)).load(&db)?,
+          None => ctlogs.load(&db)?
+        }
+      };
+      for l in logs {
+        match crate::core::tiles::export_log(&db, &l, &config.tiles.output_dir)? {
+          Some(size) => println!("{}: exported up to tree size {}", l.name, size),
+          None => println!("{}: skipped, not fully mirrored", l.name)
+        }
+      }
+      Ok(())
+    },
+    Command::Reindex { names, search } => {
+      let db = crate::core::db::open_db(&config)?;
+      let search_index = if search { Some(crate::core::search::SearchIndex::open(&config.search)?) } else { None };
+      let opts = crate::core::reindex::Options { names, search: search_index.as_deref() };
+      let stats = crate::core::reindex::reindex(&db, &opts, &mut |s| eprint!("\r{} certificates done", s.certificates))?;
+      eprintln!();
+      if let Some(s) = search_index {
+        s.commit()?;
+      }
+      println!("{} issuers set, {} names added, {} certificates could not be parsed", stats.issuers_set, stats.names_added, stats.unparsable);
+      Ok(())
+    },
+    Command::Partitions { cmd } => {
+      let db = crate::core::db::open_db(&config)?;
+      use crate::core::partitions;
+      match cmd {
+        PartitionsCommand::List => {
+          for p in partitions::list(&db)? {
+            println!("{:<28} {:<48} {:>12}  {}", p.parent, p.name, p.estimated_rows, p.bound);
+          }
+        },
+        PartitionsCommand::Create { months_ahead } => {
+          println!("{} partitions created", partitions::create_month_partitions(&db, months_ahead)?);
+        },
+        PartitionsCommand::RetireLog { log, drop } => {
+          let name = partitions::retire_log(&db, &log, drop)?;
+          println!("{} {}", if drop { "Dropped" } else { "Detached" }, name);
+        }
+      }
+      Ok(())
+    },
+    Command::Prune { dry_run } => {
+      let db = crate::core::db::open_db(&config)?;
+      let deletes_certs = !dry_run && config.retention.expired_certificate_days.is_some();
+      let search_index = if config.search.enabled && deletes_certs { Some(crate::core::search::SearchIndex::open(&config.search)?) } else { None };
+      let stats = crate::core::prune::prune(&db, &config.retention, search_index.as_deref(), dry_run)?;
+      if let Some(s) = search_index {
+        s.commit()?;
+      }
+      println!("Run {}: {} {} sth and {} certificates", stats.run_id, if dry_run { "would delete" } else { "deleted" },
+               stats.sth_deleted, stats.certificates_deleted);
+      Ok(())
+    },
+    Command::BenchIngest { leaves, batch_sizes } => {
+      let db = crate::core::db::open_db(&config)?;
+      bench::bench_ingest(&db, leaves, &batch_sizes)
+    },
+    Command::Verify { log, fetch_proofs, output } => {
+      let db = crate::core::db::open_db(&config)?;
+      let report = crate::core::verify::verify(&db, &config, &crate::core::verify::Options { log, fetch_proofs })?;
+      match output {
+        Some(path) => serde_json::to_writer_pretty(std::fs::File::create(path)?, &report)?,
+        None => {
+          serde_json::to_writer_pretty(std::io::stdout(), &report)?;
+          println!();
+        }
+      }
+      if report.total_violations > 0 {
+        std::process::exit(2);
+      }
+      Ok(())
+    },
+    Command::RetryBackfill { log } => {
+      let db = crate::core::db::open_db(&config)?;
+      use crate::schema::merkle_backfill_failures::dsl as mbf;
+      let cleared = diesel::delete(mbf::merkle_backfill_failures.filter(mbf::log_id.eq(log))).execute(&db)?;
+      if cleared == 0 {
+        eprintln!("Backfill of {} has not failed.", log);
+        std::process::exit(1);
+      }
+      println!("Cleared the backfill failure of {}", log);
+      Ok(())
+    },
+    Command::ApiKeys { cmd } => {
+      let db = crate::core::db::open_db(&config)?;
+      use crate::core::api_keys;
+      match cmd {
+        ApiKeysCommand::Create { name, scopes, per_minute } => {
+          let (id, key) = api_keys::create(&db, &name, &scopes, per_minute)?;
+          println!("Created key {}: {}", id, key);
+        },
+        ApiKeysCommand::List => {
+          for k in api_keys::list(&db)? {
+            let scopes = k.scopes.iter().map(|s| s.as_str()).collect::<Vec<_>>().join(",");
+            let per_minute = k.per_minute.map(|n| format!("{}/min", n)).unwrap_or_else(|| "default".to_owned());
+            let revoked = k.revoked_at.map(|t| format!("revoked {}", t.to_rfc3339())).unwrap_or_default();
+            println!("{:>6}  {:<24} {:<28} {:<10} created {}  {}", k.id, k.name, scopes, per_minute, k.created_at.to_rfc3339(), revoked);
+          }
+        },
+        ApiKeysCommand::Revoke { id } => {
+          if !api_keys::revoke(&db, id)? {
+            eprintln!("No unrevoked key {}.", id);
+            std::process::exit(1);
+          }
+          println!("Revoked key {}", id);
+        }
+      }
+      Ok(())
+    }
+  }
+}
+
+fn init_logs(db: &DBConn, config: &Config) -> Result<(), Box<dyn Error>> {
+  #[derive(Debug, Error)]
+  #[error("Failed to initialize ctlogs table: {0}")]
+  struct E(#[source] crate::core::initialise_ctlogs_table::E);
+  crate::core::initialise_ctlogs_table::initialise_or_update_ctlogs_table(db, config)
+      .map_err(|e| Box::new(E(e)))?;
+  Ok(())
+}
+
+/// Seed the ctlogs table if no log is being monitored, then start the update threads (unless
+/// ingestion is turned off). Stream clients are checked against `access`, which the API shares.
+fn start_monitoring(ctx: &CtCrabContext, access: &Arc<crate::api::Access>) -> Result<(), Box<dyn Error>> {
+  {
+    use crate::schema::ctlogs::dsl::*;
+    let db = ctx.db()?;
+    let count: usize = TryFrom::<i64>::try_from(ctlogs.select(count_star()).filter(monitoring.eq(true)).first(&db)
+        .map_err(Box::new)?).unwrap();
+    if count == 0 {
+      init_logs(&db, ctx.config())?;
+      std::thread::sleep(Duration::from_millis(200)); // to give db time to sync changes
+    }
+  }
+  if ctx.config().stream.enabled {
+    ctx.start_stream_server(crate::api::stream_authorizer(access.clone(), ctx.db_pool().clone()))?;
+  }
+  if ctx.config().ingestion.enabled {
+    ctx.start_partition_maintenance()?;
+    ctx.init_update_threads()?;
+  }
+  if ctx.config().retention.enabled {
+    ctx.start_pruning()?;
+  }
+  Ok(())
+}
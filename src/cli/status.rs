@@ -0,0 +1,92 @@
+use std::error::Error;
+use std::io::Write;
+
+use chrono::{DateTime, Utc};
+use diesel::expression::count::count_star;
+use diesel::prelude::*;
+
+use crate::core::db::DBConn;
+use crate::models::{CtLog, Sth};
+
+struct Row {
+  name: String,
+  log_id: String,
+  monitoring: bool,
+  tree_size: Option<i64>,
+  received: Option<DateTime<Utc>>,
+  consistency_errors: i64,
+  fetch_errors: i64,
+  last_sth_error: Option<String>,
+}
+
+pub fn print_status<W: Write>(db: &DBConn, all: bool, out: &mut W) -> Result<(), Box<dyn Error>> {
+  let logs: Vec<CtLog> = {
+    use crate::schema::ctlogs::dsl::*;
+    let query = ctlogs.order_by((monitoring.desc(), name.asc()));
+    if all {
+      query.load(db)?
+    } else {
+      query.filter(monitoring.eq(true)).load(db)?
+    }
+  };
+  let mut rows = Vec::with_capacity(logs.len());
+  for log in logs {
+    let latest: Option<Sth> = match log.latest_sth {
+      Some(sth_id) => {
+        use crate::schema::sth::dsl::*;
+        sth.filter(id.eq(sth_id)).first(db).optional()?
+      },
+      None => None
+    };
+    let consistency_errors: i64 = {
+      use crate::schema::consistency_check_errors::dsl::*;
+      consistency_check_errors.select(count_star()).filter(log_id.eq(log.log_id)).first(db)?
+    };
+    let fetch_errors: i64 = {
+      use crate::schema::cert_fetch_errors::dsl::*;
+      cert_fetch_errors.select(count_star()).filter(log_id.eq(log.log_id)).first(db)?
+    };
+    rows.push(Row {
+      name: log.name,
+      log_id: log.log_id.to_string(),
+      monitoring: log.monitoring,
+      tree_size: latest.as_ref().map(|s| s.tree_size),
+      received: latest.as_ref().map(|s| s.received_time),
+      consistency_errors,
+      fetch_errors,
+      last_sth_error: log.last_sth_error,
+    });
+  }
+
+  let name_width = rows.iter().map(|r| r.name.chars().count()).max().unwrap_or(0).max(4);
+  writeln!(out, "{:<name_width$}  {:<16}  {:<3}  {:>12}  {:>10}  {:>6}  {:>6}  {}",
+           "NAME", "LOG ID", "MON", "TREE SIZE", "STH AGE", "C.ERR", "F.ERR", "LAST STH ERROR",
+           name_width = name_width)?;
+  let now = Utc::now();
+  for r in rows {
+    writeln!(out, "{:<name_width$}  {:<16}  {:<3}  {:>12}  {:>10}  {:>6}  {:>6}  {}",
+             r.name,
+             &r.log_id[..16],
+             if r.monitoring { "yes" } else { "no" },
+             r.tree_size.map(|s| s.to_string()).unwrap_or_else(|| "-".to_owned()),
+             r.received.map(|t| format_age(now - t)).unwrap_or_else(|| "-".to_owned()),
+             r.consistency_errors,
+             r.fetch_errors,
+             r.last_sth_error.as_deref().unwrap_or(""),
+             name_width = name_width)?;
+  }
+  Ok(())
+}
+
+fn format_age(d: chrono::Duration) -> String {
+  let s = d.num_seconds().max(0);
+  if s < 120 {
+    format!("{}s", s)
+  } else if s < 7200 {
+    format!("{}m", s / 60)
+  } else if s < 172800 {
+    format!("{}h", s / 3600)
+  } else {
+    format!("{}d", s / 86400)
+  }
+}
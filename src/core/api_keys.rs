@@ -0,0 +1,150 @@
+//! API keys. A key is only shown when it is created: the database keeps its sha256, the scopes it
+//! grants, and optionally a rate limit of its own.
+
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
+use ctclient::internal::re_exports::openssl::rand::rand_bytes;
+use ctclient::internal::re_exports::openssl::sha::sha256;
+use diesel::prelude::*;
+
+use crate::core::db::DBConn;
+use crate::models::Hash;
+
+/// Keys start with this, so that they are easy to recognise (and to scan for in leaked files).
+const KEY_PREFIX: &str = "ctcrab_";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Scope {
+  /// Certificates, issuers, names, changes, feeds, exports, mirrored logs and the certificate
+  /// stream.
+  Read,
+  /// Full-text search.
+  Search,
+  /// Managing webhooks. No route needs it yet; it is accepted so that keys can be given it ahead
+  /// of time.
+  Webhooks,
+  /// Anything.
+  Admin,
+}
+
+impl Scope {
+  pub fn as_str(self) -> &'static str {
+    match self {
+      Scope::Read => "read",
+      Scope::Search => "search",
+      Scope::Webhooks => "webhooks",
+      Scope::Admin => "admin",
+    }
+  }
+
+  /// Whether having this scope allows what `needed` does.
+  pub fn grants(self, needed: Scope) -> bool {
+    self == needed || self == Scope::Admin
+  }
+}
+
+impl fmt::Display for Scope {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(self.as_str())
+  }
+}
+
+#[derive(Debug, Error)]
+#[error("Unknown scope {0:?}, expected read, search, webhooks or admin.")]
+pub struct UnknownScope(String);
+
+impl FromStr for Scope {
+  type Err = UnknownScope;
+
+  fn from_str(s: &str) -> Result<Self, UnknownScope> {
+    match s {
+      "read" => Ok(Scope::Read),
+      "search" => Ok(Scope::Search),
+      "webhooks" => Ok(Scope::Webhooks),
+      "admin" => Ok(Scope::Admin),
+      _ => Err(UnknownScope(s.to_owned()))
+    }
+  }
+}
+
+#[derive(Debug)]
+pub struct ApiKey {
+  pub id: i64,
+  pub name: String,
+  pub scopes: Vec<Scope>,
+  /// Requests per minute, instead of `rate_limit.key_per_minute`.
+  pub per_minute: Option<u32>,
+  pub created_at: DateTime<Utc>,
+  pub revoked_at: Option<DateTime<Utc>>,
+}
+
+type Row = (i64, String, Vec<String>, Option<i32>, DateTime<Utc>, Option<DateTime<Utc>>);
+
+impl From<Row> for ApiKey {
+  fn from((id, name, scopes, per_minute, created_at, revoked_at): Row) -> Self {
+    ApiKey {
+      id,
+      name,
+      // Scopes this version does not know about grant nothing.
+      scopes: scopes.iter().filter_map(|s| s.parse().ok()).collect(),
+      per_minute: per_minute.map(|n| n as u32),
+      created_at,
+      revoked_at,
+    }
+  }
+}
+
+fn hash_key(key: &str) -> Hash {
+  Hash(sha256(key.as_bytes()))
+}
+
+/// Store a new key, and return its id and the key itself.
+pub fn create(db: &DBConn, name: &str, scopes: &[Scope], per_minute: Option<u32>) -> Result<(i64, String), Box<dyn std::error::Error>> {
+  let mut secret = [0u8; 24];
+  rand_bytes(&mut secret)?;
+  let key = format!("{}{}", KEY_PREFIX, base64::encode_config(&secret, base64::URL_SAFE_NO_PAD));
+  use crate::schema::api_keys::dsl as k;
+  let id = diesel::insert_into(k::api_keys)
+      .values((
+        k::name.eq(name),
+        k::key_hash.eq(hash_key(&key)),
+        k::scopes.eq(scopes.iter().map(|s| s.as_str()).collect::<Vec<_>>()),
+        k::per_minute.eq(per_minute.map(|n| n as i32)),
+      ))
+      .returning(k::id)
+      .get_result(db)?;
+  Ok((id, key))
+}
+
+/// The unrevoked key `key`, if there is one.
+pub fn find(db: &DBConn, key: &str) -> QueryResult<Option<ApiKey>> {
+  if !key.starts_with(KEY_PREFIX) {
+    return Ok(None);
+  }
+  use crate::schema::api_keys::dsl as k;
+  let row: Option<Row> = k::api_keys
+      .select((k::id, k::name, k::scopes, k::per_minute, k::created_at, k::revoked_at))
+      .filter(k::key_hash.eq(hash_key(key)).and(k::revoked_at.is_null()))
+      .first(db).optional()?;
+  Ok(row.map(ApiKey::from))
+}
+
+pub fn list(db: &DBConn) -> QueryResult<Vec<ApiKey>> {
+  use crate::schema::api_keys::dsl as k;
+  let rows: Vec<Row> = k::api_keys
+      .select((k::id, k::name, k::scopes, k::per_minute, k::created_at, k::revoked_at))
+      .order_by(k::id.asc())
+      .load(db)?;
+  Ok(rows.into_iter().map(ApiKey::from).collect())
+}
+
+/// Returns whether there was an unrevoked key with this id.
+pub fn revoke(db: &DBConn, id: i64) -> QueryResult<bool> {
+  use crate::schema::api_keys::dsl as k;
+  let n = diesel::update(k::api_keys.filter(k::id.eq(id).and(k::revoked_at.is_null())))
+      .set(k::revoked_at.eq(diesel::dsl::now))
+      .execute(db)?;
+  Ok(n > 0)
+}
@@ -0,0 +1,242 @@
+//! Information extracted from certificates for indexing.
+
+use std::collections::BTreeSet;
+use std::convert::TryFrom;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+use ctclient::internal::re_exports::openssl;
+use openssl::sha::sha256;
+use openssl::x509::{X509, X509NameRef, X509Ref};
+
+/// A minimal DER reader, for the few fields openssl doesn't expose.
+pub struct DerReader<'a> {
+  buf: &'a [u8],
+}
+
+impl<'a> DerReader<'a> {
+  pub fn new(buf: &'a [u8]) -> Self {
+    DerReader { buf }
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.buf.is_empty()
+  }
+
+  /// The next element's tag and content.
+  pub fn read(&mut self) -> Option<(u8, &'a [u8])> {
+    let tag = *self.buf.get(0)?;
+    let l = *self.buf.get(1)? as usize;
+    let (len, header_len) = if l < 0x80 {
+      (l, 2)
+    } else {
+      let n = l & 0x7f;
+      if n == 0 || n > 4 {
+        return None;
+      }
+      let len = self.buf.get(2..2 + n)?.iter().fold(0usize, |acc, b| acc << 8 | *b as usize);
+      (len, 2 + n)
+    };
+    let content = self.buf.get(header_len..header_len.checked_add(len)?)?;
+    self.buf = &self.buf[header_len + len..];
+    Some((tag, content))
+  }
+}
+
+const OID_AUTHORITY_KEY_IDENTIFIER: &[u8] = &[0x55, 0x1d, 0x23];
+
+/// The extensions of a DER certificate, as (oid, value) pairs.
+fn extensions(der: &[u8]) -> Option<Vec<(&[u8], &[u8])>> {
+  let (_, cert) = DerReader::new(der).read()?;
+  let (_, tbs) = DerReader::new(cert).read()?;
+  let mut tbs = DerReader::new(tbs);
+  let exts = loop {
+    match tbs.read()? {
+      (0xa3, exts) => break exts,
+      _ => continue
+    }
+  };
+  let (_, exts) = DerReader::new(exts).read()?;
+  let mut exts = DerReader::new(exts);
+  let mut out = Vec::new();
+  while !exts.is_empty() {
+    let (_, ext) = exts.read()?;
+    let mut ext = DerReader::new(ext);
+    let (_, oid) = ext.read()?;
+    let mut value = ext.read()?;
+    if value.0 == 0x01 { // critical
+      value = ext.read()?;
+    }
+    out.push((oid, value.1));
+  }
+  Some(out)
+}
+
+/// The keyIdentifier of the authority key identifier extension, if any.
+pub fn authority_key_id(der: &[u8]) -> Option<Vec<u8>> {
+  let (_, aki) = extensions(der)?.into_iter().find(|(oid, _)| *oid == OID_AUTHORITY_KEY_IDENTIFIER)?;
+  let (_, aki) = DerReader::new(aki).read()?;
+  let mut aki = DerReader::new(aki);
+  while !aki.is_empty() {
+    if let (0x80, key_id) = aki.read()? {
+      return Some(key_id.to_vec());
+    }
+  }
+  None
+}
+
+/// A name as `C=US, O=Let's Encrypt, CN=R3`, in the order it appears in the certificate.
+pub fn format_name(name: &X509NameRef) -> String {
+  name.entries().map(|e| {
+    let key = e.object().nid().short_name().map(|s| s.to_owned()).unwrap_or_else(|_| e.object().to_string());
+    let value = e.data().as_utf8().map(|s| s.to_string()).unwrap_or_else(|_| String::from_utf8_lossy(e.data().as_slice()).into_owned());
+    format!("{}={}", key, value)
+  }).collect::<Vec<_>>().join(", ")
+}
+
+/// What identifies the CA which issued a certificate. An unknown authority key id or issuer key is
+/// empty rather than `NULL`, so that the triple can be used as a unique key.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct IssuerInfo {
+  pub issuer_dn: String,
+  pub authority_key_id: Vec<u8>,
+  pub spki_sha256: Vec<u8>,
+}
+
+/// `issuer` is the next certificate in the chain, if any.
+pub fn issuer_info(cert: &X509Ref, issuer: Option<&X509Ref>) -> IssuerInfo {
+  let der = cert.to_der().unwrap();
+  IssuerInfo {
+    issuer_dn: format_name(cert.issuer_name()),
+    authority_key_id: authority_key_id(&der).unwrap_or_default(),
+    spki_sha256: issuer.and_then(|i| i.public_key().ok())
+        .and_then(|k| k.public_key_to_der().ok())
+        .map(|spki| sha256(&spki).to_vec())
+        .unwrap_or_default(),
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum NameType {
+  Dns,
+  Ip,
+  Email,
+  Uri,
+}
+
+impl NameType {
+  pub fn as_str(self) -> &'static str {
+    match self {
+      NameType::Dns => "dns",
+      NameType::Ip => "ip",
+      NameType::Email => "email",
+      NameType::Uri => "uri",
+    }
+  }
+}
+
+impl FromStr for NameType {
+  type Err = ();
+
+  fn from_str(s: &str) -> Result<Self, ()> {
+    match s {
+      "dns" => Ok(NameType::Dns),
+      "ip" => Ok(NameType::Ip),
+      "email" => Ok(NameType::Email),
+      "uri" => Ok(NameType::Uri),
+      _ => Err(())
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct CertName {
+  pub name_type: NameType,
+  pub name: String,
+  /// Network order address bytes, for ip names.
+  pub ip: Option<Vec<u8>>,
+}
+
+fn ip_to_string(ip: &[u8]) -> Option<String> {
+  match ip.len() {
+    4 => Some(Ipv4Addr::from(<[u8; 4]>::try_from(ip).unwrap()).to_string()),
+    16 => Some(Ipv6Addr::from(<[u8; 16]>::try_from(ip).unwrap()).to_string()),
+    _ => None
+  }
+}
+
+/// The DNS names found by ctclient (which also considers the subject CN), and the ip, email and
+/// URI subject alternative names, without duplicates.
+pub fn cert_names(cert: &X509) -> Result<Vec<CertName>, ctclient::Error> {
+  let mut names = BTreeSet::new();
+  for name in ctclient::certutils::get_dns_names(cert)? {
+    names.insert(CertName { name_type: NameType::Dns, name, ip: None });
+  }
+  for gn in cert.subject_alt_names().iter().flatten() {
+    if let Some(ip) = gn.ipaddress() {
+      if let Some(name) = ip_to_string(ip) {
+        names.insert(CertName { name_type: NameType::Ip, name, ip: Some(ip.to_vec()) });
+      }
+    } else if let Some(email) = gn.email() {
+      names.insert(CertName { name_type: NameType::Email, name: email.to_owned(), ip: None });
+    } else if let Some(uri) = gn.uri() {
+      names.insert(CertName { name_type: NameType::Uri, name: uri.to_owned(), ip: None });
+    }
+  }
+  Ok(names.into_iter().collect())
+}
+
+/// The first and last address of an address or CIDR block such as `10.0.0.0/8`, as bytes.
+pub fn ip_range(q: &str) -> Option<(Vec<u8>, Vec<u8>)> {
+  let mut parts = q.splitn(2, '/');
+  let addr = match IpAddr::from_str(parts.next()?).ok()? {
+    IpAddr::V4(a) => a.octets().to_vec(),
+    IpAddr::V6(a) => a.octets().to_vec(),
+  };
+  let bits = addr.len() * 8;
+  let prefix = match parts.next() {
+    Some(p) => p.parse::<usize>().ok().filter(|p| *p <= bits)?,
+    None => bits
+  };
+  let (mut lo, mut hi) = (addr.clone(), addr);
+  for i in prefix..bits {
+    lo[i / 8] &= !(0x80 >> (i % 8));
+    hi[i / 8] |= 0x80 >> (i % 8);
+  }
+  Some((lo, hi))
+}
+
+#[cfg(test)]
+const TEST_CERT: &str = "MIIBsDCCAVegAwIBAgIUBJU8dFHJAW0mno5atCXccxxwtRMwCgYIKoZIzj0EAwIwLjELMAkGA1UEBhMCVVMxEDAOBgNVBAoMB1Rl\
+c3QgQ0ExDTALBgNVBAMMBFRlc3QwHhcNMjYxMDE4MTkzNDUxWhcNMjYxMDE5MTkzNDUxWjAuMQswCQYDVQQGEwJVUzEQMA4GA1UE\
+CgwHVGVzdCBDQTENMAsGA1UEAwwEVGVzdDBZMBMGByqGSM49AgEGCCqGSM49AwEHA0IABFpKfCmqH011fEE5s3+MEkFS74DeYZQQ\
+ehgd9pgd1iIqA2b4K2R4nK2RacSVxlorIsFyNtFbDZLJAFWiJS9103GjUzBRMB0GA1UdDgQWBBQO0QGpnKd6UhI6CShu1OjXIqZc\
+wjAfBgNVHSMEGDAWgBQO0QGpnKd6UhI6CShu1OjXIqZcwjAPBgNVHRMBAf8EBTADAQH/MAoGCCqGSM49BAMCA0cAMEQCIAbaE7WD\
+X2A9IFOoWgG+CnR7/ZCB0+NbDo8+pgdaPAb4AiAF9YaQda+FAm4BMFc04fcIYPnQDxIz6Go98TsmQ5469Q==";
+
+#[test]
+fn test_issuer_info() {
+  let der = base64::decode(TEST_CERT).unwrap();
+  let cert = openssl::x509::X509::from_der(&der).unwrap();
+  let info = issuer_info(&cert, Some(&cert));
+  assert_eq!(info.issuer_dn, "C=US, O=Test CA, CN=Test");
+  assert_eq!(ctclient::utils::u8_to_hex(&info.authority_key_id), "0ed101a99ca77a52123a09286ed4e8d722a65cc2");
+  assert_eq!(info.spki_sha256.len(), 32);
+  assert!(issuer_info(&cert, None).spki_sha256.is_empty());
+  assert_eq!(authority_key_id(&der[..der.len() - 80]), None);
+}
+
+#[test]
+fn test_ip_range() {
+  assert_eq!(ip_range("10.1.2.3"), Some((vec![10, 1, 2, 3], vec![10, 1, 2, 3])));
+  assert_eq!(ip_range("10.1.2.3/8"), Some((vec![10, 0, 0, 0], vec![10, 255, 255, 255])));
+  assert_eq!(ip_range("192.168.1.0/23"), Some((vec![192, 168, 0, 0], vec![192, 168, 1, 255])));
+  assert_eq!(ip_range("0.0.0.0/0"), Some((vec![0; 4], vec![255; 4])));
+  let (lo, hi) = ip_range("2001:db8::/32").unwrap();
+  assert_eq!(&lo[..], &[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+  assert_eq!(&hi[..4], &[0x20, 0x01, 0x0d, 0xb8]);
+  assert_eq!(&hi[4..], &[0xff; 12]);
+  assert_eq!(ip_range("10.0.0.0/33"), None);
+  assert_eq!(ip_range("example.com"), None);
+  assert_eq!(ip_to_string(&lo).unwrap(), "2001:db8::");
+}
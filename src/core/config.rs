@@ -0,0 +1,484 @@
+use std::collections::BTreeSet;
+use std::fmt;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use ctclient::internal::re_exports::reqwest;
+use serde::Deserialize;
+
+use crate::core::api_keys::Scope;
+use crate::models::Hash;
+
+/// Path used when neither `--config` nor `CTCRAB_CONFIG` is given. It is fine for this file to not
+/// exist, in which case defaults and environment overrides are used.
+pub const DEFAULT_CONFIG_PATH: &str = "ctcrab.toml";
+pub const DEFAULT_LOG_LIST_URL: &str = "https://www.gstatic.com/ct/log_list/v2/log_list.json";
+
+const ENV_PREFIX: &str = "CTCRAB_";
+const SECTIONS: &[&str] = &["database", "log_list", "http", "ingestion", "api", "tiles", "search", "retention", "stream", "rate_limit"];
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+  pub database: DatabaseConfig,
+  pub log_list: LogListConfig,
+  pub http: HttpConfig,
+  pub ingestion: IngestionConfig,
+  pub api: ApiConfig,
+  pub tiles: TilesConfig,
+  pub search: SearchConfig,
+  pub retention: RetentionConfig,
+  pub stream: StreamConfig,
+  pub rate_limit: RateLimitConfig,
+  /// `[[log]]` entries in the file.
+  #[serde(rename = "log")]
+  pub log_overrides: Vec<LogOverride>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+  pub url: Option<String>,
+  pub pool_size: u32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogListConfig {
+  /// Where to fetch a v2 format log list from.
+  pub url: String,
+  /// If set, the log list is read from this file instead of `url`.
+  pub file: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpConfig {
+  pub timeout_ms: u64,
+  pub connect_timeout_ms: u64,
+  pub user_agent: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IngestionConfig {
+  /// Whether to start update threads at all.
+  pub enabled: bool,
+  pub poll_interval_ms: u64,
+  /// How many leaves to fetch per round when building up the Merkle frontier of a log that was not
+  /// monitored from tree size 0. 0 turns this off, in which case root hashes are only checked
+  /// for logs whose frontier already exists.
+  pub merkle_backfill_batch: u64,
+  /// Whether to keep the raw leaf_input and extra_data of fetched entries. Can be overridden per
+  /// log.
+  pub store_raw_leaves: bool,
+  /// Number of leaves whose certificates are written in one transaction. See `ctcrab
+  /// bench-ingest` for the effect on throughput.
+  pub batch_size: usize,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ApiConfig {
+  pub address: String,
+  pub port: u16,
+  pub workers: Option<u16>,
+  /// Scopes (see `api_keys::Scope`) that requests without an API key have. Log status routes need
+  /// none.
+  pub anonymous_scopes: Vec<String>,
+  /// How long caches may keep responses that change as logs are updated, such as `/ctlogs`,
+  /// before checking their ETag again.
+  pub cache_max_age_seconds: u32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TilesConfig {
+  /// Whether update threads export tiles whenever `latest_sth` advances. Only logs with a complete
+  /// set of raw leaves are exported.
+  pub enabled: bool,
+  pub output_dir: PathBuf,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SearchConfig {
+  /// Whether to keep a full-text index of certificate subjects, names and issuers.
+  pub enabled: bool,
+  pub index_dir: PathBuf,
+  pub writer_heap_mb: usize,
+  /// How often new documents are committed, and so become searchable.
+  pub commit_interval_ms: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionConfig {
+  /// Whether `serve` and `monitor-only` prune every `interval_hours`. `ctcrab prune` can be run
+  /// regardless.
+  pub enabled: bool,
+  pub interval_hours: u64,
+  /// Older sth are thinned out to one per log per hour, plus those that were ever `latest_sth`,
+  /// are referenced by an error, or are yet to be checked.
+  pub sth_days: u32,
+  /// If set, certificates that expired more than this many days ago are deleted, unless they have
+  /// a dns name matching `watchlist`.
+  pub expired_certificate_days: Option<u32>,
+  /// Dns names (`example.com`) or suffixes (`*.example.com`) of certificates never deleted.
+  pub watchlist: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StreamConfig {
+  /// Whether to serve the live feeds of newly ingested certificates and of log status changes, on
+  /// its own port.
+  pub enabled: bool,
+  pub address: String,
+  pub port: u16,
+  pub max_clients: usize,
+  /// Events queued per client. Once full, events for that client are dropped until it catches up.
+  pub queue_len: usize,
+  pub heartbeat_interval_ms: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+  /// Whether API requests are rate limited, per API key, and per client ip for requests without
+  /// one.
+  pub enabled: bool,
+  pub ip_per_minute: u32,
+  /// Unless the key has its own limit.
+  pub key_per_minute: u32,
+  /// How many seconds worth of requests can be made at once after a quiet period.
+  pub burst_seconds: u32,
+  /// Reverse proxies whose `X-Real-IP` header is taken as the client ip. For anyone else, the
+  /// header is ignored.
+  pub trusted_proxies: Vec<IpAddr>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LogOverride {
+  pub log_id: Hash,
+  /// Force monitoring on or off regardless of the state in the log list.
+  pub monitoring: Option<bool>,
+  pub endpoint_url: Option<String>,
+  pub poll_interval_ms: Option<u64>,
+  pub store_raw_leaves: Option<bool>,
+}
+
+impl Default for DatabaseConfig {
+  fn default() -> Self {
+    DatabaseConfig { url: None, pool_size: 20 }
+  }
+}
+
+impl Default for LogListConfig {
+  fn default() -> Self {
+    LogListConfig { url: DEFAULT_LOG_LIST_URL.to_owned(), file: None }
+  }
+}
+
+impl Default for HttpConfig {
+  fn default() -> Self {
+    HttpConfig {
+      timeout_ms: 30_000,
+      connect_timeout_ms: 10_000,
+      user_agent: format!("ctcrab/{}", env!("CARGO_PKG_VERSION")),
+    }
+  }
+}
+
+impl Default for IngestionConfig {
+  fn default() -> Self {
+    IngestionConfig { enabled: true, poll_interval_ms: 5000, merkle_backfill_batch: 1024, store_raw_leaves: false, batch_size: 256 }
+  }
+}
+
+impl Default for ApiConfig {
+  fn default() -> Self {
+    ApiConfig { address: "0.0.0.0".to_owned(), port: 9000, workers: None, anonymous_scopes: Vec::new(), cache_max_age_seconds: 10 }
+  }
+}
+
+impl Default for TilesConfig {
+  fn default() -> Self {
+    TilesConfig { enabled: false, output_dir: PathBuf::from("tiles") }
+  }
+}
+
+impl Default for SearchConfig {
+  fn default() -> Self {
+    SearchConfig { enabled: false, index_dir: PathBuf::from("search-index"), writer_heap_mb: 64, commit_interval_ms: 5000 }
+  }
+}
+
+impl Default for StreamConfig {
+  fn default() -> Self {
+    StreamConfig { enabled: false, address: "0.0.0.0".to_owned(), port: 9001, max_clients: 100, queue_len: 1000, heartbeat_interval_ms: 30_000 }
+  }
+}
+
+impl Default for RateLimitConfig {
+  fn default() -> Self {
+    RateLimitConfig { enabled: true, ip_per_minute: 120, key_per_minute: 1200, burst_seconds: 10, trusted_proxies: Vec::new() }
+  }
+}
+
+impl Default for RetentionConfig {
+  fn default() -> Self {
+    RetentionConfig { enabled: false, interval_hours: 24, sth_days: 30, expired_certificate_days: None, watchlist: Vec::new() }
+  }
+}
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+  #[error("Unable to read config file {path}: {source}")]
+  Io { path: PathBuf, #[source] source: std::io::Error },
+  #[error("Unable to parse config: {0}")]
+  Parse(#[source] toml::de::Error),
+  #[error("Invalid environment override {var}: {msg}")]
+  Env { var: String, msg: String },
+  #[error("Invalid config:\n{0}")]
+  Invalid(ValidationErrors),
+}
+
+#[derive(Debug)]
+pub struct ValidationErrors(pub Vec<String>);
+impl fmt::Display for ValidationErrors {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    for e in &self.0 {
+      writeln!(f, "  - {}", e)?;
+    }
+    Ok(())
+  }
+}
+
+impl Config {
+  /// Load the config file at `path` (or the default location), apply environment overrides, then
+  /// validate the result.
+  ///
+  /// A missing file is only an error if the path was explicitly given.
+  pub fn load(path: Option<&Path>) -> Result<Config, ConfigError> {
+    dotenv::dotenv().ok();
+    let explicit_path = path.map(|p| p.to_owned())
+        .or_else(|| std::env::var_os("CTCRAB_CONFIG").map(PathBuf::from));
+    let (path, must_exist) = match explicit_path {
+      Some(p) => (p, true),
+      None => (PathBuf::from(DEFAULT_CONFIG_PATH), false)
+    };
+    let mut value = match std::fs::read_to_string(&path) {
+      Ok(s) => s.parse::<toml::Value>().map_err(ConfigError::Parse)?,
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound && !must_exist => toml::Value::Table(Default::default()),
+      Err(e) => return Err(ConfigError::Io { path, source: e })
+    };
+    apply_env_overrides(&mut value, std::env::vars())?;
+    let config: Config = value.try_into().map_err(ConfigError::Parse)?;
+    config.validate().map_err(ConfigError::Invalid)?;
+    Ok(config)
+  }
+
+  pub fn validate(&self) -> Result<(), ValidationErrors> {
+    let mut errs = Vec::new();
+    if self.database.url.as_ref().map(|u| u.is_empty()).unwrap_or(true) {
+      errs.push("database.url is required (or set the DATABASE_URL environment variable).".to_owned());
+    }
+    if self.database.pool_size == 0 {
+      errs.push("database.pool_size must be at least 1.".to_owned());
+    }
+    if self.log_list.file.is_none() {
+      if let Err(e) = check_http_url(&self.log_list.url) {
+        errs.push(format!("log_list.url: {}", e));
+      }
+    }
+    if self.http.timeout_ms == 0 {
+      errs.push("http.timeout_ms must be positive.".to_owned());
+    }
+    if self.http.connect_timeout_ms == 0 {
+      errs.push("http.connect_timeout_ms must be positive.".to_owned());
+    }
+    if self.ingestion.poll_interval_ms < 250 {
+      errs.push("ingestion.poll_interval_ms must be at least 250.".to_owned());
+    }
+    if self.ingestion.batch_size == 0 {
+      errs.push("ingestion.batch_size must be at least 1.".to_owned());
+    }
+    if self.api.port == 0 {
+      errs.push("api.port must not be 0.".to_owned());
+    }
+    if self.api.workers == Some(0) {
+      errs.push("api.workers must be at least 1.".to_owned());
+    }
+    for s in &self.api.anonymous_scopes {
+      if let Err(e) = s.parse::<Scope>() {
+        errs.push(format!("api.anonymous_scopes: {}", e));
+      }
+    }
+    if self.search.writer_heap_mb < 4 {
+      errs.push("search.writer_heap_mb must be at least 4.".to_owned());
+    }
+    if self.search.commit_interval_ms == 0 {
+      errs.push("search.commit_interval_ms must be positive.".to_owned());
+    }
+    if self.retention.interval_hours == 0 {
+      errs.push("retention.interval_hours must be at least 1.".to_owned());
+    }
+    for w in &self.retention.watchlist {
+      let name = w.strip_prefix("*.").unwrap_or(w);
+      if name.is_empty() || name.contains('*') {
+        errs.push(format!("retention.watchlist: {:?} is not a dns name or *.suffix.", w));
+      }
+    }
+    if self.stream.enabled && self.stream.port == 0 {
+      errs.push("stream.port must not be 0.".to_owned());
+    }
+    if self.stream.enabled && self.stream.port == self.api.port {
+      errs.push("stream.port must differ from api.port.".to_owned());
+    }
+    if self.stream.queue_len == 0 {
+      errs.push("stream.queue_len must be at least 1.".to_owned());
+    }
+    if self.stream.heartbeat_interval_ms == 0 {
+      errs.push("stream.heartbeat_interval_ms must be positive.".to_owned());
+    }
+    if self.rate_limit.ip_per_minute == 0 || self.rate_limit.key_per_minute == 0 {
+      errs.push("rate_limit.ip_per_minute and rate_limit.key_per_minute must be at least 1.".to_owned());
+    }
+    let mut seen = BTreeSet::new();
+    for o in &self.log_overrides {
+      if !seen.insert(o.log_id) {
+        errs.push(format!("log {} is overridden more than once.", o.log_id));
+      }
+      if let Some(ref u) = o.endpoint_url {
+        if let Err(e) = check_http_url(u) {
+          errs.push(format!("log {}: endpoint_url: {}", o.log_id, e));
+        }
+      }
+      if let Some(i) = o.poll_interval_ms {
+        if i < 250 {
+          errs.push(format!("log {}: poll_interval_ms must be at least 250.", o.log_id));
+        }
+      }
+    }
+    if errs.is_empty() {
+      Ok(())
+    } else {
+      Err(ValidationErrors(errs))
+    }
+  }
+
+  pub fn database_url(&self) -> &str {
+    self.database.url.as_ref().expect("database.url checked by validate()")
+  }
+
+  pub fn log_override(&self, log_id: &Hash) -> Option<&LogOverride> {
+    self.log_overrides.iter().find(|o| &o.log_id == log_id)
+  }
+
+  pub fn poll_interval_for(&self, log_id: &Hash) -> Duration {
+    let ms = self.log_override(log_id).and_then(|o| o.poll_interval_ms)
+        .unwrap_or(self.ingestion.poll_interval_ms);
+    Duration::from_millis(ms)
+  }
+
+  pub fn store_raw_leaves_for(&self, log_id: &Hash) -> bool {
+    self.log_override(log_id).and_then(|o| o.store_raw_leaves)
+        .unwrap_or(self.ingestion.store_raw_leaves)
+  }
+}
+
+impl HttpConfig {
+  pub fn build_client(&self) -> reqwest::Result<reqwest::blocking::Client> {
+    reqwest::blocking::Client::builder()
+        .timeout(Duration::from_millis(self.timeout_ms))
+        .connect_timeout(Duration::from_millis(self.connect_timeout_ms))
+        .user_agent(&self.user_agent)
+        .build()
+  }
+}
+
+fn check_http_url(u: &str) -> Result<(), String> {
+  match reqwest::Url::parse(u) {
+    Ok(u) if u.scheme() == "http" || u.scheme() == "https" => Ok(()),
+    Ok(u) => Err(format!("unsupported scheme {:?}.", u.scheme())),
+    Err(e) => Err(format!("{}.", e))
+  }
+}
+
+/// Environment variables of the form `CTCRAB_<SECTION>_<KEY>` override `<key>` in `[<section>]`,
+/// e.g. `CTCRAB_DATABASE_POOL_SIZE=5` or `CTCRAB_INGESTION_ENABLED=false`. `DATABASE_URL` is also
+/// accepted for `database.url`.
+fn apply_env_overrides<I: Iterator<Item = (String, String)>>(value: &mut toml::Value, vars: I) -> Result<(), ConfigError> {
+  let root = match value {
+    toml::Value::Table(t) => t,
+    _ => unreachable!()
+  };
+  for (var, val) in vars {
+    let (section, key) = if var == "DATABASE_URL" {
+      ("database", "url".to_owned())
+    } else if var.starts_with(ENV_PREFIX) && var != "CTCRAB_CONFIG" {
+      let rest = var[ENV_PREFIX.len()..].to_ascii_lowercase();
+      let section = SECTIONS.iter()
+          .filter(|s| rest.starts_with(*s) && rest[s.len()..].starts_with('_'))
+          .max_by_key(|s| s.len());
+      match section {
+        Some(s) => (*s, rest[s.len() + 1..].to_owned()),
+        None => return Err(ConfigError::Env { var, msg: format!("unknown section, expected one of {:?}.", SECTIONS) })
+      }
+    } else {
+      continue;
+    };
+    let table = root.entry(section).or_insert_with(|| toml::Value::Table(Default::default()));
+    let table = match table {
+      toml::Value::Table(t) => t,
+      _ => return Err(ConfigError::Env { var, msg: format!("{} is not a table in the config file.", section) })
+    };
+    // Interpret the value as a TOML literal (numbers, booleans) if possible, otherwise as a string.
+    // Values like `1.0` for string fields are kept as strings.
+    let parsed = format!("v = {}", val).parse::<toml::Value>().ok()
+        .and_then(|mut v| v.as_table_mut().and_then(|t| t.remove("v")))
+        .filter(|v| v.is_str() || accepts(section, &key, v.clone()) || !accepts(section, &key, toml::Value::String(val.clone())))
+        .unwrap_or(toml::Value::String(val));
+    table.insert(key, parsed);
+  }
+  Ok(())
+}
+
+/// Whether `value` for `key` in `[section]`, with everything else left to its default, is a valid
+/// config.
+fn accepts(section: &str, key: &str, value: toml::Value) -> bool {
+  let mut t = toml::value::Table::new();
+  t.insert(key.to_owned(), value);
+  let mut root = toml::value::Table::new();
+  root.insert(section.to_owned(), toml::Value::Table(t));
+  toml::Value::Table(root).try_into::<Config>().is_ok()
+}
+
+#[test]
+fn test_env_overrides() {
+  let mut value: toml::Value = "[database]\npool_size = 3\n[api]\nport = 80".parse().unwrap();
+  apply_env_overrides(&mut value, vec![
+    ("DATABASE_URL".to_owned(), "postgres://localhost/ctcrab".to_owned()),
+    ("CTCRAB_LOG_LIST_URL".to_owned(), "https://example.com/list.json".to_owned()),
+    ("CTCRAB_INGESTION_ENABLED".to_owned(), "false".to_owned()),
+    ("CTCRAB_API_PORT".to_owned(), "8080".to_owned()),
+    ("CTCRAB_HTTP_USER_AGENT".to_owned(), "1.0".to_owned()),
+    ("HOME".to_owned(), "/root".to_owned()),
+  ].into_iter()).unwrap();
+  let config: Config = value.try_into().unwrap();
+  config.validate().unwrap();
+  assert_eq!(config.database.pool_size, 3);
+  assert_eq!(config.database_url(), "postgres://localhost/ctcrab");
+  assert_eq!(config.log_list.url, "https://example.com/list.json");
+  assert_eq!(config.ingestion.enabled, false);
+  assert_eq!(config.api.port, 8080);
+  assert_eq!(config.http.user_agent, "1.0");
+
+  let mut value: toml::Value = "".parse().unwrap();
+  assert!(apply_env_overrides(&mut value, vec![("CTCRAB_NOPE_X".to_owned(), "1".to_owned())].into_iter()).is_err());
+  let config: Config = "[database]\npool_size = 0\n[ingestion]\npoll_interval_ms = 10".parse::<toml::Value>().unwrap().try_into().unwrap();
+  assert_eq!(config.validate().unwrap_err().0.len(), 3);
+}
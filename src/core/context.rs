@@ -1,26 +1,74 @@
 use std::error::Error;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 
+use crate::core::config::Config;
 use crate::core::db::{create_db_pool, DBPool, DBPooledConn};
+use crate::core::events::EventBus;
+use crate::core::search::SearchIndex;
+use crate::core::stream_server::Authorize;
 use crate::core::update_thread;
 
 pub struct CtCrabContext {
+  config: Config,
   db_pool: DBPool,
+  search: Option<Arc<SearchIndex>>,
+  events: Arc<EventBus>,
   update_threads: Mutex<Vec<update_thread::Handle>>
 }
 
 impl CtCrabContext {
-  pub fn new() -> CtCrabContext {
-    CtCrabContext {
-      db_pool: create_db_pool(),
+  /// `monitoring` is whether this process runs update threads (and pruning), which then write to
+  /// the search index. Other processes only read it.
+  pub fn new(config: Config, monitoring: bool) -> Result<CtCrabContext, Box<dyn Error>> {
+    let writes_search = monitoring && (config.ingestion.enabled || config.retention.enabled);
+    Ok(CtCrabContext {
+      db_pool: create_db_pool(&config)?,
+      search: match (config.search.enabled, writes_search) {
+        (false, _) => None,
+        (true, true) => Some(SearchIndex::open(&config.search)?),
+        (true, false) => Some(SearchIndex::open_reader(&config.search)?),
+      },
+      events: Arc::new(EventBus::new(config.stream.queue_len)),
+      config,
       update_threads: Mutex::new(Vec::new())
-    }
+    })
+  }
+
+  pub fn config(&self) -> &Config {
+    &self.config
   }
 
   pub fn db(&self) -> Result<DBPooledConn, Box<dyn Error>> {
     self.db_pool.get().map_err(|x| Box::new(x) as _)
   }
 
+  /// For responses that need connections after the handler returns, such as streamed bodies.
+  pub fn db_pool(&self) -> &DBPool {
+    &self.db_pool
+  }
+
+  pub fn search(&self) -> Option<&SearchIndex> {
+    self.search.as_deref()
+  }
+
+  /// Keep monthly partitions created ahead of what update threads insert.
+  pub fn start_partition_maintenance(&self) -> Result<(), Box<dyn Error>> {
+    crate::core::partitions::start_maintenance_thread(self.db_pool.clone())?;
+    Ok(())
+  }
+
+  /// Serve `[stream]`, with what the update threads publish, to clients `authorize` accepts.
+  pub fn start_stream_server(&self, authorize: Arc<Authorize>) -> Result<(), Box<dyn Error>> {
+    crate::core::stream_server::start(&self.config.stream, self.events.clone(), authorize)?;
+    Ok(())
+  }
+
+  /// Prune according to `[retention]` every `interval_hours`.
+  pub fn start_pruning(&self) -> Result<(), Box<dyn Error>> {
+    crate::core::prune::start_thread(self.db_pool.clone(), self.config.retention.clone(), self.search.clone())?;
+    Ok(())
+  }
+
   pub fn init_update_threads(&self) -> Result<(), Box<dyn Error>> {
     let mut update_threads = self.update_threads.lock().unwrap();
     update_threads.truncate(0);
@@ -32,7 +80,17 @@ impl CtCrabContext {
       // Safety: we pass ing a &'static DBPool so that threading works. However
       // all threads using the DBPool will exit before self, and hence the DBPool, is actually
       // dropped.
-      let hdl = update_thread::init_thread(self.db_pool.clone(), l);
+      let opts = update_thread::Options {
+        poll_interval: self.config.poll_interval_for(&l.log_id),
+        http: self.config.http.clone(),
+        merkle_backfill_batch: self.config.ingestion.merkle_backfill_batch,
+        store_raw_leaves: self.config.store_raw_leaves_for(&l.log_id),
+        tiles_dir: if self.config.tiles.enabled { Some(self.config.tiles.output_dir.clone()) } else { None },
+        search: self.search.clone(),
+        batch_size: self.config.ingestion.batch_size,
+        events: if self.config.stream.enabled { Some(self.events.clone()) } else { None },
+      };
+      let hdl = update_thread::init_thread(self.db_pool.clone(), l, opts);
       update_threads.push(hdl);
     }
     Ok(())
@@ -2,20 +2,19 @@ use diesel::{Connection, PgConnection};
 use diesel::r2d2::{ConnectionManager, Pool, PooledConnection};
 use diesel::result::Error;
 
-fn get_url_from_env() -> String {
-  dotenv::dotenv().ok();
-  std::env::var("DATABASE_URL").map_err(|_| "Expected DATABASE_URL env.").unwrap()
-}
+use crate::core::config::Config;
 
 pub type DBConn = PgConnection;
-pub fn open_db() -> DBConn {
-  DBConn::establish(&get_url_from_env()).unwrap()
+pub fn open_db(config: &Config) -> diesel::ConnectionResult<DBConn> {
+  DBConn::establish(config.database_url())
 }
 
 pub type DBPool = Pool<ConnectionManager<DBConn>>;
 pub type DBPooledConn = PooledConnection<ConnectionManager<DBConn>>;
-pub fn create_db_pool() -> DBPool {
-  Pool::builder().max_size(20).build(ConnectionManager::new(&get_url_from_env())).unwrap()
+pub fn create_db_pool(config: &Config) -> Result<DBPool, diesel::r2d2::PoolError> {
+  Pool::builder()
+      .max_size(config.database.pool_size)
+      .build(ConnectionManager::new(config.database_url()))
 }
 
 use diesel::result::Error as DieselError;
@@ -0,0 +1,292 @@
+//! In-process publication of what the update threads do, for the live feeds served by
+//! `core::stream_server`. Each subscriber has a bounded queue: a subscriber that falls behind loses
+//! events (and is told how many) rather than slowing down ingestion or using unbounded memory.
+//! Subscribers only receive one kind of event, so that the rare log events don't queue up behind
+//! certificates.
+
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{Receiver, sync_channel, SyncSender, TrySendError};
+use std::time::SystemTime;
+
+use ctclient::internal::re_exports::openssl::hash::MessageDigest;
+use ctclient::internal::re_exports::openssl::x509::{X509, X509NameRef};
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+
+use crate::core::certinfo::{cert_names, NameType};
+use crate::models::{CtLog, Hash};
+use crate::models::inserts::asn1_time_to_datetime;
+
+pub enum Event {
+  Certificate(CertificateEvent),
+  Log(LogEvent),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+  Certificate,
+  Log,
+}
+
+impl Event {
+  pub fn kind(&self) -> EventKind {
+    match self {
+      Event::Certificate(_) => EventKind::Certificate,
+      Event::Log(_) => EventKind::Log,
+    }
+  }
+}
+
+/// Same fields as `BasicSthInfo` in the API.
+#[derive(Debug, Clone, Serialize)]
+pub struct SthInfo {
+  pub id: i64,
+  pub tree_size: i64,
+  pub tree_hash: Hash,
+  /// Milliseconds since the epoch.
+  pub received_time: i64,
+  pub sth_timestamp: i64,
+}
+
+/// A change in the status of a log, as shown by `/ctlogs` and `/log/<id>`.
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum LogEvent {
+  LatestSth { log_id: Hash, latest_sth: SthInfo },
+  /// `error` is `None` once fetching the sth succeeds again.
+  SthError { log_id: Hash, error: Option<String> },
+  ConsistencyError { log_id: Hash, from_sth_id: i64, to_sth_id: i64, error: String },
+  FetchError { log_id: Hash, from_tree_size: i64, to_tree_size: i64, error: String },
+}
+
+impl LogEvent {
+  pub fn log_id(&self) -> &Hash {
+    match self {
+      LogEvent::LatestSth { log_id, .. } | LogEvent::SthError { log_id, .. } |
+      LogEvent::ConsistencyError { log_id, .. } | LogEvent::FetchError { log_id, .. } => log_id
+    }
+  }
+
+  /// The name of the event in the `/events` stream.
+  pub fn name(&self) -> &'static str {
+    match self {
+      LogEvent::LatestSth { .. } => "latest_sth",
+      LogEvent::SthError { .. } => "sth_error",
+      LogEvent::ConsistencyError { .. } => "consistency_error",
+      LogEvent::FetchError { .. } => "fetch_error",
+    }
+  }
+}
+
+pub struct CertificateEvent {
+  pub log_id: Hash,
+  pub leaf_index: u64,
+  pub fingerprint: Hash,
+  /// Dns names, including the subject CN.
+  pub all_domains: Vec<String>,
+  precert: bool,
+  /// Starts with the end-entity certificate.
+  chain: Vec<X509>,
+  log_name: String,
+  endpoint_url: String,
+  seen: f64,
+  /// The certstream messages, built by the first subscriber that wants them.
+  lite: Mutex<Option<String>>,
+  full: Mutex<Option<String>>,
+}
+
+struct Subscriber {
+  kind: EventKind,
+  sender: SyncSender<Arc<Event>>,
+  dropped: Arc<AtomicU64>,
+}
+
+pub struct Subscription {
+  pub receiver: Receiver<Arc<Event>>,
+  dropped: Arc<AtomicU64>,
+}
+
+impl Subscription {
+  /// Number of events dropped because the queue was full, since the last call.
+  pub fn take_dropped(&self) -> u64 {
+    self.dropped.swap(0, Ordering::AcqRel)
+  }
+}
+
+pub struct EventBus {
+  subscribers: Mutex<Vec<Subscriber>>,
+  queue_len: usize,
+}
+
+impl EventBus {
+  pub fn new(queue_len: usize) -> EventBus {
+    EventBus { subscribers: Mutex::new(Vec::new()), queue_len }
+  }
+
+  /// Events of `kind` are received until the subscription is dropped.
+  pub fn subscribe(&self, kind: EventKind) -> Subscription {
+    let (sender, receiver) = sync_channel(self.queue_len);
+    let dropped = Arc::new(AtomicU64::new(0));
+    self.subscribers.lock().unwrap().push(Subscriber { kind, sender, dropped: dropped.clone() });
+    Subscription { receiver, dropped }
+  }
+
+  /// So that publishers can skip building events nobody will see.
+  pub fn has_subscribers(&self, kind: EventKind) -> bool {
+    self.subscribers.lock().unwrap().iter().any(|s| s.kind == kind)
+  }
+
+  /// Never blocks.
+  pub fn publish(&self, event: Event) {
+    let kind = event.kind();
+    let event = Arc::new(event);
+    self.subscribers.lock().unwrap().retain(|s| {
+      if s.kind != kind {
+        return true;
+      }
+      match s.sender.try_send(event.clone()) {
+        Ok(()) => true,
+        Err(TrySendError::Full(_)) => {
+          s.dropped.fetch_add(1, Ordering::AcqRel);
+          true
+        },
+        Err(TrySendError::Disconnected(_)) => false
+      }
+    });
+  }
+}
+
+pub fn unix_time_f64(t: SystemTime) -> f64 {
+  t.duration_since(SystemTime::UNIX_EPOCH).map(|d| d.as_secs_f64()).unwrap_or(0.0)
+}
+
+/// `{"aggregated": "/C=US/CN=example.com", "C": "US", "CN": "example.com", ...}`
+fn certstream_name(name: &X509NameRef) -> Value {
+  let mut obj = Map::new();
+  for key in &["C", "ST", "L", "O", "OU", "CN"] {
+    obj.insert((*key).to_owned(), Value::Null);
+  }
+  let mut aggregated = String::new();
+  for e in name.entries() {
+    let key = match e.object().nid().short_name() {
+      Ok(k) => k,
+      Err(_) => continue
+    };
+    let value = match e.data().as_utf8() {
+      Ok(v) => v.to_string(),
+      Err(_) => continue
+    };
+    aggregated.push_str(&format!("/{}={}", key, value));
+    obj.insert(key.to_owned(), Value::String(value));
+  }
+  obj.insert("aggregated".to_owned(), Value::String(aggregated));
+  Value::Object(obj)
+}
+
+fn certstream_cert(cert: &X509, full: bool) -> Value {
+  let sha1 = cert.digest(MessageDigest::sha1()).map(|d| {
+    d.iter().map(|b| format!("{:02X}", b)).collect::<Vec<_>>().join(":")
+  }).unwrap_or_default();
+  let serial = cert.serial_number().to_bn().ok().and_then(|bn| bn.to_hex_str().ok()).map(|s| s.to_string()).unwrap_or_default();
+  let san = cert.subject_alt_names().map(|names| names.iter().filter_map(|gn| gn.dnsname().map(|d| format!("DNS:{}", d))).collect::<Vec<_>>().join(", "));
+  let mut extensions = Map::new();
+  if let Some(san) = san {
+    extensions.insert("subjectAltName".to_owned(), Value::String(san));
+  }
+  let mut v = json!({
+    "subject": certstream_name(cert.subject_name()),
+    "issuer": certstream_name(cert.issuer_name()),
+    "extensions": extensions,
+    "not_before": asn1_time_to_datetime(cert.not_before()).map(|t| t.timestamp()),
+    "not_after": asn1_time_to_datetime(cert.not_after()).map(|t| t.timestamp()),
+    "serial_number": serial,
+    "fingerprint": sha1,
+  });
+  if full {
+    v["as_der"] = Value::String(base64::encode(&cert.to_der().unwrap_or_default()));
+  }
+  v
+}
+
+impl CertificateEvent {
+  /// `chain` starts with the end-entity certificate.
+  pub fn new(log: &CtLog, leaf_index: u64, precert: bool, fingerprint: Hash, chain: &[X509]) -> CertificateEvent {
+    let all_domains = cert_names(&chain[0]).unwrap_or_default().into_iter()
+        .filter(|n| n.name_type == NameType::Dns)
+        .map(|n| n.name)
+        .collect::<Vec<_>>();
+    CertificateEvent {
+      log_id: log.log_id,
+      leaf_index,
+      fingerprint,
+      all_domains,
+      precert,
+      chain: chain.to_vec(),
+      log_name: log.name.clone(),
+      endpoint_url: log.endpoint_url.clone(),
+      seen: unix_time_f64(SystemTime::now()),
+      lite: Mutex::new(None),
+      full: Mutex::new(None),
+    }
+  }
+
+  /// The certstream `certificate_update` message, without the DER of the certificates.
+  pub fn certstream_lite(&self) -> String {
+    self.get_or_build(&self.lite, false)
+  }
+
+  /// The certstream `certificate_update` message, with `as_der` on each certificate.
+  pub fn certstream_full(&self) -> String {
+    self.get_or_build(&self.full, true)
+  }
+
+  fn get_or_build(&self, cached: &Mutex<Option<String>>, full: bool) -> String {
+    cached.lock().unwrap().get_or_insert_with(|| self.certstream_message(full)).clone()
+  }
+
+  fn certstream_message(&self, full: bool) -> String {
+    let mut leaf_cert = certstream_cert(&self.chain[0], full);
+    leaf_cert["all_domains"] = json!(self.all_domains);
+    leaf_cert["sha256"] = json!(self.fingerprint.to_string());
+    let source_url = self.endpoint_url.trim_start_matches("https://").trim_start_matches("http://");
+    json!({
+      "message_type": "certificate_update",
+      "data": {
+        "update_type": if self.precert { "PrecertLogEntry" } else { "X509LogEntry" },
+        "leaf_cert": leaf_cert,
+        "chain": self.chain[1..].iter().map(|c| certstream_cert(c, full)).collect::<Vec<_>>(),
+        "cert_index": self.leaf_index,
+        "cert_link": format!("{}ct/v1/get-entries?start={}&end={}", self.endpoint_url, self.leaf_index, self.leaf_index),
+        "seen": self.seen,
+        "source": {
+          "url": source_url,
+          "name": self.log_name,
+          "log_id": self.log_id.to_string(),
+        },
+      },
+    }).to_string()
+  }
+
+  /// Whether any name is `suffix` or a subdomain of it.
+  pub fn matches_suffix(&self, suffix: &str) -> bool {
+    self.all_domains.iter().any(|d| {
+      d == suffix || (d.ends_with(suffix) && d[..d.len() - suffix.len()].ends_with('.'))
+    })
+  }
+}
+
+#[test]
+fn test_subscription_kind() {
+  let bus = EventBus::new(1);
+  let certs = bus.subscribe(EventKind::Certificate);
+  let logs = bus.subscribe(EventKind::Log);
+  assert!(bus.has_subscribers(EventKind::Log));
+  for _ in 0..2 {
+    bus.publish(Event::Log(LogEvent::SthError { log_id: Hash([0; 32]), error: None }));
+  }
+  assert!(certs.receiver.try_recv().is_err());
+  assert_eq!(certs.take_dropped(), 0);
+  assert!(logs.receiver.try_recv().is_ok());
+  assert_eq!(logs.take_dropped(), 1);
+}
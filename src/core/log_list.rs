@@ -0,0 +1,151 @@
+//! Parsing of the v2 log list format (as published at
+//! <https://www.gstatic.com/ct/log_list/v2/log_list.json>).
+
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+
+use serde::Deserialize;
+
+use crate::core::config::{Config, HttpConfig};
+use crate::models::Hash;
+
+#[derive(Debug, Error)]
+pub enum LogListError {
+  #[error("Unable to fetch log list: {0}")]
+  Http(#[source] ctclient::internal::re_exports::reqwest::Error),
+  #[error("Unable to read log list: {0}")]
+  Io(#[source] std::io::Error),
+  #[error("Unable to parse log list: {0}")]
+  Parse(#[source] serde_json::Error),
+  #[error("Log list contains an invalid entry for {description:?}: {msg}")]
+  InvalidLog { description: String, msg: &'static str },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogState {
+  Pending,
+  Qualified,
+  Usable,
+  Readonly,
+  Retired,
+  Rejected,
+}
+
+#[derive(Debug, Clone)]
+pub struct Log {
+  pub base_url: String,
+  pub description: String,
+  pub pub_key: Vec<u8>,
+  pub state: LogState,
+  /// Name of the operator.
+  pub operator: String,
+}
+
+pub struct LogList {
+  pub map_id_to_log: BTreeMap<Hash, Log>,
+}
+
+#[derive(Deserialize)]
+struct RawLogList {
+  operators: Vec<RawOperator>,
+}
+
+#[derive(Deserialize)]
+struct RawOperator {
+  name: String,
+  logs: Vec<RawLog>,
+}
+
+#[derive(Deserialize)]
+struct RawLog {
+  description: String,
+  log_id: String,
+  key: String,
+  url: String,
+  #[serde(default)]
+  state: BTreeMap<String, serde_json::Value>,
+}
+
+impl LogList {
+  /// Fetch the log list from the source given in `config.log_list`.
+  pub fn get(config: &Config) -> Result<LogList, LogListError> {
+    let json = match config.log_list.file {
+      Some(ref f) => std::fs::read(f).map_err(LogListError::Io)?,
+      None => fetch(&config.http, &config.log_list.url)?
+    };
+    Self::parse(&json)
+  }
+
+  pub fn parse(json: &[u8]) -> Result<LogList, LogListError> {
+    let raw: RawLogList = serde_json::from_slice(json).map_err(LogListError::Parse)?;
+    let mut map_id_to_log = BTreeMap::new();
+    for (operator, l) in raw.operators.into_iter().flat_map(|o| {
+      let name = o.name;
+      o.logs.into_iter().map(move |l| (name.clone(), l))
+    }) {
+      macro_rules! invalid {
+        ($msg:expr) => {
+          LogListError::InvalidLog { description: l.description.clone(), msg: $msg }
+        };
+      }
+      let id: [u8; 32] = base64::decode(&l.log_id).ok()
+          .and_then(|v| v[..].try_into().ok())
+          .ok_or_else(|| invalid!("log_id is not a base64 encoded 32 byte hash"))?;
+      let pub_key = base64::decode(&l.key).map_err(|_| invalid!("key is not valid base64"))?;
+      let state = match l.state.keys().next().map(|s| &s[..]) {
+        Some("pending") => LogState::Pending,
+        Some("qualified") => LogState::Qualified,
+        Some("usable") => LogState::Usable,
+        Some("readonly") => LogState::Readonly,
+        Some("retired") => LogState::Retired,
+        Some("rejected") => LogState::Rejected,
+        // Probably a state added to the format since, which should not stop the other logs from
+        // being loaded.
+        other => {
+          log::warn!("Skipping log {:?} in the log list: unknown or missing state {:?}.", &l.description, other);
+          continue;
+        }
+      };
+      map_id_to_log.insert(Hash(id), Log {
+        base_url: l.url,
+        description: l.description,
+        pub_key,
+        state,
+        operator
+      });
+    }
+    Ok(LogList { map_id_to_log })
+  }
+}
+
+fn fetch(http: &HttpConfig, url: &str) -> Result<Vec<u8>, LogListError> {
+  let client = http.build_client().map_err(LogListError::Http)?;
+  let res = client.get(url).send().and_then(|r| r.error_for_status()).map_err(LogListError::Http)?;
+  Ok(res.bytes().map_err(LogListError::Http)?.to_vec())
+}
+
+#[test]
+fn test_parse_log_list() {
+  let json = br#"{"version": "1.0", "operators": [{"name": "Test", "email": [], "logs": [
+    {"description": "Test 'Log1'", "log_id": "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=",
+     "key": "AQID", "url": "https://ct.example.com/log1/", "mmd": 86400,
+     "state": {"usable": {"timestamp": "2020-01-01T00:00:00Z"}}},
+    {"description": "Test 'Log2'", "log_id": "HwAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=",
+     "key": "BAUG", "url": "https://ct.example.com/log2/", "mmd": 86400,
+     "state": {"retired": {"timestamp": "2020-01-01T00:00:00Z"}}},
+    {"description": "Test 'Log3'", "log_id": "AQAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=",
+     "key": "BwgJ", "url": "https://ct.example.com/log3/", "mmd": 86400,
+     "state": {"suspended": {"timestamp": "2020-01-01T00:00:00Z"}}}
+  ]}]}"#;
+  let ll = LogList::parse(json).unwrap();
+  assert_eq!(ll.map_id_to_log.len(), 2);
+  let mut id = [0u8; 32];
+  for (i, b) in id.iter_mut().enumerate() {
+    *b = i as u8;
+  }
+  let l1 = &ll.map_id_to_log[&Hash(id)];
+  assert_eq!(l1.base_url, "https://ct.example.com/log1/");
+  assert_eq!(l1.pub_key, vec![1, 2, 3]);
+  assert_eq!(l1.operator, "Test");
+  assert_eq!(l1.state, LogState::Usable);
+}
@@ -0,0 +1,291 @@
+//! RFC 6962 Merkle tree hashing.
+
+use std::convert::TryInto;
+
+use ctclient::internal::re_exports::openssl::sha::{sha256, Sha256};
+
+pub type HashBytes = [u8; 32];
+
+pub fn leaf_hash(leaf_input: &[u8]) -> HashBytes {
+  let mut h = Sha256::new();
+  h.update(&[0u8]);
+  h.update(leaf_input);
+  h.finish()
+}
+
+pub fn node_hash(left: &HashBytes, right: &HashBytes) -> HashBytes {
+  let mut h = Sha256::new();
+  h.update(&[1u8]);
+  h.update(left);
+  h.update(right);
+  h.finish()
+}
+
+pub fn empty_root() -> HashBytes {
+  sha256(&[])
+}
+
+/// The roots of the perfect subtrees making up a tree of `size` leaves, from left (largest) to
+/// right (smallest). This is all that is needed to compute the root hash of the tree, and to extend
+/// it with more leaves.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompactRange {
+  size: u64,
+  hashes: Vec<HashBytes>,
+}
+
+impl CompactRange {
+  pub fn new() -> CompactRange {
+    CompactRange { size: 0, hashes: Vec::new() }
+  }
+
+  pub fn from_parts(size: u64, hashes: &[Vec<u8>]) -> Result<CompactRange, &'static str> {
+    if hashes.len() != size.count_ones() as usize {
+      return Err("Number of subtree hashes does not match tree size.");
+    }
+    let hashes = hashes.iter()
+        .map(|h| h[..].try_into().map_err(|_| "Subtree hash is not 32 bytes."))
+        .collect::<Result<Vec<HashBytes>, _>>()?;
+    Ok(CompactRange { size, hashes })
+  }
+
+  pub fn size(&self) -> u64 {
+    self.size
+  }
+
+  pub fn hashes(&self) -> &[HashBytes] {
+    &self.hashes
+  }
+
+  pub fn append(&mut self, leaf_hash: &HashBytes) {
+    let mut h = *leaf_hash;
+    let mut s = self.size;
+    while s & 1 == 1 {
+      let left = self.hashes.pop().unwrap();
+      h = node_hash(&left, &h);
+      s >>= 1;
+    }
+    self.hashes.push(h);
+    self.size += 1;
+  }
+
+  pub fn extend<'a, I: IntoIterator<Item = &'a HashBytes>>(&mut self, leaf_hashes: I) {
+    for h in leaf_hashes {
+      self.append(h);
+    }
+  }
+
+  pub fn root(&self) -> HashBytes {
+    fold_subtrees(&self.hashes)
+  }
+}
+
+/// Root of a tree made of these perfect subtrees, largest first.
+pub fn fold_subtrees(hashes: &[HashBytes]) -> HashBytes {
+  let mut iter = hashes.iter().rev();
+  match iter.next() {
+    None => empty_root(),
+    Some(last) => iter.fold(*last, |acc, h| node_hash(h, &acc))
+  }
+}
+
+/// The perfect subtrees `(height, index)`, covering leaves `[index << height, (index + 1) << height)`,
+/// that leaves `[start, end)` split into, largest first. For the ranges of proofs, whose `start` is
+/// a multiple of a power of 2 at least `end - start`, `fold_subtrees` of their roots is the MTH of
+/// the range.
+pub fn perfect_subtrees(start: u64, end: u64) -> Vec<(u32, u64)> {
+  let mut out = Vec::new();
+  let mut next = start;
+  while next < end {
+    let height = std::cmp::min(63 - (end - next).leading_zeros(), next.trailing_zeros());
+    out.push((height, next >> height));
+    next += 1 << height;
+  }
+  out
+}
+
+impl Default for CompactRange {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+/// Largest power of 2 smaller than `n`, for `n` > 1.
+fn split_point(n: u64) -> u64 {
+  debug_assert!(n > 1);
+  1 << (63 - (n - 1).leading_zeros())
+}
+
+/// The leaf ranges `[start, end)` whose subtree hashes make up the audit path for leaf `index` in
+/// a tree of `size` leaves (RFC 6962 section 2.1.1), in proof order.
+pub fn inclusion_proof_ranges(index: u64, size: u64) -> Vec<(u64, u64)> {
+  assert!(index < size);
+  fn path(m: u64, start: u64, end: u64, out: &mut Vec<(u64, u64)>) {
+    let n = end - start;
+    if n == 1 {
+      return;
+    }
+    let k = split_point(n);
+    if m < k {
+      path(m, start, start + k, out);
+      out.push((start + k, end));
+    } else {
+      path(m - k, start + k, end, out);
+      out.push((start, start + k));
+    }
+  }
+  let mut out = Vec::new();
+  path(index, 0, size, &mut out);
+  out
+}
+
+/// The leaf ranges whose subtree hashes make up the consistency proof between tree sizes `first`
+/// and `second` (RFC 6962 section 2.1.2), in proof order.
+pub fn consistency_proof_ranges(first: u64, second: u64) -> Vec<(u64, u64)> {
+  assert!(first <= second);
+  fn subproof(m: u64, start: u64, end: u64, complete: bool, out: &mut Vec<(u64, u64)>) {
+    let n = end - start;
+    if m == n {
+      if !complete {
+        out.push((start, end));
+      }
+      return;
+    }
+    let k = split_point(n);
+    if m <= k {
+      subproof(m, start, start + k, complete, out);
+      out.push((start + k, end));
+    } else {
+      subproof(m - k, start + k, end, false, out);
+      out.push((start, start + k));
+    }
+  }
+  let mut out = Vec::new();
+  if first > 0 && first < second {
+    subproof(first, 0, second, true, &mut out);
+  }
+  out
+}
+
+#[cfg(test)]
+fn naive_root(leaves: &[HashBytes]) -> HashBytes {
+  match leaves.len() {
+    0 => empty_root(),
+    1 => leaves[0],
+    n => {
+      let k = (n as u64).next_power_of_two() as usize / 2;
+      node_hash(&naive_root(&leaves[..k]), &naive_root(&leaves[k..]))
+    }
+  }
+}
+
+#[test]
+fn test_compact_range() {
+  assert_eq!(ctclient::utils::u8_to_hex(&CompactRange::new().root()), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+  assert_eq!(ctclient::utils::u8_to_hex(&leaf_hash(&[])), "6e340b9cffb37a989ca544e6bb780a2c78901d3fb33738768511a30617afa01d");
+  let leaves: Vec<HashBytes> = (0..70u8).map(|i| leaf_hash(&[i])).collect();
+  let mut cr = CompactRange::new();
+  for (i, l) in leaves.iter().enumerate() {
+    assert_eq!(cr.root(), naive_root(&leaves[..i]));
+    cr.append(l);
+    assert_eq!(cr.hashes().len(), cr.size().count_ones() as usize);
+  }
+  let parts: Vec<Vec<u8>> = cr.hashes().iter().map(|h| h.to_vec()).collect();
+  let mut restored = CompactRange::from_parts(cr.size(), &parts).unwrap();
+  assert_eq!(restored, cr);
+  restored.extend(&leaves[..3]);
+  let mut all = leaves.clone();
+  all.extend_from_slice(&leaves[..3]);
+  assert_eq!(restored.root(), naive_root(&all));
+  assert!(CompactRange::from_parts(3, &parts).is_err());
+}
+
+#[cfg(test)]
+fn root_from_inclusion_proof(index: u64, size: u64, leaf: &HashBytes, proof: &[HashBytes]) -> Option<HashBytes> {
+  // RFC 9162 section 2.1.3.2
+  let (mut fnn, mut sn, mut r) = (index, size - 1, *leaf);
+  for p in proof {
+    if sn == 0 {
+      return None;
+    }
+    if fnn & 1 == 1 || fnn == sn {
+      r = node_hash(p, &r);
+      while fnn & 1 == 0 && fnn != 0 {
+        fnn >>= 1;
+        sn >>= 1;
+      }
+    } else {
+      r = node_hash(&r, p);
+    }
+    fnn >>= 1;
+    sn >>= 1;
+  }
+  if sn == 0 { Some(r) } else { None }
+}
+
+#[cfg(test)]
+fn verify_consistency_proof(first: u64, second: u64, first_root: &HashBytes, second_root: &HashBytes, proof: &[HashBytes]) -> bool {
+  // RFC 9162 section 2.1.4.2
+  let mut proof = proof.to_vec();
+  if first.is_power_of_two() {
+    proof.insert(0, *first_root);
+  }
+  let (mut fnn, mut sn) = (first - 1, second - 1);
+  while fnn & 1 == 1 {
+    fnn >>= 1;
+    sn >>= 1;
+  }
+  let (mut fr, mut sr) = (proof[0], proof[0]);
+  for c in &proof[1..] {
+    if sn == 0 {
+      return false;
+    }
+    if fnn & 1 == 1 || fnn == sn {
+      fr = node_hash(c, &fr);
+      sr = node_hash(c, &sr);
+      while fnn & 1 == 0 && fnn != 0 {
+        fnn >>= 1;
+        sn >>= 1;
+      }
+    } else {
+      sr = node_hash(&sr, c);
+    }
+    fnn >>= 1;
+    sn >>= 1;
+  }
+  fr == *first_root && sr == *second_root && sn == 0
+}
+
+#[test]
+fn test_proofs() {
+  let leaves: Vec<HashBytes> = (0..40u8).map(|i| leaf_hash(&[i])).collect();
+  let hashes = |ranges: Vec<(u64, u64)>| -> Vec<HashBytes> {
+    ranges.into_iter().map(|(a, b)| naive_root(&leaves[a as usize..b as usize])).collect()
+  };
+  for size in 1..=leaves.len() as u64 {
+    let root = naive_root(&leaves[..size as usize]);
+    for index in 0..size {
+      let proof = hashes(inclusion_proof_ranges(index, size));
+      assert_eq!(root_from_inclusion_proof(index, size, &leaves[index as usize], &proof), Some(root));
+    }
+    for first in 1..size {
+      let proof = hashes(consistency_proof_ranges(first, size));
+      assert!(verify_consistency_proof(first, size, &naive_root(&leaves[..first as usize]), &root, &proof));
+    }
+  }
+  // The ranges of proofs from the roots of their perfect subtrees.
+  for size in 1..=leaves.len() as u64 {
+    for (start, end) in inclusion_proof_ranges(size / 2, size).into_iter().chain(consistency_proof_ranges(size / 3, size)) {
+      let roots: Vec<HashBytes> = perfect_subtrees(start, end).into_iter()
+          .map(|(h, i)| naive_root(&leaves[(i << h) as usize..((i + 1) << h) as usize]))
+          .collect();
+      assert_eq!(fold_subtrees(&roots), naive_root(&leaves[start as usize..end as usize]));
+    }
+  }
+  assert_eq!(perfect_subtrees(4, 7), vec![(1, 2), (0, 6)]);
+  // RFC 6962 section 2.1.3 examples
+  assert_eq!(inclusion_proof_ranges(3, 7), vec![(2, 3), (0, 2), (4, 7)]);
+  assert_eq!(consistency_proof_ranges(3, 7), vec![(2, 3), (3, 4), (0, 2), (4, 7)]);
+  assert_eq!(consistency_proof_ranges(4, 7), vec![(4, 7)]);
+  assert_eq!(consistency_proof_ranges(6, 7), vec![(4, 6), (6, 7), (0, 4)]);
+}
@@ -0,0 +1,210 @@
+//! Serving a log's contents from our own database, which requires raw leaves stored for every
+//! entry from 0 up to the served tree size. Only leaves covered by the log's merkle frontier,
+//! which are stored in the same transaction as the frontier they were verified against, are
+//! used. Proofs are built from the subtree roots kept in
+//! `merkle_nodes`, so that they read a few hundred hashes at most instead of the whole log.
+
+use std::convert::TryInto;
+
+use diesel::dsl::max;
+use diesel::prelude::*;
+
+use crate::core::db::DBConn;
+use crate::core::merkle::{self, CompactRange, HashBytes};
+use crate::models::{Hash, Sth};
+
+#[derive(Debug, Error)]
+pub enum MirrorError {
+  #[error("DB: {0}")]
+  DB(#[from] diesel::result::Error),
+  #[error("Leaf #{0} is missing from the mirror.")]
+  MissingLeaf(u64),
+}
+
+/// Number of leaves covered by the log's merkle frontier, i.e. verified against some sth.
+fn verified_size(db: &DBConn, log_id: &Hash) -> QueryResult<u64> {
+  use crate::schema::merkle_frontiers::dsl as mf;
+  let size: Option<i64> = mf::merkle_frontiers
+      .select(mf::tree_size)
+      .filter(mf::log_id.eq(log_id))
+      .first(db).optional()?;
+  Ok(size.unwrap_or(0) as u64)
+}
+
+/// The sth we can serve for this log, i.e. its `latest_sth`, if we hold every leaf it covers and
+/// the frontier has reached it.
+pub fn mirrored_sth(db: &DBConn, log_id: &Hash) -> Result<Option<Sth>, MirrorError> {
+  let latest: Option<i64> = {
+    use crate::schema::ctlogs::dsl as c;
+    c::ctlogs.select(c::latest_sth).filter(c::log_id.eq(log_id)).first(db).optional()?.flatten()
+  };
+  let latest: Sth = match latest {
+    Some(sth_id) => {
+      use crate::schema::sth::dsl::*;
+      sth.filter(id.eq(sth_id)).first(db)?
+    },
+    None => return Ok(None)
+  };
+  if has_leaves(db, log_id, latest.tree_size as u64)? {
+    Ok(Some(latest))
+  } else {
+    Ok(None)
+  }
+}
+
+/// Levels of the nodes kept in `merkle_nodes` are multiples of this.
+const NODE_HEIGHT: u32 = 8;
+const NODE_WIDTH: u64 = 1 << NODE_HEIGHT;
+/// Nodes added to each level by one call of `store_nodes`, so that catching up on a large log is
+/// spread over several rounds of its update thread.
+const MAX_NEW_NODES: u64 = 1024;
+
+/// Hashes of nodes `[start, end)` at `level` (leaf hashes for level 0), up to the first missing
+/// one.
+fn load_level(db: &DBConn, log_id: &Hash, level: u32, start: u64, end: u64) -> QueryResult<Vec<HashBytes>> {
+  let rows: Vec<(i64, Hash)> = if level == 0 {
+    use crate::schema::raw_leaves::dsl as r;
+    r::raw_leaves
+        .select((r::leaf_index, r::leaf_hash))
+        .filter(r::log_id.eq(log_id).and(r::leaf_index.ge(start as i64)).and(r::leaf_index.lt(end as i64)))
+        .order_by(r::leaf_index.asc())
+        .load(db)?
+  } else {
+    use crate::schema::merkle_nodes::dsl as n;
+    n::merkle_nodes
+        .select((n::node_index, n::hash))
+        .filter(n::log_id.eq(log_id).and(n::level.eq(level as i16))
+            .and(n::node_index.ge(start as i64)).and(n::node_index.lt(end as i64)))
+        .order_by(n::node_index.asc())
+        .load(db)?
+  };
+  Ok(rows.into_iter().zip(start..).take_while(|((i, _), expected)| *i as u64 == *expected).map(|((_, h), _)| h.0).collect())
+}
+
+/// Number of nodes stored at `level`, which are nodes `[0, n)`.
+fn stored_nodes(db: &DBConn, log_id: &Hash, level: u32) -> QueryResult<u64> {
+  use crate::schema::merkle_nodes::dsl as n;
+  let last: Option<i64> = n::merkle_nodes
+      .select(max(n::node_index))
+      .filter(n::log_id.eq(log_id).and(n::level.eq(level as i16)))
+      .first(db)?;
+  Ok(last.map(|i| i as u64 + 1).unwrap_or(0))
+}
+
+fn perfect_root(hashes: &[HashBytes]) -> HashBytes {
+  let mut r = CompactRange::new();
+  r.extend(hashes);
+  r.root()
+}
+
+/// Store the nodes above the raw leaves stored since the last call, as far as leaves are stored
+/// from 0 without gaps and covered by the frontier. Called by the update thread of each log whose
+/// raw leaves are kept.
+pub fn store_nodes(db: &DBConn, log_id: &Hash) -> Result<(), MirrorError> {
+  use crate::schema::merkle_nodes::dsl as n;
+  use diesel::upsert::excluded;
+  let verified = verified_size(db, log_id)?;
+  let mut level = NODE_HEIGHT;
+  loop {
+    let have = stored_nodes(db, log_id, level)?;
+    let end = std::cmp::min((have + MAX_NEW_NODES) * NODE_WIDTH, verified >> (level - NODE_HEIGHT));
+    let below = if end > have * NODE_WIDTH {
+      load_level(db, log_id, level - NODE_HEIGHT, have * NODE_WIDTH, end)?
+    } else {
+      Vec::new()
+    };
+    let rows: Vec<_> = below.chunks_exact(NODE_WIDTH as usize).zip(have..).map(|(children, i)| (
+      n::log_id.eq(*log_id),
+      n::level.eq(level as i16),
+      n::node_index.eq(i as i64),
+      n::hash.eq(Hash(perfect_root(children))),
+    )).collect();
+    if !rows.is_empty() {
+      diesel::insert_into(n::merkle_nodes).values(&rows)
+          .on_conflict((n::log_id, n::level, n::node_index))
+          .do_update()
+          .set(n::hash.eq(excluded(n::hash)))
+          .execute(db)?;
+    }
+    if have + (rows.len() as u64) < NODE_WIDTH {
+      return Ok(());
+    }
+    level += NODE_HEIGHT;
+  }
+}
+
+/// Whether raw leaves `[0, tree_size)` are all stored and verified, going by the nodes above them
+/// and the leaves after the last one.
+pub fn has_leaves(db: &DBConn, log_id: &Hash, tree_size: u64) -> Result<bool, MirrorError> {
+  if tree_size > verified_size(db, log_id)? {
+    return Ok(false);
+  }
+  let full = tree_size / NODE_WIDTH;
+  if stored_nodes(db, log_id, NODE_HEIGHT)? < full {
+    return Ok(false);
+  }
+  let tail = load_level(db, log_id, 0, full * NODE_WIDTH, tree_size)?;
+  Ok(tail.len() as u64 == tree_size - full * NODE_WIDTH)
+}
+
+/// Root of the perfect subtree of height `level` covering leaves
+/// `[index << level, (index + 1) << level)`, from the stored nodes at most 8 levels below it.
+fn perfect_hash(db: &DBConn, log_id: &Hash, level: u32, index: u64) -> Result<HashBytes, MirrorError> {
+  if level % NODE_HEIGHT == 0 {
+    if let Some(h) = load_level(db, log_id, level, index, index + 1)?.pop() {
+      return Ok(h);
+    }
+    if level == 0 {
+      return Err(MirrorError::MissingLeaf(index));
+    }
+  }
+  let base = (level - 1) / NODE_HEIGHT * NODE_HEIGHT;
+  let n = 1u64 << (level - base);
+  let mut hashes = load_level(db, log_id, base, index * n, (index + 1) * n)?;
+  // Nodes `store_nodes` has not got to yet.
+  while (hashes.len() as u64) < n {
+    hashes.push(perfect_hash(db, log_id, base, index * n + hashes.len() as u64)?);
+  }
+  Ok(perfect_root(&hashes))
+}
+
+/// MTH of leaves `[start, end)`, a range from `merkle::inclusion_proof_ranges` or
+/// `merkle::consistency_proof_ranges`.
+pub fn subtree_hash(db: &DBConn, log_id: &Hash, start: u64, end: u64) -> Result<HashBytes, MirrorError> {
+  let hashes = merkle::perfect_subtrees(start, end).into_iter()
+      .map(|(level, index)| perfect_hash(db, log_id, level, index))
+      .collect::<Result<Vec<_>, _>>()?;
+  Ok(merkle::fold_subtrees(&hashes))
+}
+
+pub fn proof_hashes(db: &DBConn, log_id: &Hash, ranges: Vec<(u64, u64)>) -> Result<Vec<HashBytes>, MirrorError> {
+  ranges.into_iter().map(|(a, b)| subtree_hash(db, log_id, a, b)).collect()
+}
+
+pub fn consistency_proof(db: &DBConn, log_id: &Hash, first: u64, second: u64) -> Result<Vec<HashBytes>, MirrorError> {
+  proof_hashes(db, log_id, merkle::consistency_proof_ranges(first, second))
+}
+
+/// Index of the (first) leaf with this hash within the first `tree_size` leaves, and its audit
+/// path.
+pub fn proof_by_hash(db: &DBConn, log_id: &Hash, leaf_hash: &[u8], tree_size: u64) -> Result<Option<(u64, Vec<HashBytes>)>, MirrorError> {
+  let leaf_hash: HashBytes = match leaf_hash.try_into() {
+    Ok(h) => h,
+    Err(_) => return Ok(None)
+  };
+  use crate::schema::raw_leaves::dsl as r;
+  let index: Option<i64> = r::raw_leaves
+      .select(r::leaf_index)
+      .filter(r::log_id.eq(log_id)
+          .and(r::leaf_hash.eq(Hash(leaf_hash)))
+          .and(r::leaf_index.lt(tree_size as i64)))
+      .order_by(r::leaf_index.asc())
+      .first(db).optional()?;
+  match index {
+    Some(index) => {
+      let index = index as u64;
+      Ok(Some((index, proof_hashes(db, log_id, merkle::inclusion_proof_ranges(index, tree_size))?)))
+    },
+    None => Ok(None)
+  }
+}
@@ -1,4 +1,19 @@
+pub mod api_keys;
+pub mod certinfo;
 pub mod db;
+pub mod events;
+pub mod config;
 pub mod context;
+pub mod log_list;
+pub mod merkle;
+pub mod partitions;
+pub mod prune;
+pub mod mirror;
+pub mod raw_entries;
+pub mod reindex;
+pub mod search;
+pub mod stream_server;
+pub mod tiles;
 pub mod update_thread;
 pub mod initialise_ctlogs_table;
+pub mod verify;
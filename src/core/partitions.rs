@@ -0,0 +1,96 @@
+//! Maintenance of the partitions of `certificates` and `certificate_names` (by month of ingestion)
+//! and `certificate_appears_in_leaf` (by log). The DDL itself is in the functions created by the
+//! partitioning migration.
+
+use std::time::Duration;
+
+use diesel::prelude::*;
+use diesel::sql_types::{BigInt, Bool, Bytea, Integer, Text};
+use serde::Serialize;
+
+use crate::core::db::{DBConn, DBPool};
+use crate::models::Hash;
+
+/// Monthly partitions are created this far ahead.
+pub const MONTHS_AHEAD: i32 = 3;
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(6 * 3600);
+
+#[derive(QueryableByName, Debug, Serialize)]
+pub struct Partition {
+  #[sql_type = "Text"]
+  pub parent: String,
+  #[sql_type = "Text"]
+  pub name: String,
+  /// e.g. `FOR VALUES IN ('\x...')`, or `DEFAULT`.
+  #[sql_type = "Text"]
+  pub bound: String,
+  /// From the statistics, so only as recent as the last analyze.
+  #[sql_type = "BigInt"]
+  pub estimated_rows: i64,
+}
+
+/// Create the monthly partitions up to `months_ahead` months from now. Returns how many were
+/// created.
+pub fn create_month_partitions(db: &DBConn, months_ahead: i32) -> QueryResult<i32> {
+  #[derive(QueryableByName)]
+  struct Created {
+    #[sql_type = "Integer"]
+    created: i32,
+  }
+  let r: Created = diesel::sql_query("SELECT ctcrab_create_month_partitions(now() + make_interval(months => $1)) AS created")
+      .bind::<Integer, _>(months_ahead)
+      .get_result(db)?;
+  Ok(r.created)
+}
+
+/// Stop monitoring the log, and detach the partition holding its appearances (and drop it if
+/// `drop` is set). Certificates are left alone, since they may also appear in other logs. Returns
+/// the name of the partition.
+///
+/// Update threads should be stopped first, or appearances they add afterwards will go to the
+/// default partition.
+pub fn retire_log(db: &DBConn, id: &Hash, drop: bool) -> QueryResult<String> {
+  #[derive(QueryableByName)]
+  struct Retired {
+    #[sql_type = "Text"]
+    name: String,
+  }
+  db.transaction(|| {
+    use crate::schema::ctlogs::dsl::*;
+    diesel::update(ctlogs.filter(log_id.eq(id)))
+        .set(monitoring.eq(false))
+        .execute(db)?;
+    let r: Retired = diesel::sql_query("SELECT ctcrab_retire_log_partition($1, $2) AS name")
+        .bind::<Bytea, _>(*id)
+        .bind::<Bool, _>(drop)
+        .get_result(db)?;
+    Ok(r.name)
+  })
+}
+
+pub fn list(db: &DBConn) -> QueryResult<Vec<Partition>> {
+  diesel::sql_query("SELECT p.relname::text AS parent, c.relname::text AS name, \
+                       pg_get_expr(c.relpartbound, c.oid) AS bound, greatest(c.reltuples, 0)::bigint AS estimated_rows \
+                     FROM pg_inherits i \
+                       JOIN pg_class c ON c.oid = i.inhrelid \
+                       JOIN pg_class p ON p.oid = i.inhparent \
+                     WHERE p.relname IN ('certificates', 'certificate_names', 'certificate_appears_in_leaf') \
+                     ORDER BY p.relname, c.relname")
+      .load(db)
+}
+
+/// Keep monthly partitions created ahead of time, for as long as the process runs.
+pub fn start_maintenance_thread(db_pool: DBPool) -> std::io::Result<()> {
+  std::thread::Builder::new().name("partitions".to_owned()).spawn(move || loop {
+    match db_pool.get() {
+      Ok(db) => match create_month_partitions(&db, MONTHS_AHEAD) {
+        Ok(0) => {},
+        Ok(n) => log::info!("Created {} monthly partitions", n),
+        Err(e) => log::warn!("Failed to create monthly partitions: {}", e)
+      },
+      Err(e) => log::warn!("Failed to create monthly partitions: {}", e)
+    }
+    std::thread::sleep(MAINTENANCE_INTERVAL);
+  })?;
+  Ok(())
+}
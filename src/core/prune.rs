@@ -0,0 +1,218 @@
+//! Deletion of old data according to `[retention]`. Every run, including dry runs, is recorded in
+//! `prune_runs`.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use diesel::expression::functions::date_and_time::now;
+use diesel::prelude::*;
+use diesel::sql_types::{Array, BigInt, Bytea, Integer, Text};
+
+use crate::core::config::RetentionConfig;
+use crate::core::db::{DBConn, DBPool};
+use crate::core::search::SearchIndex;
+use crate::models::Hash;
+use crate::models::inserts::Issuer;
+
+/// Rows deleted per transaction.
+const BATCH_SIZE: i64 = 1000;
+
+/// Old sth which are not the first of their hour (per log), not `latest_sth` now or in the past,
+/// not referenced by an error, and already checked. `$1`: `sth_days`.
+const STH_CANDIDATES: &str = "\
+  SELECT s.id FROM sth s \
+  WHERE s.received_time < now() - make_interval(days => $1) \
+    AND s.checked_consistent_with_latest AND NOT s.was_latest \
+    AND NOT EXISTS (SELECT 1 FROM ctlogs l WHERE l.latest_sth = s.id) \
+    AND NOT EXISTS (SELECT 1 FROM consistency_check_errors e WHERE s.id IN (e.from_sth_id, e.to_sth_id)) \
+    AND NOT EXISTS (SELECT 1 FROM retired_log_changed_error r WHERE r.latest_sth = s.id) \
+    AND s.id <> (SELECT h.id FROM sth h \
+                 WHERE h.log_id = s.log_id \
+                   AND h.received_time >= date_trunc('hour', s.received_time) \
+                   AND h.received_time < date_trunc('hour', s.received_time) + interval '1 hour' \
+                 ORDER BY h.received_time, h.id LIMIT 1)";
+
+/// End-entity certificates that expired more than `$1` days ago, without a dns name equal to one
+/// of `$2` or whose reverse is like one of `$3`. Certificates that never appeared as a leaf may be
+/// an issuer in some chain, so they are kept.
+const CERTIFICATE_CANDIDATES: &str = "\
+  SELECT c.fingerprint FROM certificates c \
+  WHERE c.not_after < now() - make_interval(days => $1) \
+    AND EXISTS (SELECT 1 FROM certificate_appears_in_leaf a WHERE a.cert_fp = c.fingerprint) \
+    AND NOT EXISTS (SELECT 1 FROM certificate_names n \
+                    WHERE n.cert_fp = c.fingerprint AND n.name_type = 'dns' \
+                      AND (n.name = ANY($2) OR reverse(n.name) LIKE ANY($3)))";
+
+#[derive(Debug, Default)]
+pub struct Stats {
+  pub run_id: i64,
+  pub sth_deleted: i64,
+  pub certificates_deleted: i64,
+}
+
+#[derive(QueryableByName)]
+struct Count {
+  #[sql_type = "BigInt"]
+  count: i64,
+}
+
+#[derive(QueryableByName)]
+struct SthId {
+  #[sql_type = "BigInt"]
+  id: i64,
+}
+
+#[derive(QueryableByName)]
+struct Fingerprint {
+  #[sql_type = "Bytea"]
+  fingerprint: Hash,
+}
+
+/// Exact names, and LIKE patterns on reversed names, from `*.suffix` entries.
+fn watchlist_patterns(watchlist: &[String]) -> (Vec<String>, Vec<String>) {
+  let mut exact = Vec::new();
+  let mut suffixes = Vec::new();
+  for w in watchlist {
+    let w = w.to_lowercase();
+    match w.strip_prefix("*") {
+      Some(suffix) => {
+        let reversed = suffix.chars().rev().collect::<String>();
+        suffixes.push(format!("{}%", reversed.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_")));
+      },
+      None => exact.push(w)
+    }
+  }
+  (exact, suffixes)
+}
+
+fn delete_sth(db: &DBConn, ids: &[i64]) -> QueryResult<usize> {
+  use crate::schema::sth::dsl::*;
+  diesel::delete(sth.filter(id.eq_any(ids))).execute(db)
+}
+
+fn delete_certificates(db: &DBConn, fps: &[Hash]) -> QueryResult<usize> {
+  {
+    use crate::schema::certificate_names::dsl::*;
+    diesel::delete(certificate_names.filter(cert_fp.eq_any(fps))).execute(db)?;
+  }
+  {
+    use crate::schema::certificate_appears_in_leaf::dsl::*;
+    diesel::delete(certificate_appears_in_leaf.filter(cert_fp.eq_any(fps))).execute(db)?;
+  }
+  {
+    use crate::schema::certificates::dsl::*;
+    let issuers: Vec<Option<i64>> = diesel::delete(certificates.filter(fingerprint.eq_any(fps).and(chain_only.eq(false))))
+        .returning(issuer_id)
+        .get_results(db)?;
+    let mut cert_counts: BTreeMap<i64, i64> = BTreeMap::new();
+    for i in issuers.into_iter().flatten() {
+      *cert_counts.entry(i).or_default() -= 1;
+    }
+    Issuer::add_cert_counts(db, &cert_counts)?;
+    diesel::delete(certificates.filter(fingerprint.eq_any(fps))).execute(db)?;
+  }
+  use crate::schema::certificate_fingerprints::dsl::*;
+  diesel::delete(certificate_fingerprints.filter(fingerprint.eq_any(fps))).execute(db)
+}
+
+/// Delete what `config` says is no longer needed, a batch per transaction, or with `dry_run` only
+/// count it. Deleted certificates are also removed from `search`, which must have been opened with
+/// a writer.
+pub fn prune(db: &DBConn, config: &RetentionConfig, search: Option<&SearchIndex>, dry_run: bool) -> QueryResult<Stats> {
+  let mut stats = Stats::default();
+  {
+    use crate::schema::prune_runs::dsl as p;
+    stats.run_id = diesel::insert_into(p::prune_runs)
+        .values((
+          p::dry_run.eq(dry_run),
+          p::sth_days.eq(config.sth_days as i32),
+          p::expired_certificate_days.eq(config.expired_certificate_days.map(|d| d as i32)),
+          p::watchlist.eq(&config.watchlist),
+        ))
+        .returning(p::id)
+        .get_result(db)?;
+  }
+  let sth_days = config.sth_days as i32;
+  if dry_run {
+    stats.sth_deleted = diesel::sql_query(format!("SELECT count(*) AS count FROM ({}) t", STH_CANDIDATES))
+        .bind::<Integer, _>(sth_days)
+        .get_result::<Count>(db)?.count;
+  } else {
+    loop {
+      let n = db.transaction(|| {
+        let ids = diesel::sql_query(format!("{} LIMIT {}", STH_CANDIDATES, BATCH_SIZE))
+            .bind::<Integer, _>(sth_days)
+            .load::<SthId>(db)?.into_iter().map(|r| r.id).collect::<Vec<_>>();
+        delete_sth(db, &ids)
+      })?;
+      stats.sth_deleted += n as i64;
+      if n == 0 {
+        break;
+      }
+    }
+  }
+  if let Some(days) = config.expired_certificate_days {
+    let (exact, suffixes) = watchlist_patterns(&config.watchlist);
+    if dry_run {
+      stats.certificates_deleted = diesel::sql_query(format!("SELECT count(*) AS count FROM ({}) t", CERTIFICATE_CANDIDATES))
+          .bind::<Integer, _>(days as i32)
+          .bind::<Array<Text>, _>(&exact)
+          .bind::<Array<Text>, _>(&suffixes)
+          .get_result::<Count>(db)?.count;
+    } else {
+      loop {
+        let fps = db.transaction::<_, diesel::result::Error, _>(|| {
+          let fps = diesel::sql_query(format!("{} LIMIT {}", CERTIFICATE_CANDIDATES, BATCH_SIZE))
+              .bind::<Integer, _>(days as i32)
+              .bind::<Array<Text>, _>(&exact)
+              .bind::<Array<Text>, _>(&suffixes)
+              .load::<Fingerprint>(db)?.into_iter().map(|r| r.fingerprint).collect::<Vec<_>>();
+          delete_certificates(db, &fps)?;
+          Ok(fps)
+        })?;
+        if let Some(search) = search {
+          for fp in &fps {
+            search.delete(fp);
+          }
+        }
+        let n = fps.len();
+        stats.certificates_deleted += n as i64;
+        if n == 0 {
+          break;
+        }
+      }
+    }
+  }
+  use crate::schema::prune_runs::dsl as p;
+  diesel::update(p::prune_runs.filter(p::id.eq(stats.run_id)))
+      .set((
+        p::finished_at.eq(now),
+        p::sth_deleted.eq(stats.sth_deleted),
+        p::certificates_deleted.eq(stats.certificates_deleted),
+      ))
+      .execute(db)?;
+  Ok(stats)
+}
+
+/// Prune every `interval_hours`, for as long as the process runs.
+pub fn start_thread(db_pool: DBPool, config: RetentionConfig, search: Option<Arc<SearchIndex>>) -> std::io::Result<()> {
+  std::thread::Builder::new().name("prune".to_owned()).spawn(move || loop {
+    std::thread::sleep(Duration::from_secs(config.interval_hours * 3600));
+    match db_pool.get() {
+      Ok(db) => match prune(&db, &config, search.as_deref(), false) {
+        Ok(s) => log::info!("Prune run {}: deleted {} sth and {} certificates", s.run_id, s.sth_deleted, s.certificates_deleted),
+        Err(e) => log::warn!("Prune failed: {}", e)
+      },
+      Err(e) => log::warn!("Prune failed: {}", e)
+    }
+  })?;
+  Ok(())
+}
+
+#[test]
+fn test_watchlist_patterns() {
+  let (exact, suffixes) = watchlist_patterns(&["Example.com".to_owned(), "*.my_domain.org".to_owned()]);
+  assert_eq!(exact, vec!["example.com"]);
+  assert_eq!(suffixes, vec!["gro.niamod\\_ym.%"]);
+}
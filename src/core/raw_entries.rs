@@ -0,0 +1,150 @@
+//! Fetching of log entries while keeping the raw `leaf_input` and `extra_data`, which
+//! `ctclient::internal::get_entries` discards after parsing.
+
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::ops::Range;
+
+use ctclient::internal::Leaf;
+use ctclient::internal::re_exports::reqwest::{self, Url};
+use flate2::Compression;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// Most logs cap get-entries at 256 to 1024 entries, so asking for more is pointless.
+const MAX_BATCH: u64 = 1024;
+
+#[derive(Debug, Error)]
+pub enum RawEntriesError {
+  #[error("get-entries: {0}")]
+  Http(#[source] reqwest::Error),
+  #[error("get-entries: unable to parse response: {0}")]
+  Parse(#[source] serde_json::Error),
+  #[error("get-entries: invalid base64 in entry #{0}.")]
+  InvalidBase64(u64),
+  #[error("get-entries: log returned no entries for #{0}.")]
+  NoEntries(u64),
+}
+
+pub struct RawEntry {
+  pub leaf_input: Vec<u8>,
+  pub extra_data: Vec<u8>,
+}
+
+impl RawEntry {
+  /// Whether the MerkleTreeLeaf holds a precert_entry rather than an x509_entry.
+  pub fn is_precert(&self) -> bool {
+    self.leaf_input.get(10..12) == Some(&[0, 1][..])
+  }
+
+  pub fn parse(&self) -> Result<Leaf, ctclient::Error> {
+    Leaf::from_raw(&self.leaf_input, &self.extra_data)
+  }
+}
+
+/// The get-entries response format, also used by our own API.
+#[derive(Deserialize, Serialize, JsonSchema)]
+pub struct GetEntriesJson {
+  pub entries: Vec<LeafEntryJson>,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema)]
+pub struct LeafEntryJson {
+  pub leaf_input: String,
+  pub extra_data: String,
+}
+
+pub struct RawEntriesIter<'a> {
+  client: &'a reqwest::blocking::Client,
+  base_url: &'a Url,
+  next: u64,
+  end: u64,
+  buf: VecDeque<RawEntry>,
+  failed: bool,
+}
+
+/// Like `ctclient::internal::get_entries`, but yields the raw entries. Stops after the first error.
+pub fn get_raw_entries<'a>(client: &'a reqwest::blocking::Client, base_url: &'a Url, range: Range<u64>) -> RawEntriesIter<'a> {
+  RawEntriesIter {
+    client,
+    base_url,
+    next: range.start,
+    end: range.end,
+    buf: VecDeque::new(),
+    failed: false
+  }
+}
+
+impl<'a> RawEntriesIter<'a> {
+  fn fetch_batch(&self) -> Result<VecDeque<RawEntry>, RawEntriesError> {
+    let last = std::cmp::min(self.next + MAX_BATCH, self.end) - 1;
+    let mut url = self.base_url.join("ct/v1/get-entries").unwrap();
+    url.query_pairs_mut()
+        .append_pair("start", &self.next.to_string())
+        .append_pair("end", &last.to_string());
+    let res = self.client.get(url).send()
+        .and_then(|r| r.error_for_status())
+        .and_then(|r| r.bytes())
+        .map_err(RawEntriesError::Http)?;
+    let json: GetEntriesJson = serde_json::from_slice(&res).map_err(RawEntriesError::Parse)?;
+    let mut entries = VecDeque::with_capacity(json.entries.len());
+    for (i, e) in json.entries.into_iter().take((last + 1 - self.next) as usize).enumerate() {
+      let idx = self.next + i as u64;
+      entries.push_back(RawEntry {
+        leaf_input: base64::decode(&e.leaf_input).map_err(|_| RawEntriesError::InvalidBase64(idx))?,
+        extra_data: base64::decode(&e.extra_data).map_err(|_| RawEntriesError::InvalidBase64(idx))?,
+      });
+    }
+    if entries.is_empty() {
+      return Err(RawEntriesError::NoEntries(self.next));
+    }
+    Ok(entries)
+  }
+}
+
+impl<'a> Iterator for RawEntriesIter<'a> {
+  type Item = Result<RawEntry, RawEntriesError>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    if let Some(e) = self.buf.pop_front() {
+      return Some(Ok(e));
+    }
+    if self.failed || self.next >= self.end {
+      return None;
+    }
+    match self.fetch_batch() {
+      Ok(entries) => {
+        self.next += entries.len() as u64;
+        self.buf = entries;
+        self.buf.pop_front().map(Ok)
+      },
+      Err(e) => {
+        self.failed = true;
+        Some(Err(e))
+      }
+    }
+  }
+}
+
+/// Raw leaf data is stored zlib compressed.
+pub fn compress(data: &[u8]) -> Vec<u8> {
+  let mut enc = ZlibEncoder::new(Vec::with_capacity(data.len() / 2), Compression::default());
+  enc.write_all(data).unwrap();
+  enc.finish().unwrap()
+}
+
+pub fn decompress(data: &[u8]) -> std::io::Result<Vec<u8>> {
+  let mut out = Vec::with_capacity(data.len() * 2);
+  ZlibDecoder::new(data).read_to_end(&mut out)?;
+  Ok(out)
+}
+
+#[test]
+fn test_compress() {
+  let data = b"leaf input leaf input leaf input".repeat(10);
+  let c = compress(&data);
+  assert!(c.len() < data.len());
+  assert_eq!(decompress(&c).unwrap(), data);
+}
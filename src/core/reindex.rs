@@ -0,0 +1,127 @@
+//! Backfill of the fields extracted from certificates at insert time, for certificates inserted
+//! before the extraction was added.
+
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+
+use chrono::{DateTime, Utc};
+use ctclient::internal::re_exports::openssl::x509::X509;
+use diesel::prelude::*;
+
+use crate::core::certinfo::{cert_names, issuer_info};
+use crate::core::db::DBConn;
+use crate::core::search::SearchIndex;
+use crate::models::Hash;
+use crate::models::inserts::{insert_names, Issuer};
+
+const BATCH_SIZE: i64 = 1000;
+
+#[derive(Default)]
+pub struct Options<'a> {
+  /// Also go through every certificate and add any name missing from `certificate_names`.
+  pub names: bool,
+  /// Also (re-)add every certificate to this full-text index.
+  pub search: Option<&'a SearchIndex>,
+}
+
+#[derive(Debug, Default)]
+pub struct Stats {
+  pub certificates: u64,
+  pub issuers_set: u64,
+  pub names_added: u64,
+  pub unparsable: u64,
+}
+
+/// The certificate following this one in any chain it was logged with.
+fn issuer_fingerprint(db: &DBConn, fp: &Hash) -> Result<Option<Hash>, diesel::result::Error> {
+  use crate::schema::certificate_appears_in_leaf::dsl as a;
+  use crate::schema::certificate_chains::dsl as c;
+  let submitted: Option<Vec<Vec<u8>>> = a::certificate_appears_in_leaf
+      .inner_join(c::certificate_chains)
+      .select(c::fingerprints)
+      .filter(a::cert_fp.eq(fp))
+      .first(db).optional()?;
+  let next = match submitted {
+    Some(fps) => fps.into_iter().next(),
+    None => {
+      let containing: Option<Vec<Vec<u8>>> = c::certificate_chains
+          .select(c::fingerprints)
+          .filter(c::fingerprints.contains(vec![fp.0.to_vec()]))
+          .first(db).optional()?;
+      containing.and_then(|fps| fps.iter().position(|x| x[..] == fp.0[..]).and_then(|i| fps.get(i + 1).cloned()))
+    }
+  };
+  Ok(next.and_then(|n| n[..].try_into().ok()).map(Hash))
+}
+
+/// Set `issuer_id` on every certificate without one, and fill in names and the search index if
+/// asked to. Like at insert time, names are only indexed for certificates that appear as a leaf.
+/// Each batch is its own transaction, so this can be interrupted and run again.
+pub fn reindex(db: &DBConn, opts: &Options, progress: &mut dyn FnMut(&Stats)) -> Result<Stats, diesel::result::Error> {
+  let mut stats = Stats::default();
+  let mut after: Option<Hash> = None;
+  loop {
+    let batch: Vec<(Hash, Vec<u8>, Option<i64>, DateTime<Utc>, bool)> = {
+      use crate::schema::certificates::dsl::*;
+      let mut q = certificates.select((fingerprint, x509, issuer_id, ingested_at, chain_only)).into_boxed();
+      if !opts.names && opts.search.is_none() {
+        q = q.filter(issuer_id.is_null());
+      }
+      if let Some(after) = after {
+        q = q.filter(fingerprint.gt(after));
+      }
+      q.order_by(fingerprint.asc()).limit(BATCH_SIZE).load(db)?
+    };
+    if batch.is_empty() {
+      return Ok(stats);
+    }
+    after = Some(batch.last().unwrap().0);
+    db.transaction::<_, diesel::result::Error, _>(|| {
+      let mut cert_counts: BTreeMap<i64, i64> = BTreeMap::new();
+      for (fp, der, current_issuer, ingested, chain_only_cert) in &batch {
+        stats.certificates += 1;
+        let cert = match X509::from_der(der) {
+          Ok(c) => c,
+          Err(_) => {
+            stats.unparsable += 1;
+            continue;
+          }
+        };
+        if current_issuer.is_none() {
+          let issuer = match issuer_fingerprint(db, fp)? {
+            Some(ifp) => {
+              use crate::schema::certificates::dsl::*;
+              let d: Option<Vec<u8>> = certificates.select(x509).filter(fingerprint.eq(ifp)).first(db).optional()?;
+              d.and_then(|d| X509::from_der(&d).ok())
+            },
+            None => None
+          };
+          let id = Issuer::get_or_insert(db, &issuer_info(&cert, issuer.as_deref()))?;
+          use crate::schema::certificates::dsl::*;
+          diesel::update(certificates.filter(fingerprint.eq(fp)))
+              .set(issuer_id.eq(id))
+              .execute(db)?;
+          if !*chain_only_cert {
+            *cert_counts.entry(id).or_default() += 1;
+          }
+          stats.issuers_set += 1;
+        }
+        if (opts.names || opts.search.is_some()) && *chain_only_cert {
+          continue;
+        }
+        if opts.names {
+          match cert_names(&cert) {
+            Ok(names) => stats.names_added += insert_names(db, fp, *ingested, &names)? as u64,
+            Err(_) => stats.unparsable += 1
+          }
+        }
+        if let Some(search) = opts.search {
+          search.upsert(fp, &cert);
+        }
+      }
+      Issuer::add_cert_counts(db, &cert_counts)?;
+      Ok(())
+    })?;
+    progress(&stats);
+  }
+}
@@ -0,0 +1,256 @@
+//! Full-text index of certificates on local disk, for searches the database indexes can't answer,
+//! such as by organization or by a fragment of a domain name.
+//!
+//! Documents are added from the ingestion path and committed every `commit_interval_ms`, so
+//! results lag ingestion by that much. The database stays the source of truth: the index can be
+//! deleted and rebuilt with `ctcrab reindex --search`.
+//!
+//! Tantivy allows one writer per index, so only the ingesting process opens it with `open`; other
+//! processes, such as `api-only`, use `open_reader`, which picks up each commit of the writer.
+
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use ctclient::internal::re_exports::openssl::nid::Nid;
+use ctclient::internal::re_exports::openssl::x509::X509;
+use schemars::JsonSchema;
+use serde::Serialize;
+use tantivy::{Document, Index, IndexReader, IndexWriter, ReloadPolicy, Term};
+use tantivy::collector::TopDocs;
+use tantivy::directory::MmapDirectory;
+use tantivy::query::{BooleanQuery, Occur, Query, QueryParser, TermQuery};
+use tantivy::schema::{Field, IndexRecordOption, Schema, STORED, STRING, TEXT, TextFieldIndexing, TextOptions};
+use tantivy::tokenizer::{LowerCaser, NgramTokenizer, TextAnalyzer};
+
+use crate::core::certinfo::{cert_names, format_name};
+use crate::core::config::SearchConfig;
+use crate::models::Hash;
+
+const NGRAM_TOKENIZER: &str = "ngram3";
+/// Query syntax for substring matches on names, handled by us rather than the query parser.
+const CONTAINS_PREFIX: &str = "contains:";
+
+#[derive(Debug, Error)]
+pub enum SearchError {
+  #[error("Search index: {0}")]
+  Tantivy(#[from] tantivy::TantivyError),
+  #[error("Search index: {0}")]
+  OpenDirectory(#[from] tantivy::directory::error::OpenDirectoryError),
+  #[error("Search index: {0}")]
+  Io(#[from] std::io::Error),
+  #[error("Invalid query: {0:?}")]
+  Query(tantivy::query::QueryParserError),
+  #[error("Invalid query: {0}")]
+  Invalid(&'static str),
+}
+
+struct Fields {
+  fingerprint: Field,
+  subject: Field,
+  cn: Field,
+  o: Field,
+  ou: Field,
+  san: Field,
+  san_contains: Field,
+  issuer: Field,
+}
+
+pub struct SearchIndex {
+  index: Index,
+  reader: IndexReader,
+  /// `None` when opened with `open_reader`.
+  writer: Option<Mutex<IndexWriter>>,
+  fields: Fields,
+  dirty: AtomicBool,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct SearchHit {
+  pub fingerprint: String,
+  pub score: f32,
+  pub subject: String,
+  pub issuer: String,
+  pub names: Vec<String>,
+}
+
+fn schema() -> (Schema, Fields) {
+  let mut sb = Schema::builder();
+  let ngram = TextOptions::default().set_indexing_options(
+    TextFieldIndexing::default()
+        .set_tokenizer(NGRAM_TOKENIZER)
+        .set_index_option(IndexRecordOption::Basic)
+  );
+  let fields = Fields {
+    fingerprint: sb.add_text_field("fingerprint", STRING | STORED),
+    subject: sb.add_text_field("subject", TEXT | STORED),
+    cn: sb.add_text_field("cn", TEXT),
+    o: sb.add_text_field("o", TEXT),
+    ou: sb.add_text_field("ou", TEXT),
+    san: sb.add_text_field("san", TEXT | STORED),
+    san_contains: sb.add_text_field("san_contains", ngram),
+    issuer: sb.add_text_field("issuer", TEXT | STORED),
+  };
+  (sb.build(), fields)
+}
+
+fn open_index(config: &SearchConfig) -> Result<(Index, IndexReader, Fields), SearchError> {
+  std::fs::create_dir_all(&config.index_dir)?;
+  let (schema, fields) = schema();
+  let index = Index::open_or_create(MmapDirectory::open(&config.index_dir)?, schema)?;
+  index.tokenizers().register(NGRAM_TOKENIZER, TextAnalyzer::from(NgramTokenizer::new(3, 3, false)).filter(LowerCaser));
+  // Reloads when meta.json changes, including on commits of a writer in another process.
+  let reader = index.reader_builder().reload_policy(ReloadPolicy::OnCommit).try_into()?;
+  Ok((index, reader, fields))
+}
+
+impl SearchIndex {
+  /// Open (or create) the index for writing, and start the thread committing it periodically. The
+  /// thread exits once the index is dropped. Fails if another process has it open for writing.
+  pub fn open(config: &SearchConfig) -> Result<Arc<SearchIndex>, SearchError> {
+    let (index, reader, fields) = open_index(config)?;
+    let writer = index.writer_with_num_threads(1, config.writer_heap_mb * 1_000_000)?;
+    let s = Arc::new(SearchIndex { index, reader, writer: Some(Mutex::new(writer)), fields, dirty: AtomicBool::new(false) });
+    let weak = Arc::downgrade(&s);
+    let interval = Duration::from_millis(config.commit_interval_ms);
+    std::thread::Builder::new().name("search-commit".to_owned()).spawn(move || commit_loop(weak, interval))?;
+    Ok(s)
+  }
+
+  /// Open (or create) the index for searching only.
+  pub fn open_reader(config: &SearchConfig) -> Result<Arc<SearchIndex>, SearchError> {
+    let (index, reader, fields) = open_index(config)?;
+    Ok(Arc::new(SearchIndex { index, reader, writer: None, fields, dirty: AtomicBool::new(false) }))
+  }
+
+  /// Add or replace the document for this certificate. Does nothing on an index opened with
+  /// `open_reader`.
+  pub fn upsert(&self, fp: &Hash, cert: &X509) {
+    let writer = match self.writer {
+      Some(ref w) => w,
+      None => return
+    };
+    let f = &self.fields;
+    let fp = fp.to_string();
+    let mut doc = Document::default();
+    doc.add_text(f.fingerprint, &fp);
+    doc.add_text(f.subject, &format_name(cert.subject_name()));
+    doc.add_text(f.issuer, &format_name(cert.issuer_name()));
+    for e in cert.subject_name().entries() {
+      let field = match e.object().nid() {
+        Nid::COMMONNAME => f.cn,
+        Nid::ORGANIZATIONNAME => f.o,
+        Nid::ORGANIZATIONALUNITNAME => f.ou,
+        _ => continue
+      };
+      if let Ok(v) = e.data().as_utf8() {
+        doc.add_text(field, &v.to_string());
+      }
+    }
+    for n in cert_names(cert).unwrap_or_default() {
+      doc.add_text(f.san, &n.name);
+      doc.add_text(f.san_contains, &n.name);
+    }
+    let writer = writer.lock().unwrap();
+    writer.delete_term(Term::from_field_text(f.fingerprint, &fp));
+    writer.add_document(doc);
+    self.dirty.store(true, Ordering::Release);
+  }
+
+  /// Remove the document for this certificate, if any. Does nothing on an index opened with
+  /// `open_reader`.
+  pub fn delete(&self, fp: &Hash) {
+    if let Some(ref writer) = self.writer {
+      writer.lock().unwrap().delete_term(Term::from_field_text(self.fields.fingerprint, &fp.to_string()));
+      self.dirty.store(true, Ordering::Release);
+    }
+  }
+
+  pub fn commit(&self) -> Result<(), SearchError> {
+    if let Some(ref writer) = self.writer {
+      if self.dirty.swap(false, Ordering::AcqRel) {
+        writer.lock().unwrap().commit()?;
+      }
+    }
+    Ok(())
+  }
+
+  /// `q` uses the tantivy query syntax over the fields `subject` (default), `cn`, `o`, `ou`,
+  /// `san` (default) and `issuer` (default), e.g. `o:"Example Inc" san:example.com`. In addition,
+  /// `contains:paypal` matches names containing that substring, of at least 3 characters.
+  ///
+  /// Returns up to `limit` hits, after skipping the `offset` best ones.
+  pub fn search(&self, q: &str, limit: usize, offset: usize) -> Result<Vec<SearchHit>, SearchError> {
+    let f = &self.fields;
+    let mut clauses: Vec<(Occur, Box<dyn Query>)> = Vec::new();
+    let mut rest = Vec::new();
+    for word in q.split_whitespace() {
+      if word.starts_with(CONTAINS_PREFIX) {
+        clauses.push((Occur::Must, self.contains_query(&word[CONTAINS_PREFIX.len()..])?));
+      } else {
+        rest.push(word);
+      }
+    }
+    if !rest.is_empty() {
+      let parser = QueryParser::for_index(&self.index, vec![f.subject, f.san, f.issuer]);
+      clauses.push((Occur::Must, parser.parse_query(&rest.join(" ")).map_err(SearchError::Query)?));
+    }
+    if clauses.is_empty() {
+      return Err(SearchError::Invalid("empty query."));
+    }
+    let query = BooleanQuery::from(clauses);
+    let searcher = self.reader.searcher();
+    let mut hits = Vec::new();
+    for (score, addr) in searcher.search(&query, &TopDocs::with_limit(offset + limit))?.into_iter().skip(offset) {
+      let doc = searcher.doc(addr)?;
+      let text = |field| doc.get_first(field).and_then(|v| v.text()).unwrap_or("").to_owned();
+      hits.push(SearchHit {
+        fingerprint: text(f.fingerprint),
+        score,
+        subject: text(f.subject),
+        issuer: text(f.issuer),
+        names: doc.get_all(f.san).into_iter().filter_map(|v| v.text()).map(|s| s.to_owned()).collect(),
+      });
+    }
+    Ok(hits)
+  }
+
+  /// All trigrams of `s` must appear in the names. Trigrams may come from different names or be in
+  /// another order, which is an acceptable imprecision.
+  fn contains_query(&self, s: &str) -> Result<Box<dyn Query>, SearchError> {
+    if s.chars().count() < 3 {
+      return Err(SearchError::Invalid("contains: needs at least 3 characters."));
+    }
+    let analyzer = self.index.tokenizers().get(NGRAM_TOKENIZER).unwrap();
+    let mut terms = Vec::new();
+    analyzer.token_stream(s).process(&mut |t| terms.push(t.text.clone()));
+    terms.sort();
+    terms.dedup();
+    Ok(Box::new(BooleanQuery::from(terms.into_iter().map(|t| {
+      let q: Box<dyn Query> = Box::new(TermQuery::new(Term::from_field_text(self.fields.san_contains, &t), IndexRecordOption::Basic));
+      (Occur::Must, q)
+    }).collect::<Vec<_>>())))
+  }
+}
+
+impl Drop for SearchIndex {
+  fn drop(&mut self) {
+    if let Err(e) = self.commit() {
+      log::warn!("{}", e);
+    }
+  }
+}
+
+fn commit_loop(index: Weak<SearchIndex>, interval: Duration) {
+  loop {
+    std::thread::sleep(interval);
+    match index.upgrade() {
+      Some(s) => {
+        if let Err(e) = s.commit() {
+          log::warn!("{}", e);
+        }
+      },
+      None => return
+    }
+  }
+}
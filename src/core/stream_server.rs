@@ -0,0 +1,378 @@
+//! The live feed of `[stream]`: newly ingested certificates, sent over WebSocket in the format of
+//! certstream, so that existing certstream clients can connect to it.
+//!
+//! * `/`: `certificate_update` messages, without the DER of the certificates.
+//! * `/full-stream`: the same, with `as_der` on each certificate.
+//! * `/domains-only`: `{"message_type": "dns_entries", "data": [...]}`.
+//!
+//! `?domain=example.com,example.org` only sends certificates with a name equal to, or a
+//! subdomain of, one of these.
+//!
+//! `/events` is a Server-Sent Events stream of changes in the status of logs, for dashboards:
+//! `latest_sth`, `sth_error`, `consistency_error` and `fetch_error`, each with the `log_id` it is
+//! about. `?log=<id>,<id>` only sends events about these logs.
+//!
+//! Connections count against the rate limit, like API requests. The certificate feeds need the
+//! `read` scope, while `/events` is public like the log status routes. The key can also be passed
+//! as `?key=`, since browsers can't set headers on WebSocket and EventSource.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::{Duration, Instant, SystemTime};
+
+use serde_json::json;
+use tungstenite::{Message, WebSocket};
+use tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tungstenite::http::StatusCode;
+
+use crate::core::api_keys::Scope;
+use crate::core::config::StreamConfig;
+use crate::core::events::{Event, EventBus, EventKind, unix_time_f64};
+use crate::models::Hash;
+
+/// Checks the API key of a client, if any, against the scope needed, and takes from its rate limit
+/// (see `api::Access`). Given the key, the peer address, its `X-Real-IP` header and the scope. On
+/// refusal, gives the HTTP status and message to send.
+pub type Authorize = dyn Fn(Option<&str>, Option<IpAddr>, Option<&str>, Option<Scope>) -> Result<(), (u16, String)> + Send + Sync;
+
+/// How often a client with nothing to receive is checked for a close.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+const WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+/// For the request line to arrive.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Longest request line or head accepted.
+const MAX_REQUEST_LEN: usize = 8192;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Feed {
+  Lite,
+  Full,
+  DomainsOnly,
+}
+
+struct ClientOptions {
+  feed: Feed,
+  domains: Vec<String>,
+}
+
+fn parse_request(path: &str, query: Option<&str>) -> Option<ClientOptions> {
+  let feed = match path.trim_end_matches('/') {
+    "" => Feed::Lite,
+    "/full-stream" => Feed::Full,
+    "/domains-only" => Feed::DomainsOnly,
+    _ => return None
+  };
+  let mut domains = Vec::new();
+  for pair in query.unwrap_or("").split('&') {
+    if let Some(value) = pair.strip_prefix("domain=") {
+      for d in value.split(',').map(|d| d.trim().to_lowercase()) {
+        let d = d.strip_prefix("*.").unwrap_or(&d);
+        if !d.is_empty() {
+          domains.push(d.to_owned());
+        }
+      }
+    }
+  }
+  Some(ClientOptions { feed, domains })
+}
+
+/// `None` if a log id is invalid, otherwise the logs to send events about (all if empty).
+fn parse_events_query(query: Option<&str>) -> Option<Vec<Hash>> {
+  let mut logs = Vec::new();
+  for pair in query.unwrap_or("").split('&') {
+    if let Some(value) = pair.strip_prefix("log=") {
+      for id in value.split(',').filter(|id| !id.is_empty()) {
+        logs.push(id.parse().ok()?);
+      }
+    }
+  }
+  Some(logs)
+}
+
+/// The key from `Authorization: Bearer <key>` or `X-API-Key`, or else from `?key=`.
+fn api_key<'a>(header: impl Fn(&str) -> Option<&'a str>, query: Option<&'a str>) -> Option<&'a str> {
+  header("Authorization").and_then(|v| v.strip_prefix("Bearer "))
+      .or_else(|| header("X-API-Key"))
+      .or_else(|| query.unwrap_or("").split('&').find_map(|pair| pair.strip_prefix("key=")))
+      .map(|k| k.trim())
+}
+
+fn error_response(status: StatusCode, message: &str) -> ErrorResponse {
+  let mut res = ErrorResponse::new(Some(message.to_owned()));
+  *res.status_mut() = status;
+  res
+}
+
+/// Decrements the client count when the client goes away.
+struct ClientSlot(Arc<AtomicUsize>);
+
+impl Drop for ClientSlot {
+  fn drop(&mut self) {
+    self.0.fetch_sub(1, Ordering::AcqRel);
+  }
+}
+
+/// Read whatever the client sent, without blocking. Returns false once the connection is closed.
+fn poll_client(ws: &mut WebSocket<TcpStream>) -> bool {
+  if ws.get_mut().set_nonblocking(true).is_err() {
+    return false;
+  }
+  let open = loop {
+    match ws.read_message() {
+      Ok(Message::Close(_)) => break false,
+      // Pings are answered by tungstenite on the next write.
+      Ok(_) => continue,
+      Err(tungstenite::Error::Io(ref e)) if e.kind() == io::ErrorKind::WouldBlock => break true,
+      Err(_) => break false
+    }
+  };
+  open && ws.get_mut().set_nonblocking(false).is_ok()
+}
+
+fn serve_websocket(stream: TcpStream, config: &StreamConfig, events: &EventBus, authorize: &Authorize, too_many: bool) {
+  let ip = stream.peer_addr().ok().map(|a| a.ip());
+  // So that a client can't hold its thread by sending the handshake slowly, or not at all.
+  if stream.set_read_timeout(Some(REQUEST_TIMEOUT)).is_err() || stream.set_write_timeout(Some(WRITE_TIMEOUT)).is_err() {
+    return;
+  }
+  let mut options = None;
+  let ws = tungstenite::accept_hdr(stream, |req: &Request, res: Response| {
+    if too_many {
+      return Err(error_response(StatusCode::SERVICE_UNAVAILABLE, "Too many clients."));
+    }
+    options = parse_request(req.uri().path(), req.uri().query());
+    if options.is_none() {
+      return Err(error_response(StatusCode::NOT_FOUND, "Expected /, /full-stream or /domains-only."));
+    }
+    let header = |name: &str| req.headers().get(name).and_then(|v| v.to_str().ok());
+    if let Err((status, message)) = authorize(api_key(header, req.uri().query()), ip, header("X-Real-IP"), Some(Scope::Read)) {
+      return Err(error_response(StatusCode::from_u16(status).unwrap_or(StatusCode::FORBIDDEN), &message));
+    }
+    Ok(res)
+  });
+  let (mut ws, options) = match (ws, options) {
+    (Ok(ws), Some(options)) => (ws, options),
+    _ => return
+  };
+  let subscription = events.subscribe(EventKind::Certificate);
+  let heartbeat_interval = Duration::from_millis(config.heartbeat_interval_ms);
+  let mut last_sent = Instant::now();
+  loop {
+    if !poll_client(&mut ws) {
+      return;
+    }
+    let message = match subscription.receiver.recv_timeout(POLL_INTERVAL) {
+      Ok(event) => match *event {
+        Event::Certificate(ref cert) if options.domains.is_empty() || options.domains.iter().any(|d| cert.matches_suffix(d)) => {
+          Some(match options.feed {
+            Feed::Lite => cert.certstream_lite(),
+            Feed::Full => cert.certstream_full(),
+            Feed::DomainsOnly => json!({ "message_type": "dns_entries", "data": cert.all_domains }).to_string(),
+          })
+        },
+        _ => None
+      },
+      Err(RecvTimeoutError::Timeout) => None,
+      Err(RecvTimeoutError::Disconnected) => return
+    };
+    // Also when every event is filtered out.
+    let message = match message {
+      Some(m) => m,
+      None if last_sent.elapsed() >= heartbeat_interval => {
+        json!({ "message_type": "heartbeat", "timestamp": unix_time_f64(SystemTime::now()) }).to_string()
+      },
+      None => continue
+    };
+    let dropped = subscription.take_dropped();
+    if dropped > 0 {
+      let notice = json!({ "message_type": "dropped", "count": dropped }).to_string();
+      if ws.write_message(Message::Text(notice)).is_err() {
+        return;
+      }
+    }
+    if ws.write_message(Message::Text(message)).is_err() {
+      return;
+    }
+    last_sent = Instant::now();
+  }
+}
+
+/// Peek at the request line, leaving it to be read by whoever serves the request. Returns the
+/// request target, or `None` if the request line is missing or too long.
+fn peek_target(stream: &TcpStream) -> io::Result<Option<String>> {
+  stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+  let started = Instant::now();
+  let mut buf = vec![0u8; MAX_REQUEST_LEN];
+  loop {
+    let n = stream.peek(&mut buf)?;
+    if let Some(end) = buf[..n].windows(2).position(|w| w == b"\r\n") {
+      let line = String::from_utf8_lossy(&buf[..end]);
+      return Ok(line.split(' ').nth(1).map(|t| t.to_owned()));
+    }
+    if n == 0 || n == buf.len() || started.elapsed() > REQUEST_TIMEOUT {
+      return Ok(None);
+    }
+    // Only part of the request line has arrived, and peek would return it again right away.
+    std::thread::sleep(Duration::from_millis(10));
+  }
+}
+
+fn write_http_error(mut stream: TcpStream, status: &str, message: &str) {
+  let _ = write!(stream, "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, message.len(), message);
+}
+
+/// Whether the client has closed the connection. Anything it sends is ignored.
+fn client_closed(stream: &mut TcpStream) -> bool {
+  if stream.set_nonblocking(true).is_err() {
+    return true;
+  }
+  let mut buf = [0u8; 512];
+  let closed = loop {
+    match stream.read(&mut buf) {
+      Ok(0) => break true,
+      Ok(_) => continue,
+      Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break false,
+      Err(_) => break true
+    }
+  };
+  closed || stream.set_nonblocking(false).is_err()
+}
+
+fn serve_events(mut stream: TcpStream, query: Option<&str>, config: &StreamConfig, events: &EventBus, authorize: &Authorize, too_many: bool) {
+  // Read the request head, which is not otherwise needed, within REQUEST_TIMEOUT.
+  if stream.set_read_timeout(Some(REQUEST_TIMEOUT)).is_err() {
+    return;
+  }
+  let started = Instant::now();
+  let mut reader = BufReader::new((&stream).take(MAX_REQUEST_LEN as u64));
+  let mut headers = Vec::new();
+  loop {
+    let mut line = String::new();
+    match reader.read_line(&mut line) {
+      Ok(0) | Err(_) => return,
+      Ok(_) if line == "\r\n" || line == "\n" => break,
+      Ok(_) if started.elapsed() > REQUEST_TIMEOUT => return,
+      Ok(_) => headers.push(line)
+    }
+  }
+  drop(reader);
+  if too_many {
+    return write_http_error(stream, "503 Service Unavailable", "Too many clients.");
+  }
+  let header = |name: &str| headers.iter().find_map(|h| {
+    let (n, v) = h.split_at(h.find(':')?);
+    if n.trim().eq_ignore_ascii_case(name) { Some(v[1..].trim()) } else { None }
+  });
+  if let Err((status, message)) = authorize(api_key(header, query), stream.peer_addr().ok().map(|a| a.ip()), header("X-Real-IP"), None) {
+    let status = StatusCode::from_u16(status).unwrap_or(StatusCode::FORBIDDEN);
+    let status = format!("{} {}", status.as_u16(), status.canonical_reason().unwrap_or(""));
+    return write_http_error(stream, &status, &message);
+  }
+  let logs = match parse_events_query(query) {
+    Some(logs) => logs,
+    None => return write_http_error(stream, "400 Bad Request", "Invalid log id.")
+  };
+  let _ = stream.set_write_timeout(Some(WRITE_TIMEOUT));
+  let subscription = events.subscribe(EventKind::Log);
+  if stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\n\
+                        Access-Control-Allow-Origin: *\r\nConnection: close\r\n\r\n").is_err() {
+    return;
+  }
+  let heartbeat_interval = Duration::from_millis(config.heartbeat_interval_ms);
+  let mut last_sent = Instant::now();
+  loop {
+    if client_closed(&mut stream) {
+      return;
+    }
+    let message = match subscription.receiver.recv_timeout(POLL_INTERVAL) {
+      Ok(event) => match *event {
+        Event::Log(ref e) if logs.is_empty() || logs.contains(e.log_id()) => {
+          Some(format!("event: {}\ndata: {}\n\n", e.name(), serde_json::to_string(e).unwrap()))
+        },
+        _ => None
+      },
+      Err(RecvTimeoutError::Timeout) => None,
+      Err(RecvTimeoutError::Disconnected) => return
+    };
+    // Also when every event is filtered out.
+    let message = match message {
+      Some(m) => m,
+      None if last_sent.elapsed() >= heartbeat_interval => ": heartbeat\n\n".to_owned(),
+      None => continue
+    };
+    // A client that missed events should reload the state, as it would after reconnecting.
+    let dropped = subscription.take_dropped();
+    if dropped > 0 && write!(stream, "event: dropped\ndata: {}\n\n", dropped).is_err() {
+      return;
+    }
+    if stream.write_all(message.as_bytes()).is_err() {
+      return;
+    }
+    last_sent = Instant::now();
+  }
+}
+
+fn serve_client(stream: TcpStream, config: &StreamConfig, events: &EventBus, authorize: &Authorize, slot: ClientSlot) {
+  let too_many = slot.0.load(Ordering::Acquire) > config.max_clients;
+  let target = match peek_target(&stream) {
+    Ok(Some(t)) => t,
+    _ => return
+  };
+  let (path, query) = match target.find('?') {
+    Some(i) => (&target[..i], Some(&target[i + 1..])),
+    None => (&target[..], None)
+  };
+  if path == "/events" {
+    serve_events(stream, query, config, events, authorize, too_many);
+  } else {
+    serve_websocket(stream, config, events, authorize, too_many);
+  }
+}
+
+/// Listen on `config.address` and `config.port`, with a thread per client.
+pub fn start(config: &StreamConfig, events: Arc<EventBus>, authorize: Arc<Authorize>) -> io::Result<()> {
+  let listener = TcpListener::bind((config.address.as_str(), config.port))?;
+  log::info!("Serving the certificate stream on {}", listener.local_addr()?);
+  let config = Arc::new(config.clone());
+  let clients = Arc::new(AtomicUsize::new(0));
+  std::thread::Builder::new().name("stream-server".to_owned()).spawn(move || {
+    for stream in listener.incoming() {
+      let stream = match stream {
+        Ok(s) => s,
+        Err(e) => {
+          log::warn!("Stream server: failed to accept a connection: {}", e);
+          continue;
+        }
+      };
+      clients.fetch_add(1, Ordering::AcqRel);
+      let slot = ClientSlot(clients.clone());
+      let config = config.clone();
+      let events = events.clone();
+      let authorize = authorize.clone();
+      let res = std::thread::Builder::new().name("stream-client".to_owned()).spawn(move || {
+        serve_client(stream, &config, &events, &*authorize, slot);
+      });
+      if let Err(e) = res {
+        log::warn!("Stream server: failed to spawn a client thread: {}", e);
+      }
+    }
+  })?;
+  Ok(())
+}
+
+#[test]
+fn test_parse_request() {
+  assert_eq!(parse_request("/", None).unwrap().feed, Feed::Lite);
+  assert_eq!(parse_request("/full-stream", None).unwrap().feed, Feed::Full);
+  let o = parse_request("/domains-only/", Some("domain=Example.com,*.example.org&x=1")).unwrap();
+  assert_eq!(o.feed, Feed::DomainsOnly);
+  assert_eq!(o.domains, vec!["example.com", "example.org"]);
+  assert!(parse_request("/other", None).is_none());
+  assert_eq!(parse_events_query(None), Some(vec![]));
+  assert!(parse_events_query(Some("log=zz")).is_none());
+  assert_eq!(api_key(|_| None, Some("domain=example.com&key=k1")), Some("k1"));
+  assert_eq!(api_key(|h| if h == "X-API-Key" { Some("k2") } else { None }, Some("key=k1")), Some("k2"));
+}
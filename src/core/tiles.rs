@@ -0,0 +1,263 @@
+//! Export of mirrored logs as static tiles (<https://c2sp.org/static-ct-api>, which builds on
+//! <https://c2sp.org/tlog-tiles>).
+//!
+//! Each log is written to `<output_dir>/<log id>/`. The checkpoint is written last and doubles
+//! as the record of how far the export got, so an interrupted export is simply redone.
+
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use ctclient::internal::re_exports::openssl::sha::{sha256, Sha256};
+use diesel::prelude::*;
+
+use crate::core::db::DBConn;
+use crate::core::merkle::{CompactRange, HashBytes};
+use crate::core::mirror::{self, MirrorError};
+use crate::core::raw_entries::decompress;
+use crate::models::{CtLog, Hash, RawLeaf};
+
+const TILE_WIDTH: u64 = 256;
+const TILE_HEIGHT: u32 = 8;
+
+#[derive(Debug, Error)]
+pub enum TileError {
+  #[error("DB: {0}")]
+  DB(#[from] diesel::result::Error),
+  #[error("{0}")]
+  Mirror(#[from] MirrorError),
+  #[error("{path}: {source}")]
+  Io { path: PathBuf, #[source] source: std::io::Error },
+  #[error("Leaf #{0}: {1}")]
+  InvalidLeaf(u64, &'static str),
+  #[error("Existing tile {0} is corrupted.")]
+  CorruptedTile(PathBuf),
+}
+
+/// `N` in a tile path: groups of three digits, all but the last prefixed with `x`.
+fn encode_index(mut n: u64) -> String {
+  let mut parts = vec![format!("{:03}", n % 1000)];
+  n /= 1000;
+  while n > 0 {
+    parts.push(format!("x{:03}", n % 1000));
+    n /= 1000;
+  }
+  parts.reverse();
+  parts.join("/")
+}
+
+fn tile_path(level: &str, index: u64, width: u64) -> String {
+  if width == TILE_WIDTH {
+    format!("tile/{}/{}", level, encode_index(index))
+  } else {
+    format!("tile/{}/{}.p/{}", level, encode_index(index), width)
+  }
+}
+
+fn write_atomic(path: &Path, data: &[u8]) -> Result<(), TileError> {
+  let io_err = |source| TileError::Io { path: path.to_owned(), source };
+  std::fs::create_dir_all(path.parent().unwrap()).map_err(io_err)?;
+  let tmp = path.with_extension("tmp");
+  let mut f = std::fs::File::create(&tmp).map_err(io_err)?;
+  f.write_all(data).map_err(io_err)?;
+  f.sync_all().map_err(io_err)?;
+  std::fs::rename(&tmp, path).map_err(io_err)
+}
+
+/// The origin line for a log: its URL without scheme or trailing slash.
+pub fn origin(log: &CtLog) -> String {
+  let u = &log.endpoint_url;
+  let u = u.splitn(2, "://").nth(1).unwrap_or(u);
+  u.trim_end_matches('/').to_owned()
+}
+
+fn checkpoint_size(path: &Path) -> Result<u64, TileError> {
+  match std::fs::read_to_string(path) {
+    Ok(s) => s.lines().nth(1).and_then(|l| l.parse().ok())
+        .ok_or_else(|| TileError::CorruptedTile(path.to_owned())),
+    Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+    Err(e) => Err(TileError::Io { path: path.to_owned(), source: e })
+  }
+}
+
+/// A checkpoint signed with the log's own sth signature, as an RFC6962NoteSignature.
+fn checkpoint(log: &CtLog, tree_size: u64, root_hash: &HashBytes, sth_timestamp: u64, sth_signature: &[u8]) -> String {
+  let origin = origin(log);
+  let mut key_id = Sha256::new();
+  key_id.update(origin.as_bytes());
+  key_id.update(&[0x0a, 0x05]);
+  key_id.update(&log.public_key.0);
+  let key_id = key_id.finish();
+  let mut sig = Vec::with_capacity(4 + 8 + sth_signature.len());
+  sig.extend_from_slice(&key_id[..4]);
+  sig.extend_from_slice(&sth_timestamp.to_be_bytes());
+  sig.extend_from_slice(sth_signature);
+  format!("{}\n{}\n{}\n\n\u{2014} {} {}\n", origin, tree_size, base64::encode(root_hash), origin, base64::encode(&sig))
+}
+
+struct TlsReader<'a> {
+  buf: &'a [u8],
+}
+
+impl<'a> TlsReader<'a> {
+  fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+    if self.buf.len() < n {
+      return None;
+    }
+    let (a, b) = self.buf.split_at(n);
+    self.buf = b;
+    Some(a)
+  }
+
+  fn u24_prefixed(&mut self) -> Option<&'a [u8]> {
+    let len = self.take(3)?;
+    let len = (len[0] as usize) << 16 | (len[1] as usize) << 8 | len[2] as usize;
+    self.take(len)
+  }
+
+  fn cert_list(&mut self) -> Option<Vec<&'a [u8]>> {
+    let mut list = TlsReader { buf: self.u24_prefixed()? };
+    let mut certs = Vec::new();
+    while !list.buf.is_empty() {
+      certs.push(list.u24_prefixed()?);
+    }
+    Some(certs)
+  }
+}
+
+/// Turn a MerkleTreeLeaf and its extra_data into a static-ct-api TileLeaf, returning the issuer
+/// certificates it references.
+fn tile_leaf<'a>(leaf_input: &[u8], extra_data: &'a [u8], out: &mut Vec<u8>) -> Result<Vec<&'a [u8]>, &'static str> {
+  if leaf_input.len() < 12 || leaf_input[0] != 0 || leaf_input[1] != 0 {
+    return Err("Unsupported MerkleTreeLeaf version or type.");
+  }
+  let timestamped_entry = &leaf_input[2..];
+  let entry_type = u16::from_be_bytes([leaf_input[10], leaf_input[11]]);
+  let mut extra = TlsReader { buf: extra_data };
+  out.extend_from_slice(timestamped_entry);
+  let chain = match entry_type {
+    0 => extra.cert_list().ok_or("Invalid extra_data.")?,
+    1 => {
+      let pre_cert = extra.u24_prefixed().ok_or("Invalid extra_data.")?;
+      out.extend_from_slice(&(pre_cert.len() as u32).to_be_bytes()[1..]);
+      out.extend_from_slice(pre_cert);
+      extra.cert_list().ok_or("Invalid extra_data.")?
+    },
+    _ => return Err("Unknown entry type.")
+  };
+  out.extend_from_slice(&((chain.len() * 32) as u16).to_be_bytes());
+  for c in &chain {
+    out.extend_from_slice(&sha256(c));
+  }
+  Ok(chain)
+}
+
+/// Bring the tiles of this log up to date with its mirrored sth. Returns the new tree size, or
+/// `None` if the log is not (fully) mirrored.
+pub fn export_log(db: &DBConn, log: &CtLog, output_dir: &Path) -> Result<Option<u64>, TileError> {
+  let sth = match mirror::mirrored_sth(db, &log.log_id)? {
+    Some(s) => s,
+    None => return Ok(None)
+  };
+  let dir = output_dir.join(log.log_id.to_string());
+  let checkpoint_path = dir.join("checkpoint");
+  let prev_size = checkpoint_size(&checkpoint_path)?;
+  let size = sth.tree_size as u64;
+  if prev_size >= size {
+    return Ok(Some(prev_size));
+  }
+
+  // Level 0 hash tiles, data tiles and issuers.
+  let mut computed: BTreeMap<u64, Vec<HashBytes>> = BTreeMap::new();
+  for t in prev_size / TILE_WIDTH..(size + TILE_WIDTH - 1) / TILE_WIDTH {
+    let start = t * TILE_WIDTH;
+    let end = std::cmp::min(start + TILE_WIDTH, size);
+    let rows: Vec<RawLeaf> = {
+      use crate::schema::raw_leaves::dsl::*;
+      raw_leaves
+          .filter(log_id.eq(log.log_id).and(leaf_index.ge(start as i64)).and(leaf_index.lt(end as i64)))
+          .order_by(leaf_index.asc())
+          .load(db)?
+    };
+    if rows.len() as u64 != end - start {
+      return Err(MirrorError::MissingLeaf(start).into());
+    }
+    let mut hashes = Vec::with_capacity(rows.len());
+    let mut data_tile = Vec::new();
+    for r in rows {
+      let idx = r.leaf_index as u64;
+      let leaf_input = decompress(&r.leaf_input).map_err(|_| TileError::InvalidLeaf(idx, "Unable to decompress."))?;
+      let extra_data = decompress(&r.extra_data).map_err(|_| TileError::InvalidLeaf(idx, "Unable to decompress."))?;
+      for issuer in tile_leaf(&leaf_input, &extra_data, &mut data_tile).map_err(|e| TileError::InvalidLeaf(idx, e))? {
+        let path = dir.join("issuer").join(Hash(sha256(issuer)).to_string());
+        if !path.exists() {
+          write_atomic(&path, issuer)?;
+        }
+      }
+      hashes.push(r.leaf_hash.0);
+    }
+    let width = end - start;
+    write_atomic(&dir.join(tile_path("data", t, width)), &data_tile)?;
+    write_atomic(&dir.join(tile_path("0", t, width)), &hashes.concat())?;
+    computed.insert(t, hashes);
+  }
+
+  // Higher levels: node k at level L is the root of full tile k at level L - 1.
+  let mut level = 1u32;
+  while size >> (TILE_HEIGHT * level) > 0 {
+    let nb_nodes = size >> (TILE_HEIGHT * level);
+    let prev_nb_nodes = prev_size >> (TILE_HEIGHT * level);
+    let mut next_computed = BTreeMap::new();
+    for t in prev_nb_nodes / TILE_WIDTH..(nb_nodes + TILE_WIDTH - 1) / TILE_WIDTH {
+      let start = t * TILE_WIDTH;
+      let end = std::cmp::min(start + TILE_WIDTH, nb_nodes);
+      let mut hashes = Vec::with_capacity((end - start) as usize);
+      for k in start..end {
+        let below = match computed.get(&k) {
+          Some(h) if h.len() as u64 == TILE_WIDTH => h.clone(),
+          _ => read_full_tile(&dir.join(tile_path(&(level - 1).to_string(), k, TILE_WIDTH)))?
+        };
+        let mut r = CompactRange::new();
+        r.extend(&below);
+        hashes.push(r.root());
+      }
+      write_atomic(&dir.join(tile_path(&level.to_string(), t, end - start)), &hashes.concat())?;
+      next_computed.insert(t, hashes);
+    }
+    computed = next_computed;
+    level += 1;
+  }
+
+  write_atomic(&checkpoint_path, checkpoint(log, size, &sth.tree_hash.0, sth.sth_timestamp as u64, &sth.signature.0).as_bytes())?;
+  Ok(Some(size))
+}
+
+fn read_full_tile(path: &Path) -> Result<Vec<HashBytes>, TileError> {
+  let data = std::fs::read(path).map_err(|e| TileError::Io { path: path.to_owned(), source: e })?;
+  if data.len() as u64 != TILE_WIDTH * 32 {
+    return Err(TileError::CorruptedTile(path.to_owned()));
+  }
+  Ok(data.chunks(32).map(|c| c.try_into().unwrap()).collect())
+}
+
+#[test]
+fn test_tile_paths() {
+  assert_eq!(tile_path("0", 0, 256), "tile/0/000");
+  assert_eq!(tile_path("1", 1234067, 256), "tile/1/x001/x234/067");
+  assert_eq!(tile_path("data", 1000, 17), "tile/data/x001/000.p/17");
+}
+
+#[test]
+fn test_tile_leaf() {
+  // x509_entry with a 2 byte cert and a chain of one 1 byte cert.
+  let leaf_input = [0u8, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 2, 0xaa, 0xbb, 0, 0];
+  let extra_data = [0u8, 0, 4, 0, 0, 1, 0xcc];
+  let mut out = Vec::new();
+  let chain = tile_leaf(&leaf_input, &extra_data, &mut out).unwrap();
+  assert_eq!(chain, vec![&[0xccu8][..]]);
+  assert_eq!(&out[..leaf_input.len() - 2], &leaf_input[2..]);
+  assert_eq!(&out[leaf_input.len() - 2..leaf_input.len()], &[0u8, 32]);
+  assert_eq!(&out[leaf_input.len()..], &sha256(&[0xcc]));
+  assert!(tile_leaf(&leaf_input, &extra_data[..5], &mut Vec::new()).is_err());
+}
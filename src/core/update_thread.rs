@@ -1,22 +1,46 @@
 use std::convert::TryFrom;
 use std::mem::{MaybeUninit, replace};
+use std::path::PathBuf;
 use std::panic::AssertUnwindSafe;
-use std::sync::mpsc;
+use std::sync::{Arc, mpsc};
 use std::thread;
 use std::thread::JoinHandle;
 use std::time::Duration;
 
 use ctclient::{CTClient, SignedTreeHead, SthResult};
-use ctclient::internal::Leaf;
 use diesel::prelude::*;
 
+use crate::core::config::HttpConfig;
 use crate::core::db::{DBPool, DBPooledConn, PgConnectionHelper};
+use crate::core::events::{CertificateEvent, Event, EventBus, EventKind, LogEvent, SthInfo};
+use crate::core::merkle::CompactRange;
+use crate::core::raw_entries::{RawEntry, get_raw_entries};
+use crate::core::search::SearchIndex;
 use crate::models::{CtLog, Hash, Sth};
+use crate::models::inserts::ParsedLeaf;
+
+/// How long to hold on to the db connection after each round before returning it to the pool.
+const DB_RELEASE_DELAY: Duration = Duration::from_millis(250);
 
 enum ChannelMessage {
   Stop
 }
 
+pub struct Options {
+  pub poll_interval: Duration,
+  pub http: HttpConfig,
+  pub merkle_backfill_batch: u64,
+  pub store_raw_leaves: bool,
+  /// Export tiles here whenever latest_sth advances.
+  pub tiles_dir: Option<PathBuf>,
+  /// Add new certificates to this full-text index.
+  pub search: Option<Arc<SearchIndex>>,
+  /// Number of leaves whose certificates are written in one transaction.
+  pub batch_size: usize,
+  /// Publish each stored leaf here.
+  pub events: Option<Arc<EventBus>>,
+}
+
 pub struct Handle {
   jh: MaybeUninit<JoinHandle<()>>,
   sender: mpsc::Sender<ChannelMessage>,
@@ -44,7 +68,7 @@ impl<T> _DbErrorUnwrapHelper for Result<T, diesel::result::Error> {
   }
 }
 
-pub fn init_thread(db_pool: DBPool, log: CtLog) -> Handle {
+pub fn init_thread(db_pool: DBPool, log: CtLog, opts: Options) -> Handle {
   let (sender, recv) = mpsc::channel::<ChannelMessage>();
   let jh = thread::Builder::new().name(format!("update-{}", &log.log_id)).spawn(move || {
     match std::panic::catch_unwind(AssertUnwindSafe(move || {
@@ -64,13 +88,22 @@ pub fn init_thread(db_pool: DBPool, log: CtLog) -> Handle {
       use crate::schema::ctlogs::dsl::log_id as ctlogs_log_id;
       use crate::schema::sth::dsl::log_id as sth_log_id;
 
-      let http_client = ctclient::internal::new_http_client().unwrap();
+      let http_client = opts.http.build_client().unwrap();
       let parsed_url = ctclient::internal::re_exports::reqwest::Url::parse(&log.endpoint_url).unwrap();
       let parsed_pub_key = ctclient::internal::re_exports::openssl::pkey::PKey::public_key_from_der(&log.public_key.0).unwrap();
       struct FetchedSth {
         stored_as_id: i64,
         sth: SignedTreeHead,
       }
+      let publish = |event: LogEvent| {
+        if let Some(ref events) = opts.events {
+          events.publish(Event::Log(event));
+        }
+      };
+      let consistency_error = |db: &DBPooledConn, from_sth_id: i64, to_sth_id: i64, error: String| {
+        crate::models::inserts::ConsistencyCheckError::upsert(db, log.log_id, from_sth_id, to_sth_id, &error).unwrap_or_display_err();
+        publish(LogEvent::ConsistencyError { log_id: log.log_id, from_sth_id, to_sth_id, error });
+      };
       #[derive(Debug, Error)]
       enum FetchSthError {
         #[error("{0}")]
@@ -144,13 +177,7 @@ pub fn init_thread(db_pool: DBPool, log: CtLog) -> Handle {
               if s.root_hash == latest.sth.root_hash {
                 pass = true;
               } else {
-                crate::models::inserts::ConsistencyCheckError::upsert(
-                  db,
-                  log.log_id,
-                  s_id,
-                  latest.stored_as_id,
-                  "Different hash but same tree size."
-                ).unwrap_or_display_err();
+                consistency_error(db, s_id, latest.stored_as_id, "Different hash but same tree size.".to_owned());
               }
             },
             Less => {
@@ -166,13 +193,7 @@ pub fn init_thread(db_pool: DBPool, log: CtLog) -> Handle {
                   pass = true;
                 },
                 Err(e) => {
-                  crate::models::inserts::ConsistencyCheckError::upsert(
-                    db,
-                    log.log_id,
-                    s_id,
-                    latest.stored_as_id,
-                    &format!("{}", e)
-                  ).unwrap_or_display_err();
+                  consistency_error(db, s_id, latest.stored_as_id, format!("{}", e));
                 }
               }
             }
@@ -183,12 +204,12 @@ pub fn init_thread(db_pool: DBPool, log: CtLog) -> Handle {
                 .set(checked_consistent_with_latest.eq(true))
                 .execute(db).unwrap_or_display_err();
             {
+              // Including errors against earlier latest sth, which are consistent with this one.
               use crate::schema::consistency_check_errors::dsl;
               diesel::delete(dsl::consistency_check_errors)
                   .filter(
                     dsl::log_id.eq(&log.log_id)
                         .and(dsl::from_sth_id.eq(s_id))
-                        .and(dsl::to_sth_id.eq(latest.stored_as_id))
                   )
                   .execute(db).unwrap_or_display_err();
             }
@@ -196,22 +217,143 @@ pub fn init_thread(db_pool: DBPool, log: CtLog) -> Handle {
         }
       };
 
-      let advance_latest_sth = |db: &DBPooledConn, new_latest: &FetchedSth| {
+      // Raw leaves are only stored here, together with the sth or frontier they were verified
+      // against, so that leaves which failed verification are never kept.
+      let store_raw_leaves = |db: &DBPooledConn, first_leaf: u64, raw_leaves: &[RawEntry]| -> Result<(), diesel::result::Error> {
+        for (i, e) in raw_leaves.iter().enumerate() {
+          crate::models::inserts::RawLeaf::insert(db, log.log_id, first_leaf + i as u64, e)?;
+        }
+        Ok(())
+      };
+
+      let advance_latest_sth = |db: &DBPooledConn, new_latest: &FetchedSth, new_frontier: Option<&CompactRange>, first_leaf: u64, raw_leaves: &[RawEntry]| {
         db.transaction_rw_serializable::<(), diesel::result::Error, _>(|| {
           diesel::update(sth)
               .filter(sth_id.eq(new_latest.stored_as_id))
-              .set(checked_consistent_with_latest.eq(true))
+              .set((checked_consistent_with_latest.eq(true), was_latest.eq(true)))
               .execute(db)?;
           diesel::update(ctlogs)
               .filter(ctlogs_log_id.eq(&log.log_id))
               .set(latest_sth.eq(new_latest.stored_as_id))
               .execute(db)?;
+          if let Some(f) = new_frontier {
+            crate::models::inserts::MerkleFrontier::upsert(db, log.log_id, f)?;
+          }
+          store_raw_leaves(db, first_leaf, raw_leaves)?;
           Ok(())
         }).unwrap_or_display_err();
+        if opts.events.as_ref().map_or(false, |e| e.has_subscribers(EventKind::Log)) {
+          let row: Sth = sth.filter(sth_id.eq(new_latest.stored_as_id)).first(db).unwrap_or_display_err();
+          publish(LogEvent::LatestSth {
+            log_id: log.log_id,
+            latest_sth: SthInfo {
+              id: row.id,
+              tree_size: row.tree_size,
+              tree_hash: row.tree_hash,
+              received_time: row.received_time.timestamp_millis(),
+              sth_timestamp: row.sth_timestamp,
+            },
+          });
+        }
         check_unchecked_consistency(db, new_latest);
       };
 
+      let load_frontier = |db: &DBPooledConn| -> Option<CompactRange> {
+        use crate::schema::merkle_frontiers::dsl as mf;
+        let row: Option<crate::models::MerkleFrontier> = mf::merkle_frontiers
+            .filter(mf::log_id.eq(&log.log_id))
+            .first(db).optional().unwrap_or_display_err();
+        row.map(|r| r.to_compact_range().unwrap())
+      };
+
+      // Build up the frontier for leaves we did not fetch during normal updates (i.e. those before
+      // the first sth we got), a batch at a time, until it catches up with latest_sth. Each batch is
+      // checked with a consistency proof from its root to latest_sth before it is saved. If the
+      // root of the last batch does not match, stop until the failure is cleared, instead of
+      // fetching it again every round.
+      let merkle_backfill = |db: &DBPooledConn, latest: &FetchedSth| {
+        if opts.merkle_backfill_batch == 0 {
+          return;
+        }
+        use crate::schema::merkle_backfill_failures::dsl as mbf;
+        let failed: i64 = mbf::merkle_backfill_failures
+            .select(diesel::dsl::count_star())
+            .filter(mbf::log_id.eq(&log.log_id))
+            .first(db).unwrap_or_display_err();
+        if failed > 0 {
+          return;
+        }
+        let mut frontier = load_frontier(db).unwrap_or_default();
+        let from = frontier.size();
+        if from >= latest.sth.tree_size {
+          return;
+        }
+        let to = std::cmp::min(from + opts.merkle_backfill_batch, latest.sth.tree_size);
+        let mut raw_leaves = Vec::new();
+        for e in get_raw_entries(&http_client, &parsed_url, from..to) {
+          match e {
+            Ok(e) => {
+              frontier.append(&crate::core::merkle::leaf_hash(&e.leaf_input));
+              if opts.store_raw_leaves {
+                raw_leaves.push(e);
+              }
+            },
+            Err(e) => {
+              log::warn!("{}: Merkle frontier backfill failed at leaf #{}: {}", &log.log_id, frontier.size(), e);
+              return;
+            }
+          }
+        }
+        assert_eq!(frontier.size(), to);
+        if to == latest.sth.tree_size && frontier.root() != latest.sth.root_hash {
+          use crate::schema::cert_fetch_errors::dsl as cfe;
+          let error_msg = format!("Root hash computed from all leaves ({}) does not match sth.", ctclient::utils::u8_to_hex(&frontier.root()));
+          log::warn!("{}: Merkle frontier backfill stopped: {}", &log.log_id, error_msg);
+          diesel::insert_into(mbf::merkle_backfill_failures)
+              .values((mbf::log_id.eq(&log.log_id), mbf::tree_size.eq(to as i64), mbf::error_msg.eq(&error_msg)))
+              .on_conflict_do_nothing()
+              .execute(db).unwrap_or_display_err();
+          let existing: i64 = cfe::cert_fetch_errors
+              .select(diesel::dsl::count_star())
+              .filter(cfe::log_id.eq(&log.log_id)
+                  .and(cfe::from_tree_size.eq(0))
+                  .and(cfe::to_tree_size.eq(to as i64))
+                  .and(cfe::error_msg.eq(&error_msg)))
+              .first(db).unwrap_or_display_err();
+          if existing == 0 {
+            diesel::insert_into(cfe::cert_fetch_errors)
+                .values(&crate::models::inserts::CertFetchError {
+                  log_id: log.log_id,
+                  from_tree_size: 0,
+                  to_tree_size: to as i64,
+                  error_msg: &error_msg
+                })
+                .execute(db).unwrap_or_display_err();
+            publish(LogEvent::FetchError { log_id: log.log_id, from_tree_size: 0, to_tree_size: to as i64, error: error_msg });
+          }
+          return;
+        }
+        if to < latest.sth.tree_size {
+          if let Err(e) = ctclient::internal::check_consistency_proof(
+            &http_client,
+            &parsed_url,
+            to,
+            latest.sth.tree_size,
+            &frontier.root(),
+            &latest.sth.root_hash
+          ) {
+            log::warn!("{}: Merkle frontier backfill of leaves #{}..{} not consistent with latest sth: {}", &log.log_id, from, to, e);
+            return;
+          }
+        }
+        db.transaction_rw_serializable::<(), diesel::result::Error, _>(|| {
+          crate::models::inserts::MerkleFrontier::upsert(db, log.log_id, &frontier)?;
+          store_raw_leaves(db, from, &raw_leaves)
+        }).unwrap_or_display_err();
+      };
+
       let mut last_fetched_sth: Option<FetchedSth> = None;
+      let mut current_sth_error = log.last_sth_error.clone();
       let mut current_db_hdl: Option<DBPooledConn> = Some(get_db!());
       if let Some(latest_sth_id) = log.latest_sth {
         let db = current_db_hdl.as_ref().unwrap();
@@ -233,6 +375,7 @@ pub fn init_thread(db_pool: DBPool, log: CtLog) -> Handle {
       }
 
       loop {
+        let latest_before = last_fetched_sth.as_ref().map(|s| s.stored_as_id);
         'a: {
           if current_db_hdl.is_none() {
             current_db_hdl = Some(get_db!());
@@ -244,20 +387,28 @@ pub fn init_thread(db_pool: DBPool, log: CtLog) -> Handle {
                   .filter(ctlogs_log_id.eq(&log.log_id))
                   .set(last_sth_error.eq(None::<String>))
                   .execute(db).unwrap_or_display_err();
+              if current_sth_error.take().is_some() {
+                publish(LogEvent::SthError { log_id: log.log_id, error: None });
+              }
               s
             },
             Err(e) => {
+              let error = format!("{}", e);
               diesel::update(ctlogs)
                   .filter(ctlogs_log_id.eq(&log.log_id))
-                  .set(last_sth_error.eq(format!("{}", e)))
+                  .set(last_sth_error.eq(&error))
                   .execute(db).unwrap_or_display_err();
+              if current_sth_error.as_ref() != Some(&error) {
+                publish(LogEvent::SthError { log_id: log.log_id, error: Some(error.clone()) });
+                current_sth_error = Some(error);
+              }
               current_db_hdl = None;
               break 'a;
             }
           };
           match last_fetched_sth {
             None => {
-              advance_latest_sth(db, &new_sth);
+              advance_latest_sth(db, &new_sth, None, 0, &[]);
               last_fetched_sth = Some(new_sth);
             },
             Some(ref old_sth) => {
@@ -276,19 +427,18 @@ pub fn init_thread(db_pool: DBPool, log: CtLog) -> Handle {
                     &new_sth.sth.root_hash
                   );
                   if let Err(e) = consistency_proof_parts_res {
-                    crate::models::inserts::ConsistencyCheckError::upsert(
-                      db,
-                      log.log_id,
-                      old_sth.stored_as_id,
-                      new_sth.stored_as_id,
-                      &format!("{}", e),
-                    ).unwrap_or_display_err();
+                    consistency_error(db, old_sth.stored_as_id, new_sth.stored_as_id, format!("{}", e));
                     break 'o;
                   }
                   use crate::schema::cert_fetch_errors::dsl as cfe;
                   let consistency_proof_parts = consistency_proof_parts_res.unwrap();
+                  // Only usable if it ends exactly where old_sth does, otherwise backfill is still
+                  // catching up.
+                  let frontier = load_frontier(db).filter(|f| f.size() == old_sth.sth.tree_size);
                   let mut leaf_hashs = Vec::with_capacity(usize::try_from(new_sth.sth.tree_size - old_sth.sth.tree_size).unwrap());
                   let mut has_error = false;
+                  let mut batch = Vec::with_capacity(opts.batch_size);
+                  let mut raw_leaves = Vec::new();
                   macro_rules! cfe_insert {
                     ($e:expr) => {
                       let ins = crate::models::inserts::CertFetchError {
@@ -300,6 +450,7 @@ pub fn init_thread(db_pool: DBPool, log: CtLog) -> Handle {
                       diesel::insert_into(cfe::cert_fetch_errors)
                           .values(&ins)
                           .execute(db).unwrap_or_display_err();
+                      publish(LogEvent::FetchError { log_id: log.log_id, from_tree_size: ins.from_tree_size, to_tree_size: ins.to_tree_size, error: ins.error_msg.to_owned() });
                       has_error = true;
                     };
                 }
@@ -308,6 +459,8 @@ pub fn init_thread(db_pool: DBPool, log: CtLog) -> Handle {
                       match $r {
                         Ok(k) => k,
                         Err(e) => {
+                          // Leaves fetched so far are still good.
+                          check_certs(db, &log, &mut batch, &opts);
                           cfe_insert!(e);
                           break 'o;
                         }
@@ -315,14 +468,25 @@ pub fn init_thread(db_pool: DBPool, log: CtLog) -> Handle {
                     };
                 }
                   let mut leid = old_sth.sth.tree_size;
-                  for le in ctclient::internal::get_entries(&http_client, &parsed_url, old_sth.sth.tree_size..new_sth.sth.tree_size) {
-                    let le = cfe_try!(le);
+                  for e in get_raw_entries(&http_client, &parsed_url, old_sth.sth.tree_size..new_sth.sth.tree_size) {
+                    let e = cfe_try!(e);
+                    let le = cfe_try!(e.parse());
                     leaf_hashs.push(le.hash);
-                    if let Err(e) = check_cert(db, log.log_id, &le, leid) {
-                      cfe_insert!(format!("Certificate error (leaf #{}={}): {}", leid, ctclient::utils::u8_to_hex(&le.hash), e));
+                    match le.verify_and_get_x509_chain() {
+                      Ok(chain) => batch.push(ParsedLeaf { leaf_index: leid, leaf_hash: Hash(le.hash), precert: e.is_precert(), chain }),
+                      Err(e) => {
+                        cfe_insert!(format!("Certificate error (leaf #{}={}): {}", leid, ctclient::utils::u8_to_hex(&le.hash), e));
+                      }
+                    }
+                    if opts.store_raw_leaves {
+                      raw_leaves.push(e);
+                    }
+                    if batch.len() >= opts.batch_size {
+                      check_certs(db, &log, &mut batch, &opts);
                     }
                     leid += 1;
                   }
+                  check_certs(db, &log, &mut batch, &opts);
                   assert_eq!(leaf_hashs.len(), (new_sth.sth.tree_size - old_sth.sth.tree_size) as usize);
                   for proof_part in consistency_proof_parts {
                     assert!(proof_part.subtree.0 >= old_sth.sth.tree_size);
@@ -333,8 +497,17 @@ pub fn init_thread(db_pool: DBPool, log: CtLog) -> Handle {
                       break 'o;
                     }
                   }
+                  let mut new_frontier = None;
+                  if let Some(mut f) = frontier {
+                    f.extend(&leaf_hashs);
+                    if f.root() != new_sth.sth.root_hash {
+                      cfe_insert!(format!("Root hash computed from fetched leaves ({}) does not match sth.", ctclient::utils::u8_to_hex(&f.root())));
+                      break 'o;
+                    }
+                    new_frontier = Some(f);
+                  }
                   if !has_error {
-                    advance_latest_sth(db, &new_sth);
+                    advance_latest_sth(db, &new_sth, new_frontier.as_ref(), old_sth.sth.tree_size, &raw_leaves);
                     diesel::delete(cfe::cert_fetch_errors)
                         .filter(
                           cfe::from_tree_size.eq(old_sth.sth.tree_size as i64)
@@ -346,14 +519,26 @@ pub fn init_thread(db_pool: DBPool, log: CtLog) -> Handle {
               }
             }
           }
+          if let Some(ref l) = last_fetched_sth {
+            merkle_backfill(db, l);
+            if opts.store_raw_leaves {
+              if let Err(e) = crate::core::mirror::store_nodes(db, &log.log_id) {
+                log::warn!("{}: storing merkle nodes failed: {}", &log.log_id, e);
+              }
+            }
+            if let Some(ref dir) = opts.tiles_dir {
+              if latest_before != Some(l.stored_as_id) {
+                if let Err(e) = crate::core::tiles::export_log(db, &log, dir) {
+                  log::warn!("{}: tile export failed: {}", &log.log_id, e);
+                }
+              }
+            }
+          }
         }
 
-        'o: for &it in &[false, true] {
-          let sleep_time = match it {
-            false => 250,
-            true => 4750
-          };
-          match recv.recv_timeout(Duration::from_millis(sleep_time)) {
+        let first_sleep = std::cmp::min(DB_RELEASE_DELAY, opts.poll_interval);
+        'o: for &sleep_time in &[first_sleep, opts.poll_interval - first_sleep] {
+          match recv.recv_timeout(sleep_time) {
             Err(mpsc::RecvTimeoutError::Timeout) => {
               current_db_hdl = None;
             }
@@ -379,21 +564,28 @@ pub fn init_thread(db_pool: DBPool, log: CtLog) -> Handle {
   Handle { jh: MaybeUninit::new(jh), sender }
 }
 
-fn check_cert(db: &DBPooledConn, logid: Hash, leaf: &Leaf, leaf_index: u64) -> Result<(), String> {
-  let chain = leaf.verify_and_get_x509_chain().map_err(|e| format!("{}", e))?;
-  db.build_transaction().read_committed().run(|| -> Result<(), diesel::result::Error> {
-    let fp = crate::models::inserts::insert_x509_and_chain(db, &chain)?;
-    use crate::schema::certificate_appears_in_leaf::dsl::certificate_appears_in_leaf;
-    diesel::insert_into(certificate_appears_in_leaf)
-        .values(crate::models::inserts::CertificateAppearsInLeaf {
-          leaf_hash: Hash(leaf.hash),
-          cert_fp: fp,
-          log_id: logid,
-          leaf_index: leaf_index as i64
-        })
-        .on_conflict_do_nothing()
-        .execute(db)?;
-    Ok(())
+/// Store the certificates of these leaves, and empty the batch.
+fn check_certs(db: &DBPooledConn, log: &CtLog, batch: &mut Vec<ParsedLeaf>, opts: &Options) {
+  if batch.is_empty() {
+    return;
+  }
+  let fps = db.build_transaction().read_committed().run(|| {
+    crate::models::inserts::insert_leaves(db, log.log_id, batch)
   }).unwrap_or_display_err();
-  Ok(())
+  if let Some(ref search) = opts.search {
+    // Certificates already stored, from another log or an earlier leaf, are already indexed.
+    for (leaf, (fp, new)) in batch.iter().zip(&fps) {
+      if *new {
+        search.upsert(fp, &leaf.chain[0]);
+      }
+    }
+  }
+  if let Some(ref events) = opts.events {
+    if events.has_subscribers(EventKind::Certificate) {
+      for (leaf, (fp, _)) in batch.iter().zip(fps) {
+        events.publish(Event::Certificate(CertificateEvent::new(log, leaf.leaf_index, leaf.precert, fp, &leaf.chain)));
+      }
+    }
+  }
+  batch.clear();
 }
@@ -0,0 +1,224 @@
+//! Offline re-check of the invariants described in `doc/update_process.md`.
+
+use std::collections::BTreeMap;
+
+use chrono::Utc;
+use ctclient::SignedTreeHead;
+use ctclient::internal::re_exports::{openssl, reqwest};
+use diesel::prelude::*;
+use serde::Serialize;
+
+use crate::core::config::Config;
+use crate::core::db::DBConn;
+use crate::models::{CtLog, Hash, Sth};
+
+const PAGE_SIZE: i64 = 10000;
+
+pub struct Options {
+  /// Only check this log.
+  pub log: Option<Hash>,
+  /// Fetch consistency proofs from the log for each link in the chain of checked sth.
+  pub fetch_proofs: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+  pub generated_at: i64,
+  pub total_violations: usize,
+  pub logs: Vec<LogReport>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LogReport {
+  pub log_id: Hash,
+  pub name: String,
+  pub latest_sth: Option<i64>,
+  pub nb_sths: usize,
+  pub nb_checked_sths: usize,
+  /// Number of consecutive pairs in the chain of checked sth (ordered by tree size).
+  pub nb_links: usize,
+  /// Number of those pairs for which a consistency proof was fetched and verified.
+  pub nb_links_verified: usize,
+  pub violations: Vec<Violation>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Violation {
+  /// `ctlogs.public_key` could not be parsed, so no signature could be checked.
+  InvalidPublicKey { error: String },
+  /// `ctlogs.latest_sth` points to a sth that does not exist or belongs to another log.
+  LatestSthMissing { sth_id: i64 },
+  /// The sth pointed to by `latest_sth` is not marked as `checked_consistent_with_latest`.
+  LatestSthNotChecked { sth_id: i64 },
+  InvalidSignature { sth_id: i64, error: String },
+  /// A sth is marked as checked but is larger than `latest_sth`.
+  CheckedSthLargerThanLatest { sth_id: i64, tree_size: i64, latest_tree_size: i64 },
+  /// Two checked sth have the same tree size but different root hashes.
+  ConflictingRootHash { sth_ids: [i64; 2], tree_size: i64 },
+  /// A sth is marked as checked while a consistency error from it to `latest_sth` is still recorded.
+  CheckedSthHasConsistencyError { sth_id: i64, to_sth_id: i64, error: String },
+  /// A sth is marked as checked but the log did not give a valid consistency proof between it and
+  /// the next sth in the chain. This includes network errors.
+  ConsistencyProofFailed { from_sth_id: i64, to_sth_id: i64, error: String },
+}
+
+pub fn verify(db: &DBConn, config: &Config, opts: &Options) -> Result<Report, Box<dyn std::error::Error>> {
+  let logs: Vec<CtLog> = {
+    use crate::schema::ctlogs::dsl::*;
+    match opts.log {
+      Some(id) => ctlogs.filter(log_id.eq(id)).load(db)?,
+      None => ctlogs.order_by(name.asc()).load(db)?
+    }
+  };
+  let http_client = if opts.fetch_proofs {
+    Some(config.http.build_client()?)
+  } else {
+    None
+  };
+  let mut reports = Vec::with_capacity(logs.len());
+  for log in logs {
+    reports.push(verify_log(db, &log, http_client.as_ref())?);
+  }
+  Ok(Report {
+    generated_at: Utc::now().timestamp_millis(),
+    total_violations: reports.iter().map(|r| r.violations.len()).sum(),
+    logs: reports
+  })
+}
+
+fn to_signed_tree_head(s: &Sth) -> SignedTreeHead {
+  SignedTreeHead {
+    tree_size: s.tree_size as u64,
+    timestamp: s.sth_timestamp as u64,
+    root_hash: s.tree_hash.0,
+    signature: s.signature.0.clone()
+  }
+}
+
+fn verify_log(db: &DBConn, log: &CtLog, http_client: Option<&reqwest::blocking::Client>) -> Result<LogReport, Box<dyn std::error::Error>> {
+  let mut violations = Vec::new();
+  let pub_key = match openssl::pkey::PKey::public_key_from_der(&log.public_key.0) {
+    Ok(k) => Some(k),
+    Err(e) => {
+      violations.push(Violation::InvalidPublicKey { error: format!("{}", e) });
+      None
+    }
+  };
+
+  // Check every signature, and keep the checked sth around for the chain checks.
+  let mut nb_sths = 0usize;
+  let mut checked: Vec<Sth> = Vec::new();
+  let mut latest: Option<Sth> = None;
+  let mut last_id = i64::MIN;
+  loop {
+    use crate::schema::sth::dsl::*;
+    let page: Vec<Sth> = sth
+        .filter(log_id.eq(log.log_id).and(id.gt(last_id)))
+        .order_by(id.asc())
+        .limit(PAGE_SIZE)
+        .load(db)?;
+    if page.is_empty() {
+      break;
+    }
+    last_id = page.last().unwrap().id;
+    for s in page {
+      nb_sths += 1;
+      if let Some(ref k) = pub_key {
+        if let Err(e) = to_signed_tree_head(&s).verify(k) {
+          violations.push(Violation::InvalidSignature { sth_id: s.id, error: format!("{}", e) });
+        }
+      }
+      if Some(s.id) == log.latest_sth {
+        latest = Some(s.clone());
+      }
+      if s.checked_consistent_with_latest {
+        checked.push(s);
+      }
+    }
+  }
+
+  if let Some(latest_id) = log.latest_sth {
+    match latest {
+      None => violations.push(Violation::LatestSthMissing { sth_id: latest_id }),
+      Some(ref l) if !l.checked_consistent_with_latest => violations.push(Violation::LatestSthNotChecked { sth_id: latest_id }),
+      _ => {}
+    }
+  }
+
+  if let Some(ref latest) = latest {
+    for s in &checked {
+      if s.tree_size > latest.tree_size {
+        violations.push(Violation::CheckedSthLargerThanLatest {
+          sth_id: s.id, tree_size: s.tree_size, latest_tree_size: latest.tree_size
+        });
+      }
+    }
+  }
+
+  {
+    use crate::schema::consistency_check_errors::dsl::*;
+    let errs: Vec<(i64, i64, String)> = consistency_check_errors
+        .select((from_sth_id, to_sth_id, last_check_error))
+        .filter(log_id.eq(log.log_id))
+        .load(db)?;
+    let checked_ids: std::collections::BTreeSet<i64> = checked.iter().map(|s| s.id).collect();
+    // Errors against an earlier latest_sth (e.g. a network error) are resolved once the sth is
+    // checked against a later one.
+    for (from, to, error) in errs {
+      if checked_ids.contains(&from) && Some(to) == log.latest_sth {
+        violations.push(Violation::CheckedSthHasConsistencyError { sth_id: from, to_sth_id: to, error });
+      }
+    }
+  }
+
+  // The chain: one representative per distinct tree size, ordered by tree size.
+  let mut chain: BTreeMap<i64, &Sth> = BTreeMap::new();
+  for s in &checked {
+    match chain.get(&s.tree_size) {
+      Some(other) if other.tree_hash != s.tree_hash => {
+        violations.push(Violation::ConflictingRootHash { sth_ids: [other.id, s.id], tree_size: s.tree_size });
+      },
+      Some(_) => {},
+      None => { chain.insert(s.tree_size, s); }
+    }
+  }
+  let chain: Vec<&Sth> = chain.into_iter().map(|(_, s)| s).collect();
+  let nb_links = chain.len().saturating_sub(1);
+  let mut nb_links_verified = 0usize;
+  if let Some(http_client) = http_client {
+    let url = reqwest::Url::parse(&log.endpoint_url)?;
+    for pair in chain.windows(2) {
+      let (from, to) = (pair[0], pair[1]);
+      if from.tree_size == 0 {
+        // Empty tree is consistent with everything, and logs refuse to give proofs for it.
+        nb_links_verified += 1;
+        continue;
+      }
+      match ctclient::internal::check_consistency_proof(
+        http_client,
+        &url,
+        from.tree_size as u64,
+        to.tree_size as u64,
+        &from.tree_hash.0,
+        &to.tree_hash.0
+      ) {
+        Ok(_) => nb_links_verified += 1,
+        Err(e) => violations.push(Violation::ConsistencyProofFailed {
+          from_sth_id: from.id, to_sth_id: to.id, error: format!("{}", e)
+        })
+      }
+    }
+  }
+
+  Ok(LogReport {
+    log_id: log.log_id,
+    name: log.name.clone(),
+    latest_sth: log.latest_sth,
+    nb_sths,
+    nb_checked_sths: checked.len(),
+    nb_links,
+    nb_links_verified,
+    violations
+  })
+}
@@ -4,32 +4,39 @@
 #[macro_use]
 extern crate diesel;
 #[macro_use]
+extern crate diesel_migrations;
+#[macro_use]
 extern crate rocket;
 #[macro_use]
 extern crate thiserror;
 
-use std::convert::TryFrom;
 use std::error::Error;
-use std::time::Duration;
+use std::sync::Arc;
 
-use diesel::expression::count::count_star;
-use diesel::prelude::*;
 use rocket::{Request, Response};
 use rocket::fairing::{Fairing, Info};
-use rocket::http::Header;
+use rocket::http::{Header, Method, Status};
+use structopt::StructOpt;
 
+use crate::core::config::Config;
 use crate::core::context::CtCrabContext;
 
 mod schema;
 mod models;
 mod core;
 mod api;
+mod cli;
 
 #[catch(500)]
 fn http500catcher() -> api::APIError {
   #[derive(Debug, Error)]
   #[error("Whoops! Looks like we messed up.")]
   struct E;
+  impl api::ErrorCode for E {
+    fn code(&self) -> std::borrow::Cow<'static, str> {
+      "internal_error".into()
+    }
+  }
   api::APIError(500, Box::new(E))
 }
 
@@ -38,6 +45,11 @@ fn http404catcher() -> api::APIError {
   #[derive(Debug, Error)]
   #[error("Page not found")]
   struct PNF;
+  impl api::ErrorCode for PNF {
+    fn code(&self) -> std::borrow::Cow<'static, str> {
+      "not_found".into()
+    }
+  }
   api::APIError(404, Box::new(PNF))
 }
 
@@ -46,36 +58,45 @@ struct AccessControlFairing;
 impl Fairing for AccessControlFairing {
   fn info(&self) -> Info {
     Info {
-      name: "Access control allow origin: *",
+      name: "CORS for any origin",
       kind: rocket::fairing::Kind::Response
     }
   }
 
-  fn on_response(&self, _request: &Request, response: &mut Response) {
+  fn on_response(&self, request: &Request, response: &mut Response) {
     response.set_header(Header::new("Access-Control-Allow-Origin", "*"));
+    response.set_header(Header::new("Access-Control-Expose-Headers", "Retry-After"));
+    // Preflight requests, which sending an API key in a header needs.
+    if request.method() == Method::Options {
+      response.set_status(Status::NoContent);
+      response.set_sized_body(std::io::Cursor::new(Vec::new()));
+      response.set_header(Header::new("Access-Control-Allow-Methods", "GET"));
+      response.set_header(Header::new("Access-Control-Allow-Headers", "Authorization, X-API-Key"));
+      response.set_header(Header::new("Access-Control-Max-Age", "86400"));
+    }
   }
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
-  let ctx = CtCrabContext::new();
-  {
-    use crate::schema::ctlogs::dsl::*;
-    let db = ctx.db()?;
-    let count: usize = TryFrom::<i64>::try_from(ctlogs.select(count_star()).filter(monitoring.eq(true)).first(&db)
-        .map_err(Box::new)?).unwrap();
-    if count == 0 {
-      #[derive(Debug, Error)]
-      #[error("Failed to initialize ctlogs table: {0}")]
-      struct E(#[source] core::initialise_ctlogs_table::E);
-      core::initialise_ctlogs_table::initialise_or_update_ctlogs_table(&db)
-          .map_err(|e| Box::new(E(e)))?;
-      std::thread::sleep(Duration::from_millis(200)); // to give db time to sync changes
-    }
+fn rocket_config(config: &Config) -> Result<rocket::Config, Box<dyn Error>> {
+  let mut builder = rocket::Config::build(rocket::config::Environment::active()?)
+      .address(config.api.address.clone())
+      .port(config.api.port);
+  if let Some(workers) = config.api.workers {
+    builder = builder.workers(workers);
   }
-  ctx.init_update_threads();
-  Err(Box::new(rocket::ignite()
+  Ok(builder.finalize()?)
+}
+
+fn build_rocket(rocket_config: rocket::Config, ctx: CtCrabContext, access: Arc<api::Access>) -> rocket::Rocket {
+  rocket::custom(rocket_config)
       .mount("/", api::api_routes())
       .register(catchers![http500catcher, http404catcher])
+      .register(api::auth_catchers())
       .attach(AccessControlFairing)
-      .manage(ctx).launch()))
+      .manage(access)
+      .manage(ctx)
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+  cli::run(cli::Opt::from_args())
 }
@@ -13,8 +13,11 @@ use serde::export::{Formatter, TryFrom};
 use serde::ser::Serializer;
 use serde::Serialize;
 use std::str::FromStr;
-use rocket::request::FromParam;
+use rocket::request::{FromFormValue, FromParam};
 use rocket::http::RawStr;
+use schemars::JsonSchema;
+use schemars::gen::SchemaGenerator;
+use schemars::schema::{InstanceType, Schema, SchemaObject, StringValidation};
 
 macro_rules! impl_sql_binary_type {
   ($type_name:ident) => {
@@ -42,6 +45,21 @@ impl Serialize for Hash {
     serializer.serialize_str(&u8_to_hex(&self.0))
   }
 }
+impl JsonSchema for Hash {
+  fn is_referenceable() -> bool {
+    false
+  }
+  fn schema_name() -> String {
+    "Hash".to_owned()
+  }
+  fn json_schema(_: &mut SchemaGenerator) -> Schema {
+    SchemaObject {
+      instance_type: Some(InstanceType::String.into()),
+      string: Some(Box::new(StringValidation { pattern: Some("^[0-9a-f]{64}$".to_owned()), ..Default::default() })),
+      ..Default::default()
+    }.into()
+  }
+}
 #[derive(Debug, Error)]
 pub enum HashFromStrError {
   #[error("Expected length of 64 (hex of 32 byte hash), got {0}.")]
@@ -115,6 +133,13 @@ impl<'a> FromParam<'a> for Hash {
     s.try_into()
   }
 }
+impl<'v> FromFormValue<'v> for Hash {
+  type Error = HashFromStrError;
+
+  fn from_form_value(form_value: &'v RawStr) -> Result<Self, Self::Error> {
+    form_value.as_bytes().try_into()
+  }
+}
 
 impl Display for Hash {
   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -132,6 +157,21 @@ impl Serialize for BytesWithBase64Repr {
     serializer.serialize_str(&base64::encode(&self.0))
   }
 }
+impl JsonSchema for BytesWithBase64Repr {
+  fn is_referenceable() -> bool {
+    false
+  }
+  fn schema_name() -> String {
+    "BytesWithBase64Repr".to_owned()
+  }
+  fn json_schema(_: &mut SchemaGenerator) -> Schema {
+    SchemaObject {
+      instance_type: Some(InstanceType::String.into()),
+      format: Some("byte".to_owned()),
+      ..Default::default()
+    }.into()
+  }
+}
 impl<'de> Deserialize<'de> for BytesWithBase64Repr {
   fn deserialize<D>(deserializer: D) -> Result<Self, <D as Deserializer<'de>>::Error> where
       D: Deserializer<'de> {
@@ -1,13 +1,17 @@
-use std::collections::HashSet;
+use std::collections::{BTreeMap, BTreeSet};
 use std::convert::TryInto;
-use std::iter::FromIterator;
 
+use chrono::{DateTime, TimeZone, Utc};
 use ctclient::internal::re_exports::openssl;
 use diesel::expression::functions::date_and_time::now;
 use diesel::prelude::*;
+use openssl::asn1::{Asn1Time, Asn1TimeRef};
 use openssl::hash::MessageDigest;
+use openssl::sha::Sha256;
 use openssl::x509::X509;
 
+use crate::core::certinfo::{cert_names, issuer_info, CertName, IssuerInfo};
+
 use super::*;
 
 #[derive(Insertable, Debug)]
@@ -17,7 +21,8 @@ pub struct CtLog<'a> {
   pub endpoint_url: &'a str,
   pub name: &'a str,
   pub public_key: &'a [u8],
-  pub monitoring: bool
+  pub monitoring: bool,
+  pub operator: Option<&'a str>
 }
 
 #[derive(Insertable, Debug)]
@@ -55,6 +60,67 @@ impl<'a> ConsistencyCheckError<'a> {
   }
 }
 
+#[derive(Insertable, Debug)]
+#[table_name = "merkle_frontiers"]
+pub struct MerkleFrontier<'a> {
+  log_id: Hash,
+  tree_size: i64,
+  subtree_hashes: &'a [Vec<u8>]
+}
+
+impl<'a> MerkleFrontier<'a> {
+  pub fn upsert<DB: diesel::Connection<Backend = diesel::pg::Pg>>(db: &DB, log_id: Hash, range: &CompactRange) -> Result<(), diesel::result::Error> {
+    use crate::schema::merkle_frontiers::dsl;
+    let subtree_hashes = range.hashes().iter().map(|h| h.to_vec()).collect::<Vec<_>>();
+    let ins = MerkleFrontier {
+      log_id,
+      tree_size: range.size() as i64,
+      subtree_hashes: &subtree_hashes
+    };
+    diesel::insert_into(dsl::merkle_frontiers)
+        .values(&ins)
+        .on_conflict(dsl::log_id)
+        .do_update()
+        .set((dsl::tree_size.eq(ins.tree_size), dsl::subtree_hashes.eq(ins.subtree_hashes)))
+        .execute(db).map(|_| {})
+  }
+}
+
+#[derive(Insertable, Debug)]
+#[table_name = "raw_leaves"]
+pub struct RawLeaf<'a> {
+  pub log_id: Hash,
+  pub leaf_index: i64,
+  pub leaf_hash: Hash,
+  pub leaf_input: &'a [u8],
+  pub extra_data: &'a [u8]
+}
+
+impl<'a> RawLeaf<'a> {
+  /// Compress and store a verified entry, replacing any stored one.
+  pub fn insert<DB: diesel::Connection<Backend = diesel::pg::Pg>>(db: &DB, log_id: Hash, leaf_index: u64, entry: &crate::core::raw_entries::RawEntry) -> Result<(), diesel::result::Error> {
+    use crate::core::raw_entries::compress;
+    use crate::schema::raw_leaves::dsl;
+    use diesel::upsert::excluded;
+    diesel::insert_into(dsl::raw_leaves)
+        .values(RawLeaf {
+          log_id,
+          leaf_index: leaf_index as i64,
+          leaf_hash: Hash(crate::core::merkle::leaf_hash(&entry.leaf_input)),
+          leaf_input: &compress(&entry.leaf_input),
+          extra_data: &compress(&entry.extra_data)
+        })
+        .on_conflict((dsl::log_id, dsl::leaf_index))
+        .do_update()
+        .set((
+          dsl::leaf_hash.eq(excluded(dsl::leaf_hash)),
+          dsl::leaf_input.eq(excluded(dsl::leaf_input)),
+          dsl::extra_data.eq(excluded(dsl::extra_data))
+        ))
+        .execute(db).map(|_| {})
+  }
+}
+
 #[derive(Insertable, Debug)]
 #[table_name = "cert_fetch_errors"]
 pub struct CertFetchError<'a> {
@@ -68,60 +134,209 @@ pub struct CertFetchError<'a> {
 #[table_name = "certificates"]
 struct Certificate<'a> {
   pub fingerprint: Hash,
-  pub x509: &'a [u8]
+  pub x509: &'a [u8],
+  pub not_before: Option<DateTime<Utc>>,
+  pub not_after: Option<DateTime<Utc>>,
+  pub issuer_id: Option<i64>,
+  /// That of the `CertificateFingerprint`, which decides the partition.
+  pub ingested_at: DateTime<Utc>,
+  /// Until it appears as the end-entity certificate of a leaf.
+  pub chain_only: bool
 }
 
-/// **This function must be called within a transaction**
-/// # Return
-///
-/// sha256 fingerprint
-pub fn insert_x509_and_chain<DB>(db: &DB, x509_chain: &[X509]) -> Result<Hash, diesel::result::Error>
+/// Inserted first, since fingerprints are only unique there.
+#[derive(Insertable, Debug)]
+#[table_name = "certificate_fingerprints"]
+struct CertificateFingerprint {
+  pub fingerprint: Hash
+}
+
+pub(crate) fn asn1_time_to_datetime(t: &Asn1TimeRef) -> Option<DateTime<Utc>> {
+  let diff = Asn1Time::from_unix(0).ok()?.diff(t).ok()?;
+  Utc.timestamp_opt(diff.days as i64 * 86400 + diff.secs as i64, 0).single()
+}
+
+#[derive(Insertable, Debug)]
+#[table_name = "issuers"]
+pub struct Issuer<'a> {
+  pub issuer_dn: &'a str,
+  pub authority_key_id: &'a [u8],
+  pub spki_sha256: &'a [u8]
+}
+
+impl<'a> Issuer<'a> {
+  /// Id of the issuer, inserting it if it's new.
+  pub fn get_or_insert<DB: diesel::Connection<Backend = diesel::pg::Pg>>(db: &DB, info: &IssuerInfo) -> Result<i64, diesel::result::Error> {
+    use crate::schema::issuers::dsl::*;
+    let find = || issuers
+        .select(id)
+        .filter(issuer_dn.eq(&info.issuer_dn)
+            .and(authority_key_id.eq(&info.authority_key_id))
+            .and(spki_sha256.eq(&info.spki_sha256)))
+        .first::<i64>(db).optional();
+    if let Some(found) = find()? {
+      return Ok(found);
+    }
+    diesel::insert_into(issuers)
+        .values(Issuer {
+          issuer_dn: &info.issuer_dn,
+          authority_key_id: &info.authority_key_id,
+          spki_sha256: &info.spki_sha256
+        })
+        .on_conflict_do_nothing()
+        .execute(db)?;
+    find()?.ok_or(diesel::result::Error::NotFound)
+  }
+
+  /// Add to `cert_count` of each issuer, which counts its certificates that are not chain-only.
+  /// Issuers are updated in order of id, so that concurrent transactions can't deadlock.
+  pub fn add_cert_counts<DB: diesel::Connection<Backend = diesel::pg::Pg>>(db: &DB, counts: &BTreeMap<i64, i64>) -> Result<(), diesel::result::Error> {
+    use crate::schema::issuers::dsl::*;
+    for (issuer, n) in counts {
+      if *n != 0 {
+        diesel::update(issuers.filter(id.eq(issuer)))
+            .set(cert_count.eq(cert_count + n))
+            .execute(db)?;
+      }
+    }
+    Ok(())
+  }
+}
+
+fn fingerprint(cert: &X509) -> Hash {
+  let fp = cert.digest(MessageDigest::sha256()).unwrap();
+  Hash(fp.as_ref().try_into().unwrap())
+}
+
+/// Insert a certificate if it's new. Names are only indexed for end-entity certificates.
+fn insert_certificate<DB>(db: &DB, cert: &X509, issuer: Option<&X509>, index_names: bool) -> Result<Hash, diesel::result::Error>
   where DB: diesel::Connection<Backend=diesel::pg::Pg> {
-  let fp = x509_chain[0].digest(MessageDigest::sha256()).unwrap();
-  let fp = Hash(fp.as_ref().try_into().unwrap());
-  let der_chain = x509_chain.iter().map(|x| x.to_der().unwrap()).collect::<Vec<_>>();
-  use crate::schema::certificates::dsl as c_dsl;
+  let fp = fingerprint(cert);
+  use crate::schema::certificate_fingerprints::dsl as f_dsl;
+  let ingested_at = diesel::insert_into(f_dsl::certificate_fingerprints)
+      .values(CertificateFingerprint { fingerprint: fp })
+      .on_conflict_do_nothing()
+      .returning(f_dsl::ingested_at)
+      .get_result::<DateTime<Utc>>(db).optional()?;
+  let ingested_at = match ingested_at {
+    Some(t) => t,
+    None => {
+      // already existed, but maybe only as a parent
+      if index_names {
+        use crate::schema::certificates::dsl as c;
+        let first_leaf = diesel::update(c::certificates.filter(c::fingerprint.eq(fp).and(c::chain_only)))
+            .set(c::chain_only.eq(false))
+            .returning((c::ingested_at, c::issuer_id))
+            .get_result::<(DateTime<Utc>, Option<i64>)>(db).optional()?;
+        if let Some((t, issuer_id)) = first_leaf {
+          if let Some(issuer_id) = issuer_id {
+            Issuer::add_cert_counts(db, &std::iter::once((issuer_id, 1)).collect())?;
+          }
+          insert_names(db, &fp, t, &cert_names(cert).unwrap())?;
+        }
+      }
+      return Ok(fp);
+    }
+  };
+  let issuer_id = Issuer::get_or_insert(db, &issuer_info(cert, issuer.map(|x| x.as_ref())))?;
   let ins = Certificate {
     fingerprint: fp,
-    x509: &der_chain[0]
+    x509: &cert.to_der().unwrap(),
+    not_before: asn1_time_to_datetime(cert.not_before()),
+    not_after: asn1_time_to_datetime(cert.not_after()),
+    issuer_id: Some(issuer_id),
+    ingested_at,
+    chain_only: !index_names
   };
-  let already_existed = diesel::insert_into(c_dsl::certificates)
+  use crate::schema::certificates::dsl::certificates;
+  diesel::insert_into(certificates)
       .values(ins)
-      .on_conflict_do_nothing()
-      .execute(db)? == 0;
-  if already_existed {
-    return Ok(fp);
-  }
-  use crate::schema::certificate_dns_names::dsl as d_dsl;
-  let dns_names = ctclient::certutils::get_dns_names(&x509_chain[0]).unwrap();
-  let dns_names = HashSet::<String>::from_iter(dns_names).into_iter().collect::<Vec<String>>();
-  let d_vals = dns_names.into_iter()
-      .map(|x| (d_dsl::dns_name.eq(x), d_dsl::cert_fp.eq(&fp)))
-      .collect::<Vec<_>>();
-  diesel::insert_into(d_dsl::certificate_dns_names)
-      .values(&d_vals)
       .execute(db)?;
-  insert_rest_of_the_chain(db, &fp, &der_chain[1..])?;
+  if index_names {
+    Issuer::add_cert_counts(db, &std::iter::once((issuer_id, 1)).collect())?;
+    insert_names(db, &fp, ingested_at, &cert_names(cert).unwrap())?;
+  }
   Ok(fp)
 }
 
+/// **This function must be called within a transaction**
+///
+/// Every certificate of the chain is stored once in `certificates`, and the chain itself as the
+/// list of fingerprints of the parents.
+///
+/// # Return
+///
+/// sha256 fingerprint of the end-entity certificate, and the chain id.
+pub fn insert_x509_and_chain<DB>(db: &DB, x509_chain: &[X509]) -> Result<(Hash, i64), diesel::result::Error>
+  where DB: diesel::Connection<Backend=diesel::pg::Pg> {
+  let mut fps = Vec::with_capacity(x509_chain.len());
+  for (i, cert) in x509_chain.iter().enumerate() {
+    fps.push(insert_certificate(db, cert, x509_chain.get(i + 1), i == 0)?);
+  }
+  let chain_id = CertificateChain::get_or_insert(db, &fps[1..])?;
+  Ok((fps[0], chain_id))
+}
+
 #[derive(Insertable, Debug)]
-#[table_name = "certificate_chain"]
-struct CertificateChain<'a> {
-  pub certificate_fingerprint: &'a [u8],
-  pub chain: &'a [Vec<u8>]
+#[table_name = "certificate_names"]
+struct CertificateName<'a> {
+  pub cert_fp: Hash,
+  pub name_type: &'a str,
+  pub name: &'a str,
+  pub ip: Option<&'a [u8]>,
+  pub ingested_at: DateTime<Utc>
 }
 
-fn insert_rest_of_the_chain<DB>(db: &DB, fp: &Hash, rest_of_the_chain: &[Vec<u8>]) -> diesel::result::QueryResult<()>
+/// Names already indexed for this certificate are skipped. `ingested_at` must be that of the
+/// certificate.
+pub fn insert_names<DB>(db: &DB, fp: &Hash, ingested_at: DateTime<Utc>, names: &[CertName]) -> diesel::result::QueryResult<usize>
   where DB: diesel::Connection<Backend = diesel::pg::Pg> {
-  let ins = CertificateChain {
-    certificate_fingerprint: &fp.0,
-    chain: rest_of_the_chain
-  };
-  use crate::schema::certificate_chain::dsl::certificate_chain;
-  diesel::insert_into(certificate_chain)
-      .values(ins)
-      .execute(db).map(|_| ())
+  let vals = names.iter().map(|n| CertificateName {
+    cert_fp: *fp,
+    name_type: n.name_type.as_str(),
+    name: &n.name,
+    ip: n.ip.as_deref(),
+    ingested_at
+  }).collect::<Vec<_>>();
+  use crate::schema::certificate_names::dsl::certificate_names;
+  diesel::insert_into(certificate_names)
+      .values(&vals)
+      .on_conflict_do_nothing()
+      .execute(db)
+}
+
+#[derive(Insertable, Debug)]
+#[table_name = "certificate_chains"]
+struct CertificateChain<'a> {
+  pub chain_hash: Hash,
+  pub fingerprints: &'a [Vec<u8>]
+}
+
+fn chain_hash(parents: &[Hash]) -> Hash {
+  let mut h = Sha256::new();
+  for fp in parents {
+    h.update(&fp.0);
+  }
+  Hash(h.finish())
+}
+
+impl<'a> CertificateChain<'a> {
+  /// Id of the chain made of these parent certificates, inserting it if it's new.
+  fn get_or_insert<DB>(db: &DB, parents: &[Hash]) -> diesel::result::QueryResult<i64>
+    where DB: diesel::Connection<Backend = diesel::pg::Pg> {
+    let hash = chain_hash(parents);
+    use crate::schema::certificate_chains::dsl::*;
+    let find = || certificate_chains.select(id).filter(chain_hash.eq(hash)).first::<i64>(db).optional();
+    if let Some(found) = find()? {
+      return Ok(found);
+    }
+    let fps = parents.iter().map(|fp| fp.0.to_vec()).collect::<Vec<_>>();
+    diesel::insert_into(certificate_chains)
+        .values(CertificateChain { chain_hash: hash, fingerprints: &fps })
+        .on_conflict_do_nothing()
+        .execute(db)?;
+    find()?.ok_or(diesel::result::Error::NotFound)
+  }
 }
 
 #[derive(Insertable, Debug)]
@@ -130,5 +345,200 @@ pub struct CertificateAppearsInLeaf {
   pub leaf_hash: Hash,
   pub cert_fp: Hash,
   pub log_id: Hash,
-  pub leaf_index: i64
+  pub leaf_index: i64,
+  pub chain_id: Option<i64>,
+  /// From `reserve_ingest_seqs`.
+  pub ingest_seq: i64
+}
+
+/// Take `n` numbers for `CertificateAppearsInLeaf::ingest_seq`, returning the first. This locks
+/// the counter until the transaction ends, so it should be the last thing done before inserting
+/// the appearances and committing.
+pub fn reserve_ingest_seqs<DB>(db: &DB, n: i64) -> Result<i64, diesel::result::Error>
+  where DB: diesel::Connection<Backend = diesel::pg::Pg> {
+  use crate::schema::ingest_counter::dsl::*;
+  let last: i64 = diesel::update(ingest_counter)
+      .set(last_seq.eq(last_seq + n))
+      .returning(last_seq)
+      .get_result(db)?;
+  Ok(last - n + 1)
+}
+
+/// A leaf whose certificate chain has been verified, ready to be stored.
+pub struct ParsedLeaf {
+  pub leaf_index: u64,
+  pub leaf_hash: Hash,
+  pub precert: bool,
+  pub chain: Vec<X509>,
+}
+
+/// Rows per statement, to stay well below the limit of 65535 bind parameters.
+const MAX_ROWS_PER_STATEMENT: usize = 5000;
+
+/// **This function must be called within a transaction**
+///
+/// Same as `insert_x509_and_chain` followed by inserting a `CertificateAppearsInLeaf` for each
+/// leaf, but with a handful of multi-row statements per table for the whole batch. (Diesel can't
+/// issue `COPY`, and multi-row inserts already remove most of the round trips.)
+///
+/// # Return
+///
+/// sha256 fingerprint of the end-entity certificate of each leaf, and whether this call stored
+/// it or first saw it as a leaf (only for the first leaf with it).
+pub fn insert_leaves<DB>(db: &DB, log_id: Hash, leaves: &[ParsedLeaf]) -> Result<Vec<(Hash, bool)>, diesel::result::Error>
+  where DB: diesel::Connection<Backend = diesel::pg::Pg> {
+  // Every distinct certificate, with its issuer in the first chain it appears in, and whether it
+  // is an end-entity certificate anywhere.
+  let mut certs: BTreeMap<Hash, (&X509, Option<&X509>, bool)> = BTreeMap::new();
+  let mut leaf_chains = Vec::with_capacity(leaves.len());
+  for leaf in leaves {
+    let fps = leaf.chain.iter().map(fingerprint).collect::<Vec<_>>();
+    for (i, cert) in leaf.chain.iter().enumerate() {
+      certs.entry(fps[i]).or_insert((cert, leaf.chain.get(i + 1), false)).2 |= i == 0;
+    }
+    leaf_chains.push(fps);
+  }
+
+  use crate::schema::certificate_fingerprints::dsl as f_dsl;
+  let all_fps = certs.keys().copied().collect::<Vec<_>>();
+  let mut existing = BTreeSet::new();
+  for chunk in all_fps.chunks(MAX_ROWS_PER_STATEMENT) {
+    existing.extend(f_dsl::certificate_fingerprints.select(f_dsl::fingerprint).filter(f_dsl::fingerprint.eq_any(chunk)).load::<Hash>(db)?);
+  }
+  let new_fps = all_fps.into_iter().filter(|fp| !existing.contains(fp)).map(|fp| CertificateFingerprint { fingerprint: fp }).collect::<Vec<_>>();
+  // Another update thread may have inserted some of these since, so only certificates whose
+  // fingerprint this statement actually inserted are stored, as in insert_certificate.
+  let mut inserted: Vec<(Hash, DateTime<Utc>)> = Vec::with_capacity(new_fps.len());
+  for chunk in new_fps.chunks(MAX_ROWS_PER_STATEMENT) {
+    inserted.extend(diesel::insert_into(f_dsl::certificate_fingerprints)
+        .values(chunk)
+        .on_conflict_do_nothing()
+        .returning((f_dsl::fingerprint, f_dsl::ingested_at))
+        .get_results::<(Hash, DateTime<Utc>)>(db)?);
+  }
+  let mut issuer_ids: BTreeMap<IssuerInfo, i64> = BTreeMap::new();
+  let mut cert_counts: BTreeMap<i64, i64> = BTreeMap::new();
+  let mut new_certs = Vec::with_capacity(inserted.len());
+  for (fp, ingested_at) in &inserted {
+    let (cert, issuer, end_entity) = certs[fp];
+    let info = issuer_info(cert, issuer.map(|x| x.as_ref()));
+    let issuer_id = match issuer_ids.get(&info) {
+      Some(id) => *id,
+      None => {
+        let id = Issuer::get_or_insert(db, &info)?;
+        issuer_ids.insert(info, id);
+        id
+      }
+    };
+    if end_entity {
+      *cert_counts.entry(issuer_id).or_default() += 1;
+    }
+    new_certs.push((*fp, cert.to_der().unwrap(), issuer_id, *ingested_at));
+  }
+  for chunk in new_certs.chunks(MAX_ROWS_PER_STATEMENT) {
+    let rows = chunk.iter().map(|(fp, der, issuer_id, ingested_at)| {
+      let cert = certs[fp].0;
+      Certificate {
+        fingerprint: *fp,
+        x509: der,
+        not_before: asn1_time_to_datetime(cert.not_before()),
+        not_after: asn1_time_to_datetime(cert.not_after()),
+        issuer_id: Some(*issuer_id),
+        ingested_at: *ingested_at,
+        chain_only: !certs[fp].2
+      }
+    }).collect::<Vec<_>>();
+    use crate::schema::certificates::dsl::certificates;
+    diesel::insert_into(certificates)
+        .values(&rows)
+        .execute(db)?;
+  }
+  // Certificates stored earlier only as a parent, now the end-entity certificate of a leaf, are
+  // handled as if new from here on.
+  let stored: BTreeSet<Hash> = inserted.iter().map(|(fp, _)| *fp).collect();
+  let was_chain_only = certs.iter()
+      .filter(|(fp, (_, _, end_entity))| *end_entity && !stored.contains(fp))
+      .map(|(fp, _)| *fp)
+      .collect::<Vec<_>>();
+  for chunk in was_chain_only.chunks(MAX_ROWS_PER_STATEMENT) {
+    use crate::schema::certificates::dsl as c;
+    let now_leaves = diesel::update(c::certificates.filter(c::fingerprint.eq_any(chunk).and(c::chain_only)))
+        .set(c::chain_only.eq(false))
+        .returning((c::fingerprint, c::ingested_at, c::issuer_id))
+        .get_results::<(Hash, DateTime<Utc>, Option<i64>)>(db)?;
+    for (fp, ingested_at, issuer_id) in now_leaves {
+      if let Some(issuer_id) = issuer_id {
+        *cert_counts.entry(issuer_id).or_default() += 1;
+      }
+      inserted.push((fp, ingested_at));
+    }
+  }
+  Issuer::add_cert_counts(db, &cert_counts)?;
+  let mut names = Vec::new();
+  for (fp, ingested_at) in &inserted {
+    let (cert, _, end_entity) = certs[fp];
+    if end_entity {
+      names.extend(cert_names(cert).unwrap().into_iter().map(|n| (*fp, n, *ingested_at)));
+    }
+  }
+  for chunk in names.chunks(MAX_ROWS_PER_STATEMENT) {
+    let rows = chunk.iter().map(|(fp, n, ingested_at)| CertificateName {
+      cert_fp: *fp,
+      name_type: n.name_type.as_str(),
+      name: &n.name,
+      ip: n.ip.as_deref(),
+      ingested_at: *ingested_at
+    }).collect::<Vec<_>>();
+    use crate::schema::certificate_names::dsl::certificate_names;
+    diesel::insert_into(certificate_names)
+        .values(&rows)
+        .on_conflict_do_nothing()
+        .execute(db)?;
+  }
+
+  use crate::schema::certificate_chains::dsl as ch_dsl;
+  let mut chains: BTreeMap<Hash, &[Hash]> = BTreeMap::new();
+  for fps in &leaf_chains {
+    chains.insert(chain_hash(&fps[1..]), &fps[1..]);
+  }
+  let hashes = chains.keys().copied().collect::<Vec<_>>();
+  let load_ids = |hashes: &[Hash]| -> Result<Vec<(Hash, i64)>, diesel::result::Error> {
+    let mut ids = Vec::with_capacity(hashes.len());
+    for chunk in hashes.chunks(MAX_ROWS_PER_STATEMENT) {
+      ids.extend(ch_dsl::certificate_chains.select((ch_dsl::chain_hash, ch_dsl::id)).filter(ch_dsl::chain_hash.eq_any(chunk)).load::<(Hash, i64)>(db)?);
+    }
+    Ok(ids)
+  };
+  let mut chain_ids: BTreeMap<Hash, i64> = load_ids(&hashes)?.into_iter().collect();
+  let missing = hashes.into_iter().filter(|h| !chain_ids.contains_key(h)).collect::<Vec<_>>();
+  if !missing.is_empty() {
+    let fps = missing.iter().map(|h| chains[h].iter().map(|fp| fp.0.to_vec()).collect::<Vec<_>>()).collect::<Vec<_>>();
+    for (hs, fps) in missing.chunks(MAX_ROWS_PER_STATEMENT).zip(fps.chunks(MAX_ROWS_PER_STATEMENT)) {
+      let rows = hs.iter().zip(fps).map(|(h, fps)| CertificateChain { chain_hash: *h, fingerprints: fps }).collect::<Vec<_>>();
+      diesel::insert_into(ch_dsl::certificate_chains)
+          .values(&rows)
+          .on_conflict_do_nothing()
+          .execute(db)?;
+    }
+    chain_ids.extend(load_ids(&missing)?);
+  }
+
+  let first_seq = reserve_ingest_seqs(db, leaves.len() as i64)?;
+  let appearances = leaves.iter().zip(&leaf_chains).zip(first_seq..).map(|((leaf, fps), ingest_seq)| CertificateAppearsInLeaf {
+    leaf_hash: leaf.leaf_hash,
+    cert_fp: fps[0],
+    log_id,
+    leaf_index: leaf.leaf_index as i64,
+    chain_id: chain_ids.get(&chain_hash(&fps[1..])).copied(),
+    ingest_seq
+  }).collect::<Vec<_>>();
+  for chunk in appearances.chunks(MAX_ROWS_PER_STATEMENT) {
+    use crate::schema::certificate_appears_in_leaf::dsl::certificate_appears_in_leaf;
+    diesel::insert_into(certificate_appears_in_leaf)
+        .values(chunk)
+        .on_conflict_do_nothing()
+        .execute(db)?;
+  }
+  let mut new: BTreeSet<Hash> = inserted.iter().map(|(fp, _)| *fp).collect();
+  Ok(leaf_chains.into_iter().map(|fps| (fps[0], new.remove(&fps[0]))).collect())
 }
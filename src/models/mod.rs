@@ -1,17 +1,19 @@
 use diesel::Connection;
 use diesel::pg::Pg;
 
+use schemars::JsonSchema;
 use serde::{Serialize, Serializer};
 
 pub use bytea_t::*;
 
+use crate::core::merkle::CompactRange;
 use crate::schema::*;
 use chrono::{DateTime, Utc};
 
 mod bytea_t;
 pub mod inserts;
 
-#[derive(Queryable, QueryableByName, Debug, Serialize)]
+#[derive(Queryable, QueryableByName, Debug, Serialize, JsonSchema)]
 #[table_name = "ctlogs"]
 pub struct CtLog {
   pub log_id: Hash,
@@ -20,7 +22,8 @@ pub struct CtLog {
   pub public_key: BytesWithBase64Repr,
   pub monitoring: bool,
   pub latest_sth: Option<i64>,
-  pub last_sth_error: Option<String>
+  pub last_sth_error: Option<String>,
+  pub operator: Option<String>
 }
 
 impl CtLog {
@@ -36,7 +39,7 @@ impl CtLog {
   }
 }
 
-#[derive(Queryable, QueryableByName, Debug, Serialize)]
+#[derive(Queryable, QueryableByName, Debug, Clone, Serialize, JsonSchema)]
 #[table_name = "sth"]
 pub struct Sth {
   pub id: i64,
@@ -45,9 +48,33 @@ pub struct Sth {
   pub tree_size: i64,
   pub sth_timestamp: i64,
   #[serde(serialize_with = "serialize_datetime")]
+  #[schemars(with = "i64")]
   pub received_time: DateTime<Utc>,
   pub signature: BytesWithBase64Repr,
-  pub checked_consistent_with_latest: bool
+  pub checked_consistent_with_latest: bool,
+  pub was_latest: bool
+}
+
+#[derive(Queryable, Debug)]
+pub struct MerkleFrontier {
+  pub log_id: Hash,
+  pub tree_size: i64,
+  pub subtree_hashes: Vec<Vec<u8>>
+}
+
+impl MerkleFrontier {
+  pub fn to_compact_range(&self) -> Result<CompactRange, &'static str> {
+    CompactRange::from_parts(self.tree_size as u64, &self.subtree_hashes)
+  }
+}
+
+#[derive(Queryable, Debug)]
+pub struct RawLeaf {
+  pub log_id: Hash,
+  pub leaf_index: i64,
+  pub leaf_hash: Hash,
+  pub leaf_input: Vec<u8>,
+  pub extra_data: Vec<u8>
 }
 
 pub fn serialize_datetime<S: Serializer>(t: &DateTime<Utc>, s: S) -> Result<S::Ok, S::Error> {
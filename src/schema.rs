@@ -1,3 +1,15 @@
+table! {
+    api_keys (id) {
+        id -> Int8,
+        name -> Text,
+        key_hash -> Bytea,
+        scopes -> Array<Text>,
+        per_minute -> Nullable<Int4>,
+        created_at -> Timestamptz,
+        revoked_at -> Nullable<Timestamptz>,
+    }
+}
+
 table! {
     cert_fetch_errors (id) {
         id -> Int8,
@@ -16,22 +28,34 @@ table! {
         cert_fp -> Bytea,
         log_id -> Bytea,
         leaf_index -> Int8,
+        chain_id -> Nullable<Int8>,
+        ingest_seq -> Int8,
     }
 }
 
 table! {
-    certificate_chain (__pk) {
-        __pk -> Int8,
-        certificate_fingerprint -> Bytea,
-        chain -> Array<Bytea>,
+    certificate_chains (id) {
+        id -> Int8,
+        chain_hash -> Bytea,
+        fingerprints -> Array<Bytea>,
     }
 }
 
 table! {
-    certificate_dns_names (__pk) {
+    certificate_fingerprints (fingerprint) {
+        fingerprint -> Bytea,
+        ingested_at -> Timestamptz,
+    }
+}
+
+table! {
+    certificate_names (__pk) {
         __pk -> Int8,
         cert_fp -> Bytea,
-        dns_name -> Text,
+        name_type -> Text,
+        name -> Text,
+        ip -> Nullable<Bytea>,
+        ingested_at -> Timestamptz,
     }
 }
 
@@ -39,6 +63,11 @@ table! {
     certificates (fingerprint) {
         fingerprint -> Bytea,
         x509 -> Bytea,
+        not_before -> Nullable<Timestamptz>,
+        not_after -> Nullable<Timestamptz>,
+        issuer_id -> Nullable<Int8>,
+        ingested_at -> Timestamptz,
+        chain_only -> Bool,
     }
 }
 
@@ -63,6 +92,74 @@ table! {
         monitoring -> Bool,
         latest_sth -> Nullable<Int8>,
         last_sth_error -> Nullable<Text>,
+        operator -> Nullable<Text>,
+    }
+}
+
+table! {
+    ingest_counter (id) {
+        id -> Bool,
+        last_seq -> Int8,
+    }
+}
+
+table! {
+    issuers (id) {
+        id -> Int8,
+        issuer_dn -> Text,
+        authority_key_id -> Bytea,
+        spki_sha256 -> Bytea,
+        cert_count -> Int8,
+    }
+}
+
+table! {
+    merkle_backfill_failures (log_id) {
+        log_id -> Bytea,
+        tree_size -> Int8,
+        error_msg -> Text,
+        failed_at -> Timestamptz,
+    }
+}
+
+table! {
+    merkle_frontiers (log_id) {
+        log_id -> Bytea,
+        tree_size -> Int8,
+        subtree_hashes -> Array<Bytea>,
+    }
+}
+
+table! {
+    merkle_nodes (log_id, level, node_index) {
+        log_id -> Bytea,
+        level -> Int2,
+        node_index -> Int8,
+        hash -> Bytea,
+    }
+}
+
+table! {
+    prune_runs (id) {
+        id -> Int8,
+        started_at -> Timestamptz,
+        finished_at -> Nullable<Timestamptz>,
+        dry_run -> Bool,
+        sth_days -> Int4,
+        expired_certificate_days -> Nullable<Int4>,
+        watchlist -> Array<Text>,
+        sth_deleted -> Int8,
+        certificates_deleted -> Int8,
+    }
+}
+
+table! {
+    raw_leaves (log_id, leaf_index) {
+        log_id -> Bytea,
+        leaf_index -> Int8,
+        leaf_hash -> Bytea,
+        leaf_input -> Bytea,
+        extra_data -> Bytea,
     }
 }
 
@@ -83,26 +180,41 @@ table! {
         received_time -> Timestamptz,
         signature -> Bytea,
         checked_consistent_with_latest -> Bool,
+        was_latest -> Bool,
     }
 }
 
 joinable!(cert_fetch_errors -> ctlogs (log_id));
+joinable!(certificate_appears_in_leaf -> certificate_chains (chain_id));
 joinable!(certificate_appears_in_leaf -> certificates (cert_fp));
 joinable!(certificate_appears_in_leaf -> ctlogs (log_id));
-joinable!(certificate_chain -> certificates (certificate_fingerprint));
-joinable!(certificate_dns_names -> certificates (cert_fp));
+joinable!(certificate_names -> certificates (cert_fp));
+joinable!(certificates -> issuers (issuer_id));
 joinable!(consistency_check_errors -> ctlogs (log_id));
+joinable!(merkle_backfill_failures -> ctlogs (log_id));
+joinable!(merkle_frontiers -> ctlogs (log_id));
+joinable!(merkle_nodes -> ctlogs (log_id));
+joinable!(raw_leaves -> ctlogs (log_id));
 joinable!(retired_log_changed_error -> ctlogs (log_id));
 joinable!(retired_log_changed_error -> sth (latest_sth));
 
 allow_tables_to_appear_in_same_query!(
+    api_keys,
     cert_fetch_errors,
     certificate_appears_in_leaf,
-    certificate_chain,
-    certificate_dns_names,
+    certificate_chains,
+    certificate_fingerprints,
+    certificate_names,
     certificates,
     consistency_check_errors,
     ctlogs,
+    ingest_counter,
+    issuers,
+    merkle_backfill_failures,
+    merkle_frontiers,
+    merkle_nodes,
+    prune_runs,
+    raw_leaves,
     retired_log_changed_error,
     sth,
 );